@@ -5,12 +5,12 @@ fn main() {
     println!("cargo:rerun-if-changed=migrations");
 
     // Set version env
-    if let Ok(output) = Command::new("git").args(["describe", "--tags"]).output() {
-        if output.status.success() {
-            println!(
-                "cargo:rustc-env=STAMON_VERSION={}",
-                String::from_utf8_lossy(&output.stdout)
-            );
-        }
+    if let Ok(output) = Command::new("git").args(["describe", "--tags"]).output()
+        && output.status.success()
+    {
+        println!(
+            "cargo:rustc-env=STAMON_VERSION={}",
+            String::from_utf8_lossy(&output.stdout)
+        );
     }
 }
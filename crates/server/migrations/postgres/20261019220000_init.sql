@@ -0,0 +1,276 @@
+-- Schema for PostgreSQL, the same as the SQLite migrations up to 20261019210000
+
+CREATE TABLE IF NOT EXISTS Users (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL DEFAULT 'admin',
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    timezone TEXT,
+    oidc_subject TEXT
+);
+
+CREATE UNIQUE INDEX idx_users_oidc_subject ON Users(oidc_subject);
+
+CREATE TABLE IF NOT EXISTS ServiceGroups (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name TEXT NOT NULL,
+    parent_id BIGINT,
+    aggregate TEXT NOT NULL DEFAULT 'worst',
+    quorum BIGINT,
+    last_status INTEGER NOT NULL DEFAULT 0,
+
+    FOREIGN KEY (parent_id) REFERENCES ServiceGroups(id) ON DELETE SET NULL
+);
+
+CREATE TABLE IF NOT EXISTS Services (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    name TEXT NOT NULL,
+    interval BIGINT NOT NULL,
+    url TEXT NOT NULL,
+    payload TEXT,
+    timeout BIGINT NOT NULL DEFAULT 10,
+    last_status INTEGER NOT NULL DEFAULT 0,
+    service_type TEXT NOT NULL,
+    retry BIGINT NOT NULL DEFAULT 3,
+    retry_interval BIGINT NOT NULL,
+    invert BOOLEAN NOT NULL DEFAULT FALSE,
+    expected_code BIGINT,
+    expected_payload TEXT,
+    push_token TEXT,
+    grace_period BIGINT NOT NULL DEFAULT 0,
+    last_push TIMESTAMPTZ,
+    cron TEXT,
+    cron_timezone TEXT,
+    max_runtime BIGINT,
+    started_at TIMESTAMPTZ,
+    group_id BIGINT REFERENCES ServiceGroups(id) ON DELETE SET NULL,
+    resume_at TIMESTAMPTZ,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id)
+);
+
+CREATE INDEX svc_idx ON Services(last_status);
+CREATE UNIQUE INDEX svc_push_token_idx ON Services(push_token);
+CREATE INDEX svc_group_idx ON Services(group_id);
+
+CREATE TABLE IF NOT EXISTS Logs (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    service_id BIGINT NOT NULL,
+    status INTEGER NOT NULL,
+    message TEXT,
+    time TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    duration BIGINT NOT NULL,
+    cause_id BIGINT REFERENCES Services(id) ON DELETE SET NULL,
+
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS Notifications (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    service_id BIGINT NOT NULL,
+    log_id BIGINT NOT NULL,
+    message TEXT,
+    sent_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE,
+    FOREIGN KEY (log_id) REFERENCES Logs(id)
+);
+
+CREATE TABLE IF NOT EXISTS Configs (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    category TEXT,
+    last_updated TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX idx_configs_name ON Configs (name);
+
+CREATE OR REPLACE FUNCTION update_service_status() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE Services
+    SET last_status = NEW.status
+    WHERE id = NEW.service_id;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER update_service_status
+AFTER INSERT ON Logs
+FOR EACH ROW
+EXECUTE FUNCTION update_service_status();
+
+CREATE OR REPLACE FUNCTION update_config_timestamp() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.last_updated = CURRENT_TIMESTAMP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER update_config_timestamp
+BEFORE UPDATE ON Configs
+FOR EACH ROW
+EXECUTE FUNCTION update_config_timestamp();
+
+CREATE TABLE IF NOT EXISTS Tags (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    color TEXT
+);
+
+CREATE TABLE IF NOT EXISTS ServiceTags (
+    service_id BIGINT NOT NULL,
+    tag_id BIGINT NOT NULL,
+
+    PRIMARY KEY (service_id, tag_id),
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE,
+    FOREIGN KEY (tag_id) REFERENCES Tags(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS ServiceDependencies (
+    service_id BIGINT NOT NULL,
+    parent_id BIGINT NOT NULL,
+
+    PRIMARY KEY (service_id, parent_id),
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE,
+    FOREIGN KEY (parent_id) REFERENCES Services(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS Teams (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS TeamMembers (
+    team_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+
+    PRIMARY KEY (team_id, user_id),
+    FOREIGN KEY (team_id) REFERENCES Teams(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS ServiceShares (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    service_id BIGINT NOT NULL,
+    user_id BIGINT,
+    team_id BIGINT,
+
+    CHECK ((user_id IS NULL) != (team_id IS NULL)),
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE,
+    FOREIGN KEY (team_id) REFERENCES Teams(id) ON DELETE CASCADE
+);
+
+CREATE INDEX share_service_idx ON ServiceShares(service_id);
+
+CREATE TABLE IF NOT EXISTS Invitations (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    role TEXT NOT NULL,
+    created_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    used_by BIGINT,
+
+    FOREIGN KEY (created_by) REFERENCES Users(id) ON DELETE CASCADE,
+    FOREIGN KEY (used_by) REFERENCES Users(id) ON DELETE SET NULL
+);
+
+CREATE TABLE IF NOT EXISTS Sessions (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    refresh_hash TEXT NOT NULL UNIQUE,
+    -- hash of the token this one replaced, to detect reuse of a rotated token
+    previous_hash TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_sessions_user ON Sessions(user_id);
+CREATE INDEX idx_sessions_previous_hash ON Sessions(previous_hash);
+
+CREATE TABLE IF NOT EXISTS ApiTokens (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    -- read, write or admin, limits the role of the user when using the token
+    scope TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_api_tokens_user ON ApiTokens(user_id);
+
+CREATE TABLE IF NOT EXISTS TwoFactor (
+    user_id BIGINT PRIMARY KEY,
+    -- base32 encoded TOTP secret
+    secret TEXT NOT NULL,
+    -- set once the user proved they enrolled the secret
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    -- last accepted time step, a code can't be used twice
+    last_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS RecoveryCodes (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at TIMESTAMPTZ,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_recovery_codes_user ON RecoveryCodes(user_id);
+
+CREATE TABLE IF NOT EXISTS AuthEvents (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    kind TEXT NOT NULL,
+    user_id BIGINT,
+    -- the username tried, kept for failed logins of unknown users
+    username TEXT,
+    ip TEXT,
+    detail TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_auth_events_username ON AuthEvents(username, created_at);
+CREATE INDEX idx_auth_events_ip ON AuthEvents(ip, created_at);
+
+CREATE TABLE IF NOT EXISTS AuditLog (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    user_id BIGINT,
+    -- what was done, like `update` or `pause`
+    action TEXT NOT NULL,
+    -- kind of the changed entity, like `service` or `user`
+    entity TEXT NOT NULL,
+    entity_id TEXT,
+    -- fields that changed, as JSON objects
+    before JSONB,
+    after JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_audit_log_entity ON AuditLog(entity, entity_id);
+CREATE INDEX idx_audit_log_created ON AuditLog(created_at);
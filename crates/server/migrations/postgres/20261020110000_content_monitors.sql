@@ -0,0 +1,29 @@
+-- keyword monitors: a string or regex that must be in (or, inverted, absent from) the body
+ALTER TABLE Services
+ADD keyword TEXT;
+
+ALTER TABLE Services
+ADD keyword_regex BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- content change monitors: the part of the body that is compared between checks
+ALTER TABLE Services
+ADD css_selector TEXT;
+
+ALTER TABLE Services
+ADD extract_pattern TEXT;
+
+ALTER TABLE Services
+ADD snapshot_limit BIGINT NOT NULL DEFAULT 10;
+
+-- last contents of content change monitors, the newest is the baseline
+CREATE TABLE IF NOT EXISTS ContentSnapshots (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    service_id BIGINT NOT NULL,
+    hash TEXT NOT NULL,
+    content TEXT NOT NULL,
+    time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE
+);
+
+CREATE INDEX snapshot_service_idx ON ContentSnapshots(service_id);
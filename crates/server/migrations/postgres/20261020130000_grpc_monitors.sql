@@ -0,0 +1,6 @@
+-- gRPC monitors: service name sent to the health check and metadata headers, a JSON object
+ALTER TABLE Services
+ADD grpc_service TEXT;
+
+ALTER TABLE Services
+ADD grpc_metadata JSONB;
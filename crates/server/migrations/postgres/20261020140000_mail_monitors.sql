@@ -0,0 +1,3 @@
+-- mail monitors: upgrade plaintext connections with STARTTLS before authenticating
+ALTER TABLE Services
+ADD starttls BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,11 @@
+-- push monitors: services that receive heartbeats instead of being probed
+ALTER TABLE Services
+ADD push_token TEXT;
+
+ALTER TABLE Services
+ADD grace_period INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE Services
+ADD last_push DATETIME;
+
+CREATE UNIQUE INDEX IF NOT EXISTS svc_push_token_idx ON Services(push_token);
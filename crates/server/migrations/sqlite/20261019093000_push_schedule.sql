@@ -0,0 +1,12 @@
+-- cron schedules and run tracking for push monitors
+ALTER TABLE Services
+ADD cron TEXT;
+
+ALTER TABLE Services
+ADD cron_timezone TEXT;
+
+ALTER TABLE Services
+ADD max_runtime INTEGER;
+
+ALTER TABLE Services
+ADD started_at DATETIME;
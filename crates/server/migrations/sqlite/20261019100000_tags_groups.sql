@@ -0,0 +1,32 @@
+-- tags: coloured labels attached to services
+CREATE TABLE Tags (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    color TEXT
+);
+
+CREATE TABLE ServiceTags (
+    service_id INTEGER NOT NULL,
+    tag_id INTEGER NOT NULL,
+
+    PRIMARY KEY (service_id, tag_id),
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE,
+    FOREIGN KEY (tag_id) REFERENCES Tags(id) ON DELETE CASCADE
+);
+
+-- service groups: hierarchical grouping with an aggregate status
+CREATE TABLE ServiceGroups (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    parent_id INTEGER,
+    aggregate TEXT NOT NULL DEFAULT 'worst',
+    quorum INTEGER,
+    last_status INTEGER NOT NULL DEFAULT 0,
+
+    FOREIGN KEY (parent_id) REFERENCES ServiceGroups(id) ON DELETE SET NULL
+);
+
+ALTER TABLE Services
+ADD group_id INTEGER REFERENCES ServiceGroups(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS svc_group_idx ON Services(group_id);
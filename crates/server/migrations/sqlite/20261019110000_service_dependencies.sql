@@ -0,0 +1,13 @@
+-- service dependencies: a service is unreachable while one of its parents is down
+CREATE TABLE ServiceDependencies (
+    service_id INTEGER NOT NULL,
+    parent_id INTEGER NOT NULL,
+
+    PRIMARY KEY (service_id, parent_id),
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE,
+    FOREIGN KEY (parent_id) REFERENCES Services(id) ON DELETE CASCADE
+);
+
+-- the parent service an unreachable log is attributed to
+ALTER TABLE Logs
+ADD cause_id INTEGER REFERENCES Services(id) ON DELETE SET NULL;
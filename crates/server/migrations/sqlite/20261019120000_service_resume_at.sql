@@ -0,0 +1,3 @@
+-- time a paused service is automatically resumed
+ALTER TABLE Services
+ADD resume_at DATETIME;
@@ -0,0 +1,29 @@
+-- teams: groups of users that services can be shared with
+CREATE TABLE Teams (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE TeamMembers (
+    team_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+
+    PRIMARY KEY (team_id, user_id),
+    FOREIGN KEY (team_id) REFERENCES Teams(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+-- service shares: gives a user or a whole team access to a service
+CREATE TABLE ServiceShares (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    service_id INTEGER NOT NULL,
+    user_id INTEGER,
+    team_id INTEGER,
+
+    CHECK ((user_id IS NULL) != (team_id IS NULL)),
+    FOREIGN KEY (service_id) REFERENCES Services(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE,
+    FOREIGN KEY (team_id) REFERENCES Teams(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS share_service_idx ON ServiceShares(service_id);
@@ -0,0 +1,14 @@
+-- invitations: single-use links for new users to register with a given role
+CREATE TABLE Invitations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT NOT NULL UNIQUE,
+    role TEXT NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL,
+    used_at DATETIME,
+    used_by INTEGER,
+
+    FOREIGN KEY (created_by) REFERENCES Users(id) ON DELETE CASCADE,
+    FOREIGN KEY (used_by) REFERENCES Users(id) ON DELETE SET NULL
+);
@@ -0,0 +1,19 @@
+-- sessions: server-side refresh tokens, one row per login
+CREATE TABLE Sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    refresh_hash TEXT NOT NULL UNIQUE,
+    -- hash of the token this one replaced, to detect reuse of a rotated token
+    previous_hash TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL,
+    revoked_at DATETIME,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_sessions_user ON Sessions(user_id);
+CREATE INDEX idx_sessions_previous_hash ON Sessions(previous_hash);
@@ -0,0 +1,24 @@
+-- two_factor: optional TOTP second factor for logging in
+CREATE TABLE TwoFactor (
+    user_id INTEGER PRIMARY KEY,
+    -- base32 encoded TOTP secret
+    secret TEXT NOT NULL,
+    -- set once the user proved they enrolled the secret
+    enabled BOOLEAN NOT NULL DEFAULT 0,
+    -- last accepted time step, a code can't be used twice
+    last_step INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE RecoveryCodes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at DATETIME,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_recovery_codes_user ON RecoveryCodes(user_id);
@@ -0,0 +1,4 @@
+-- oidc_users: link users provisioned by single sign-on to their identity provider subject
+ALTER TABLE Users ADD COLUMN oidc_subject TEXT;
+
+CREATE UNIQUE INDEX idx_users_oidc_subject ON Users(oidc_subject);
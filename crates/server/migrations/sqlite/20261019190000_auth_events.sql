@@ -0,0 +1,16 @@
+-- auth_events: log of logins, failures and credential changes, also used to throttle logins
+CREATE TABLE AuthEvents (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    user_id INTEGER,
+    -- the username tried, kept for failed logins of unknown users
+    username TEXT,
+    ip TEXT,
+    detail TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_auth_events_username ON AuthEvents(username, created_at);
+CREATE INDEX idx_auth_events_ip ON AuthEvents(ip, created_at);
@@ -0,0 +1,19 @@
+-- audit_log: who changed what through the api
+CREATE TABLE AuditLog (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER,
+    -- what was done, like `update` or `pause`
+    action TEXT NOT NULL,
+    -- kind of the changed entity, like `service` or `user`
+    entity TEXT NOT NULL,
+    entity_id TEXT,
+    -- fields that changed, as JSON objects
+    before TEXT,
+    after TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (user_id) REFERENCES Users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_audit_log_entity ON AuditLog(entity, entity_id);
+CREATE INDEX idx_audit_log_created ON AuditLog(created_at);
@@ -0,0 +1,5 @@
+-- Settings are looked up by name, keep only the latest value of duplicates
+DELETE FROM Configs
+WHERE id NOT IN (SELECT MAX(id) FROM Configs GROUP BY name);
+
+CREATE UNIQUE INDEX idx_configs_name ON Configs (name);
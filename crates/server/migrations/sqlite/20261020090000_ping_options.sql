@@ -0,0 +1,29 @@
+-- ping options: several packets per check, resolved from a hostname if needed
+ALTER TABLE Services
+ADD ping_count INTEGER NOT NULL DEFAULT 3;
+
+ALTER TABLE Services
+ADD packet_size INTEGER NOT NULL DEFAULT 56;
+
+ALTER TABLE Services
+ADD ttl INTEGER NOT NULL DEFAULT 128;
+
+-- percentage of packets lost for the service to be down
+ALTER TABLE Services
+ADD loss_threshold INTEGER NOT NULL DEFAULT 100;
+
+ALTER TABLE Services
+ADD ip_preference TEXT NOT NULL DEFAULT 'ipv4';
+
+-- round trip times in milliseconds and packet loss of ping checks
+ALTER TABLE Logs
+ADD rtt_min INTEGER;
+
+ALTER TABLE Logs
+ADD rtt_avg INTEGER;
+
+ALTER TABLE Logs
+ADD rtt_max INTEGER;
+
+ALTER TABLE Logs
+ADD packet_loss REAL;
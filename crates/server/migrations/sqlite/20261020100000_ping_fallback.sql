@@ -0,0 +1,7 @@
+-- port of the TCP connections ping checks fall back to when ICMP is not permitted
+ALTER TABLE Services
+ADD tcp_port INTEGER NOT NULL DEFAULT 80;
+
+-- how a ping check was done: icmp, icmp-raw or tcp
+ALTER TABLE Logs
+ADD ping_method TEXT;
@@ -0,0 +1,3 @@
+-- database monitors: query run on each check, `SELECT 1` or `PING` when not set
+ALTER TABLE Services
+ADD query TEXT;
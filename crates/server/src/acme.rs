@@ -0,0 +1,886 @@
+//! Certificate issuance with ACME (RFC 8555), using HTTP-01 challenges
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Router,
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    routing::get,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rcgen::{CertificateParams, KeyPair};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use ring::{
+    rand::SystemRandom,
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _},
+};
+use rustls::pki_types::{CertificateDer, pem::PemObject};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use simple_asn1::ASN1Block;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info};
+
+use crate::config::{AcmeConfig, TlsConfig};
+
+/// Certificates are renewed when they expire in less than this, they are valid for 90 days
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often the certificate expiry is checked
+const CHECK_EVERY: Duration = Duration::from_secs(12 * 60 * 60);
+/// First wait after a failed renewal, doubled on each failure up to `CHECK_EVERY`
+const RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Times a request is sent again when the server rejects its nonce
+const NONCE_RETRIES: u32 = 3;
+/// Polls of a pending authorization or order before giving up
+const MAX_POLLS: u32 = 30;
+
+/// Key authorizations of pending HTTP-01 challenges, by token
+pub type Challenges = Arc<RwLock<HashMap<String, String>>>;
+
+/// Routes answering HTTP-01 challenges, served on plain HTTP
+pub fn challenge_routes(challenges: Challenges) -> Router {
+    Router::new()
+        .route("/.well-known/acme-challenge/{token}", get(answer_challenge))
+        .with_state(challenges)
+}
+
+async fn answer_challenge(
+    State(challenges): State<Challenges>,
+    UrlPath(token): UrlPath<String>,
+) -> Result<String, StatusCode> {
+    challenges
+        .read()
+        .unwrap()
+        .get(&token)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug)]
+pub enum AcmeError {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    Crypto(String),
+    /// The server refused a request or a challenge failed
+    Rejected(String),
+}
+
+impl std::fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AcmeError::Http(e) => write!(f, "acme request failed: {e}"),
+            AcmeError::Io(e) => write!(f, "{e}"),
+            AcmeError::Crypto(e) => write!(f, "{e}"),
+            AcmeError::Rejected(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for AcmeError {
+    fn from(e: reqwest::Error) -> Self {
+        AcmeError::Http(e)
+    }
+}
+
+impl From<std::io::Error> for AcmeError {
+    fn from(e: std::io::Error) -> Self {
+        AcmeError::Io(e)
+    }
+}
+
+impl From<rcgen::Error> for AcmeError {
+    fn from(e: rcgen::Error) -> Self {
+        AcmeError::Crypto(e.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+/// An ACME account, identified by its key
+pub struct Account {
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    http: reqwest::Client,
+    directory: Directory,
+    nonce: Option<String>,
+    /// Account url, used as key id once registered
+    kid: Option<String>,
+}
+
+impl Account {
+    /// Register or look up the account of `pkcs8` with the server at `config.directory`
+    pub async fn login(config: &AcmeConfig, pkcs8: &[u8]) -> Result<Account, AcmeError> {
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+            .map_err(|e| AcmeError::Crypto(format!("invalid account key: {e}")))?;
+        let http = reqwest::Client::new();
+        let directory = http
+            .get(&config.directory)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut account = Account {
+            key,
+            rng,
+            http,
+            directory,
+            nonce: None,
+            kid: None,
+        };
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &config.email {
+            payload["contact"] = json!([format!("mailto:{email}")]);
+        }
+        let url = account.directory.new_account.clone();
+        let res = account.post(&url, Some(payload)).await?;
+        account.kid = Some(location(&res)?);
+
+        Ok(account)
+    }
+
+    /// A new account key, as PKCS#8 DER
+    pub fn generate_key() -> Result<Vec<u8>, AcmeError> {
+        EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+            .map(|doc| doc.as_ref().to_vec())
+            .map_err(|_| AcmeError::Crypto("failed to generate account key".into()))
+    }
+
+    fn jwk(&self) -> Value {
+        // uncompressed point: 0x04 || x || y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+
+    /// `token.thumbprint`, what the challenge url has to answer
+    fn key_authorization(&self, token: &str) -> String {
+        // members in lexicographic order, as required for the thumbprint (RFC 7638)
+        let jwk = self.jwk();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            jwk["x"].as_str().unwrap_or_default(),
+            jwk["y"].as_str().unwrap_or_default()
+        );
+        let thumbprint = URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()));
+        format!("{token}.{thumbprint}")
+    }
+
+    async fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let res = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await?
+            .error_for_status()?;
+        replay_nonce(&res).ok_or(AcmeError::Rejected("no nonce from acme server".into()))
+    }
+
+    /// Signed POST, `None` as payload is a POST-as-GET. Requests whose nonce the server
+    /// rejected are sent again with the fresh nonce it answered with.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<reqwest::Response, AcmeError> {
+        let mut retries = 0;
+        loop {
+            let body = self.sign(url, payload.as_ref()).await?;
+            let res = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body)
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&res);
+            if res.status().is_success() {
+                return Ok(res);
+            }
+            let status = res.status();
+            let problem = res.text().await.unwrap_or_default();
+            if is_bad_nonce(&problem) && retries < NONCE_RETRIES {
+                retries += 1;
+                continue;
+            }
+            return Err(AcmeError::Rejected(format!("{url}: {status} {problem}")));
+        }
+    }
+
+    /// JWS body of a request to `url`, signed with the account key
+    async fn sign(&mut self, url: &str, payload: Option<&Value>) -> Result<String, AcmeError> {
+        let mut protected = json!({
+            "alg": "ES256",
+            "nonce": self.nonce().await?,
+            "url": url,
+        });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|p| URL_SAFE_NO_PAD.encode(p.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+            .map_err(|_| AcmeError::Crypto("failed to sign request".into()))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        })
+        .to_string())
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&mut self, url: &str) -> Result<T, AcmeError> {
+        Ok(self.post(url, None).await?.json().await?)
+    }
+
+    /// Prove control of the domains of an order, answering its challenges from `challenges`
+    async fn authorize(
+        &mut self,
+        authorizations: &[String],
+        challenges: &Challenges,
+        poll: Duration,
+    ) -> Result<(), AcmeError> {
+        for url in authorizations {
+            let authz: Authorization = self.get(url).await?;
+            if authz.status == "valid" {
+                continue;
+            }
+            let challenge = authz
+                .challenges
+                .into_iter()
+                .find(|c| c.kind == "http-01")
+                .ok_or(AcmeError::Rejected("no http-01 challenge offered".into()))?;
+            challenges.write().unwrap().insert(
+                challenge.token.clone(),
+                self.key_authorization(&challenge.token),
+            );
+
+            let result = async {
+                self.post(&challenge.url, Some(json!({}))).await?;
+                for _ in 0..MAX_POLLS {
+                    let authz: Authorization = self.get(url).await?;
+                    match authz.status.as_str() {
+                        "valid" => return Ok(()),
+                        "pending" | "processing" => tokio::time::sleep(poll).await,
+                        status => {
+                            return Err(AcmeError::Rejected(format!(
+                                "authorization {status}: {url}"
+                            )));
+                        }
+                    }
+                }
+                Err(AcmeError::Rejected(format!(
+                    "authorization timed out: {url}"
+                )))
+            }
+            .await;
+            challenges.write().unwrap().remove(&challenge.token);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Order a certificate for `domains`, returning the certificate chain and key as PEM
+    pub async fn issue(
+        &mut self,
+        domains: &[String],
+        challenges: &Challenges,
+        poll: Duration,
+    ) -> Result<(String, String), AcmeError> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let res = self
+            .post(&url, Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(&res)?;
+        let order: Order = res.json().await?;
+
+        self.authorize(&order.authorizations, challenges, poll)
+            .await?;
+
+        let key = KeyPair::generate()?;
+        let csr = CertificateParams::new(domains.to_vec())?.serialize_request(&key)?;
+        self.post(
+            &order.finalize,
+            Some(json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) })),
+        )
+        .await?;
+
+        for _ in 0..MAX_POLLS {
+            let order: Order = self.get(&order_url).await?;
+            match (order.status.as_str(), order.certificate) {
+                ("valid", Some(certificate)) => {
+                    let chain = self.post(&certificate, None).await?.text().await?;
+                    return Ok((chain, key.serialize_pem()));
+                }
+                ("pending" | "ready" | "processing" | "valid", _) => tokio::time::sleep(poll).await,
+                (status, _) => {
+                    return Err(AcmeError::Rejected(format!("order {status}: {order_url}")));
+                }
+            }
+        }
+        Err(AcmeError::Rejected(format!("order timed out: {order_url}")))
+    }
+}
+
+fn replay_nonce(res: &reqwest::Response) -> Option<String> {
+    res.headers()
+        .get("Replay-Nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+fn location(res: &reqwest::Response) -> Result<String, AcmeError> {
+    res.headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .ok_or(AcmeError::Rejected("no location in acme response".into()))
+}
+
+/// Whether an error document is a `badNonce` problem (RFC 8555 section 6.5)
+fn is_bad_nonce(problem: &str) -> bool {
+    serde_json::from_str::<Value>(problem)
+        .is_ok_and(|p| p["type"] == "urn:ietf:params:acme:error:badNonce")
+}
+
+/// When the first certificate of a PEM chain expires (its notAfter)
+fn expiry(pem: &[u8]) -> Option<SystemTime> {
+    let der = CertificateDer::from_pem_slice(pem).ok()?;
+    // Certificate ::= SEQUENCE { tbsCertificate, ... }, the validity is the only sequence of
+    // the tbsCertificate made of two times
+    let blocks = simple_asn1::from_der(&der).ok()?;
+    let [ASN1Block::Sequence(_, certificate), ..] = &blocks[..] else {
+        return None;
+    };
+    let [ASN1Block::Sequence(_, tbs), ..] = &certificate[..] else {
+        return None;
+    };
+    let not_after = tbs.iter().find_map(|block| match block {
+        ASN1Block::Sequence(_, validity) => match &validity[..] {
+            [
+                ASN1Block::UTCTime(..) | ASN1Block::GeneralizedTime(..),
+                ASN1Block::UTCTime(_, time) | ASN1Block::GeneralizedTime(_, time),
+            ] => Some(time.assume_utc().unix_timestamp()),
+            _ => None,
+        },
+        _ => None,
+    })?;
+    let secs = u64::try_from(not_after).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Whether the certificate at `path` is missing, unreadable or expires soon
+fn needs_renewal(path: &Path) -> bool {
+    std::fs::read(path)
+        .ok()
+        .and_then(|pem| expiry(&pem))
+        .and_then(|expiry| expiry.duration_since(SystemTime::now()).ok())
+        .is_none_or(|left| left < RENEW_BEFORE)
+}
+
+/// Write `data` to a temporary file next to `path`, readable by the owner only when
+/// `private`, and move it over `path` so readers never see a partial file
+async fn write_atomic(path: &Path, data: &[u8], private: bool) -> std::io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Issue a certificate and store it with its key where the server reads them from
+async fn renew(
+    tls: &TlsConfig,
+    acme: &AcmeConfig,
+    challenges: &Challenges,
+) -> Result<(), AcmeError> {
+    let account_key = tls.key.with_file_name("acme-account.key");
+    let pkcs8 = match tokio::fs::read(&account_key).await {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = Account::generate_key()?;
+            write_atomic(&account_key, &pkcs8, true).await?;
+            pkcs8
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut account = Account::login(acme, &pkcs8).await?;
+    let (chain, key) = account
+        .issue(&acme.domains, challenges, Duration::from_secs(2))
+        .await?;
+    // the key first, a new certificate with the old key would fail to load
+    write_atomic(&tls.key, key.as_bytes(), true).await?;
+    write_atomic(&tls.cert, chain.as_bytes(), false).await?;
+
+    Ok(())
+}
+
+/// Wait before the next check, shorter after failed renewals and doubling with each of them
+fn backoff(failures: u32) -> Duration {
+    match failures {
+        0 => CHECK_EVERY,
+        n => RETRY_AFTER
+            .saturating_mul(1 << (n - 1).min(16))
+            .min(CHECK_EVERY),
+    }
+}
+
+/// Keep the certificate issued and renewed, reloading the server certificate each time
+pub async fn run(tls: TlsConfig, challenges: Challenges, rustls: RustlsConfig) {
+    let Some(acme) = &tls.acme else {
+        return;
+    };
+    let mut failures = 0;
+    loop {
+        if needs_renewal(&tls.cert) {
+            info!("Requesting a certificate for {}", acme.domains.join(", "));
+            match renew(&tls, acme, &challenges).await {
+                Ok(()) => {
+                    failures = 0;
+                    match rustls.reload_from_pem_file(&tls.cert, &tls.key).await {
+                        Ok(()) => info!("Certificate issued for {}", acme.domains.join(", ")),
+                        Err(e) => error!("Error loading issued certificate: {e}"),
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    error!(
+                        "Error issuing certificate, retrying in {:?}: {e}",
+                        backoff(failures)
+                    );
+                }
+            }
+        }
+        tokio::time::sleep(backoff(failures)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use axum::{
+        Json,
+        http::{HeaderMap, HeaderValue},
+        response::IntoResponse,
+        routing::{head, post},
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// State of a minimal ACME server, checking nonces and the HTTP-01 answer
+    #[derive(Clone)]
+    struct Mock {
+        base: String,
+        /// Where challenge answers are fetched from, instead of port 80 of the domain
+        challenge_base: String,
+        nonces: Arc<AtomicU32>,
+        used: Arc<RwLock<Vec<String>>>,
+        /// What the challenge url answered
+        answer: Arc<RwLock<Option<String>>>,
+    }
+
+    impl Mock {
+        fn headers(&self, location: Option<String>) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            let nonce = self.nonces.fetch_add(1, Ordering::SeqCst).to_string();
+            headers.insert("Replay-Nonce", HeaderValue::from_str(&nonce).unwrap());
+            if let Some(location) = location {
+                headers.insert(LOCATION, HeaderValue::from_str(&location).unwrap());
+            }
+            headers
+        }
+
+        fn validated(&self) -> bool {
+            self.answer
+                .read()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|answer| answer.starts_with("tok-1."))
+        }
+
+        /// Decode a JWS body, rejecting replayed nonces
+        fn payload(&self, body: &str) -> Result<(Value, Value), StatusCode> {
+            let jws: Value = serde_json::from_str(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+            let decode = |field: &str| -> Result<Value, StatusCode> {
+                let raw = URL_SAFE_NO_PAD
+                    .decode(jws[field].as_str().unwrap_or_default())
+                    .map_err(|_| StatusCode::BAD_REQUEST)?;
+                Ok(serde_json::from_slice(&raw).unwrap_or(Value::Null))
+            };
+            let protected = decode("protected")?;
+            let nonce = protected["nonce"].as_str().unwrap_or_default().to_string();
+            let mut used = self.used.write().unwrap();
+            if used.contains(&nonce) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            used.push(nonce);
+            Ok((protected, decode("payload")?))
+        }
+    }
+
+    async fn mock_acme(challenge_base: String) -> (AcmeConfig, Mock) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let mock = Mock {
+            base: base.clone(),
+            challenge_base,
+            nonces: Arc::new(AtomicU32::new(0)),
+            used: Arc::new(RwLock::new(vec![])),
+            answer: Arc::new(RwLock::new(None)),
+        };
+
+        let directory = |State(mock): State<Mock>| async move {
+            Json(json!({
+                "newNonce": format!("{}/nonce", mock.base),
+                "newAccount": format!("{}/account", mock.base),
+                "newOrder": format!("{}/order", mock.base),
+            }))
+        };
+        let nonce = |State(mock): State<Mock>| async move { (mock.headers(None), "") };
+        let account = |State(mock): State<Mock>, body: String| async move {
+            let (protected, payload) = mock.payload(&body)?;
+            if protected.get("jwk").is_none() || payload["termsOfServiceAgreed"] != true {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            let location = Some(format!("{}/account/1", mock.base));
+            Ok((StatusCode::CREATED, mock.headers(location), "{}"))
+        };
+        let order = |State(mock): State<Mock>, body: String| async move {
+            let (protected, payload) = mock.payload(&body)?;
+            if protected.get("kid").is_none() {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            let status = match payload.get("identifiers") {
+                // new order
+                Some(_) => "pending",
+                None if mock.validated() => "valid",
+                None => "pending",
+            };
+            let body = json!({
+                "status": status,
+                "authorizations": [format!("{}/authz/1", mock.base)],
+                "finalize": format!("{}/finalize", mock.base),
+                "certificate": format!("{}/cert", mock.base),
+            });
+            let location = Some(format!("{}/order/1", mock.base));
+            Ok((StatusCode::CREATED, mock.headers(location), Json(body)))
+        };
+        let authz = |State(mock): State<Mock>, body: String| async move {
+            mock.payload(&body)?;
+            let status = if mock.validated() { "valid" } else { "pending" };
+            let body = json!({
+                "status": status,
+                "challenges": [
+                    { "type": "dns-01", "url": format!("{}/chall/dns", mock.base), "token": "dns" },
+                    { "type": "http-01", "url": format!("{}/chall/1", mock.base), "token": "tok-1" },
+                ],
+            });
+            Ok::<_, StatusCode>((mock.headers(None), Json(body)))
+        };
+        let challenge = |State(mock): State<Mock>, body: String| async move {
+            mock.payload(&body)?;
+            let answer = reqwest::get(format!(
+                "{}/.well-known/acme-challenge/tok-1",
+                mock.challenge_base
+            ))
+            .await
+            .map_err(|_| StatusCode::BAD_GATEWAY)?
+            .text()
+            .await
+            .ok();
+            *mock.answer.write().unwrap() = answer;
+            Ok::<_, StatusCode>((mock.headers(None), "{}"))
+        };
+        let finalize = |State(mock): State<Mock>, body: String| async move {
+            let (_, payload) = mock.payload(&body)?;
+            if payload["csr"].as_str().is_none_or(str::is_empty) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Ok((mock.headers(None), "{}"))
+        };
+        let cert = |State(mock): State<Mock>, body: String| async move {
+            if let Err(status) = mock.payload(&body) {
+                return status.into_response();
+            }
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["status.test".to_string()])
+                .unwrap()
+                .self_signed(&key)
+                .unwrap();
+            (mock.headers(None), cert.pem()).into_response()
+        };
+
+        let app = Router::new()
+            .route("/directory", get(directory))
+            .route("/nonce", head(nonce))
+            .route("/account", post(account))
+            .route("/order", post(order))
+            .route("/order/1", post(order))
+            .route("/authz/1", post(authz))
+            .route("/chall/1", post(challenge))
+            .route("/finalize", post(finalize))
+            .route("/cert", post(cert))
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = AcmeConfig {
+            domains: vec!["status.test".into()],
+            email: Some("ops@status.test".into()),
+            directory: format!("{base}/directory"),
+        };
+        (config, mock)
+    }
+
+    async fn serve_challenges() -> (String, Challenges) {
+        let challenges = Challenges::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let app = challenge_routes(challenges.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base, challenges)
+    }
+
+    #[tokio::test]
+    async fn issue_certificate() {
+        let (challenge_base, challenges) = serve_challenges().await;
+        let (config, mock) = mock_acme(challenge_base).await;
+
+        let key = Account::generate_key().unwrap();
+        let mut account = Account::login(&config, &key).await.unwrap();
+        let (chain, key) = account
+            .issue(&config.domains, &challenges, Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *mock.answer.read().unwrap(),
+            Some(account.key_authorization("tok-1"))
+        );
+        assert!(chain.starts_with("-----BEGIN CERTIFICATE-----"));
+        // answered challenges are cleaned up
+        assert!(challenges.read().unwrap().is_empty());
+        assert!(KeyPair::from_pem(&key).is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_challenge() {
+        // nothing answers the challenge
+        let (config, _mock) = mock_acme("http://127.0.0.1:9".into()).await;
+        let challenges = Challenges::default();
+
+        let key = Account::generate_key().unwrap();
+        let mut account = Account::login(&config, &key).await.unwrap();
+        assert!(matches!(
+            account
+                .issue(&config.domains, &challenges, Duration::from_millis(10))
+                .await,
+            Err(AcmeError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn key_authorization_thumbprint() {
+        let key = Account::generate_key().unwrap();
+        let rng = SystemRandom::new();
+        let account = Account {
+            key: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key, &rng).unwrap(),
+            rng,
+            http: reqwest::Client::new(),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            nonce: None,
+            kid: None,
+        };
+
+        let auth = account.key_authorization("tok");
+        let (token, thumbprint) = auth.split_once('.').unwrap();
+        assert_eq!(token, "tok");
+        // base64url of a sha256 digest
+        assert_eq!(thumbprint.len(), 43);
+    }
+
+    #[tokio::test]
+    async fn retry_bad_nonce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let attempts = Arc::new(AtomicU32::new(0));
+        let directory = json!({
+            "newNonce": format!("{base}/nonce"),
+            "newAccount": format!("{base}/account"),
+            "newOrder": format!("{base}/order"),
+        });
+        let nonce_headers = || {
+            let mut headers = HeaderMap::new();
+            headers.insert("Replay-Nonce", HeaderValue::from_static("fresh"));
+            headers
+        };
+        let account = {
+            let (attempts, base) = (attempts.clone(), base.clone());
+            move || async move {
+                let mut headers = nonce_headers();
+                // the first nonce is stale
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    let problem = json!({ "type": "urn:ietf:params:acme:error:badNonce" });
+                    return (StatusCode::BAD_REQUEST, headers, Json(problem));
+                }
+                let location = HeaderValue::from_str(&format!("{base}/account/1")).unwrap();
+                headers.insert(LOCATION, location);
+                (StatusCode::CREATED, headers, Json(json!({})))
+            }
+        };
+        let app = Router::new()
+            .route("/directory", get(move || async move { Json(directory) }))
+            .route("/nonce", head(move || async move { (nonce_headers(), "") }))
+            .route("/account", post(account));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = AcmeConfig {
+            domains: vec!["status.test".into()],
+            email: None,
+            directory: format!("{base}/directory"),
+        };
+        let key = Account::generate_key().unwrap();
+        let account = Account::login(&config, &key).await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(account.kid, Some(format!("{base}/account/1")));
+
+        assert!(!is_bad_nonce("not json"));
+        assert!(!is_bad_nonce(
+            r#"{"type":"urn:ietf:params:acme:error:malformed"}"#
+        ));
+    }
+
+    #[test]
+    fn renew_missing_certificate() {
+        assert!(needs_renewal(Path::new("/nonexistent/cert.pem")));
+    }
+
+    #[tokio::test]
+    async fn renew_expiring_certificate() {
+        let dir = std::env::temp_dir().join(format!("stamon-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = |not_after| {
+            let mut params = CertificateParams::new(vec!["status.test".to_string()]).unwrap();
+            params.not_after = not_after;
+            params
+                .self_signed(&KeyPair::generate().unwrap())
+                .unwrap()
+                .pem()
+        };
+
+        let path = dir.join("cert.pem");
+        let pem = cert(rcgen::date_time_ymd(2090, 1, 1));
+        assert_eq!(
+            expiry(pem.as_bytes()),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(3786912000))
+        );
+        write_atomic(&path, pem.as_bytes(), false).await.unwrap();
+        assert!(!needs_renewal(&path));
+
+        let pem = cert(rcgen::date_time_ymd(2020, 1, 1));
+        write_atomic(&path, pem.as_bytes(), false).await.unwrap();
+        assert!(needs_renewal(&path));
+
+        write_atomic(&path, b"garbage", false).await.unwrap();
+        assert!(needs_renewal(&path));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn private_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("stamon-acme-key-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key.pem");
+        write_atomic(&path, b"old", true).await.unwrap();
+        write_atomic(&path, b"new", true).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the temporary file was moved
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_renewal_backoff() {
+        assert_eq!(backoff(0), CHECK_EVERY);
+        assert_eq!(backoff(1), RETRY_AFTER);
+        assert_eq!(backoff(2), RETRY_AFTER * 2);
+        assert_eq!(backoff(10), CHECK_EVERY);
+        assert_eq!(backoff(u32::MAX), CHECK_EVERY);
+    }
+}
@@ -1,23 +1,42 @@
+use std::sync::LazyLock;
+
 use argon2::{
     Argon2, PasswordHasher,
     password_hash::{SaltString, rand_core::OsRng},
 };
 use axum::{
-    RequestPartsExt,
+    Extension, RequestPartsExt,
     extract::FromRequestParts,
-    http::{StatusCode, request::Parts},
-    response::{IntoResponse, Json, Response},
+    http::request::Parts,
+    response::{IntoResponse, Response},
 };
 use axum_extra::{
     TypedHeader,
     extract::CookieJar,
     headers::{Authorization, authorization::Bearer},
 };
-use jsonwebtoken::{DecodingKey, Validation, decode};
+use chrono::{TimeDelta, Utc};
+use jsonwebtoken::{
+    DecodingKey, EncodingKey, Header, Validation, decode, encode, errors::ErrorKind,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use tracing::error;
+
+use crate::{
+    config::env_config,
+    error::ApiError,
+    models::{
+        DbPool,
+        api_token::{ApiToken, TOKEN_PREFIX},
+        session::Session,
+        user::UserRole,
+    },
+};
 
-use crate::{config::env_config, models::user::UserRole};
+/// Lifetime of an access token, clients use their refresh token to get a new one
+pub const ACCESS_TOKEN_TTL: TimeDelta = TimeDelta::minutes(15);
+/// Lifetime of a refresh token, extended every time it is used
+pub const REFRESH_TOKEN_TTL: TimeDelta = TimeDelta::days(30);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -28,6 +47,87 @@ pub struct Claims {
     pub issued_at: usize,
     #[serde(rename = "exp")]
     pub expiry: usize,
+    /// Session the access token belongs to, `None` when authenticated with an api token
+    #[serde(rename = "sid", default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<u32>,
+}
+
+impl Claims {
+    pub fn new(user_id: u32, role: UserRole, session_id: u32) -> Self {
+        let now = Utc::now();
+        Claims {
+            user_id,
+            role,
+            issued_at: now.timestamp() as usize,
+            expiry: (now + ACCESS_TOKEN_TTL).timestamp() as usize,
+            session_id: Some(session_id),
+        }
+    }
+
+    /// Sign the claims into an access token
+    pub fn encode(&self) -> jsonwebtoken::errors::Result<String> {
+        encode(
+            &Header::default(),
+            self,
+            &EncodingKey::from_secret(env_config().jwt_secret.as_bytes()),
+        )
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin
+    }
+
+    /// The user services are limited to, `None` for admins who can see everything
+    pub fn visible_to(&self) -> Option<u32> {
+        (!self.is_admin()).then_some(self.user_id)
+    }
+
+    /// Claims of an access or api token
+    pub async fn authenticate(pool: &DbPool, token: &str) -> Result<Self, AuthError> {
+        if token.starts_with(TOKEN_PREFIX) {
+            return match ApiToken::authenticate(pool, token).await {
+                Ok(Some((token, role))) => Ok(Claims {
+                    user_id: token.user_id,
+                    role,
+                    issued_at: token.created_at.timestamp() as usize,
+                    expiry: token
+                        .expires_at
+                        .map_or(usize::MAX, |at| at.timestamp() as usize),
+                    session_id: None,
+                }),
+                Ok(None) => Err(AuthError::InvalidToken),
+                Err(e) => {
+                    error!("Error checking api token: {e}");
+                    Err(AuthError::InvalidToken)
+                }
+            };
+        }
+
+        // Decode the user data
+        let mut claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(env_config().jwt_secret.as_bytes()),
+            &Validation::default(),
+        )
+        .map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => AuthError::ExpiredToken,
+            _ => AuthError::InvalidToken,
+        })?
+        .claims;
+        let session_id = claims.session_id.ok_or(AuthError::InvalidToken)?;
+
+        // Check the session was not revoked, and pick up role changes since the token was issued
+        claims.role = match Session::active_role(pool, session_id, claims.user_id).await {
+            Ok(Some(role)) => role,
+            Ok(None) => return Err(AuthError::SessionRevoked),
+            Err(e) => {
+                error!("Error checking session({session_id}): {e}");
+                return Err(AuthError::InvalidToken);
+            }
+        };
+
+        Ok(claims)
+    }
 }
 
 impl<S> FromRequestParts<S> for Claims
@@ -51,15 +151,57 @@ where
                     .ok_or(AuthError::MissingCredentials)?
             }
         };
-        // Decode the user data
-        let token_data = decode::<Claims>(
-            &token,
-            &DecodingKey::from_secret(env_config().jwt_secret.as_bytes()),
-            &Validation::default(),
+        let Extension(pool) = parts
+            .extract::<Extension<DbPool>>()
+            .await
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        Claims::authenticate(&pool, &token).await
+    }
+}
+
+const CHALLENGE_AUDIENCE: &str = "2fa";
+const CHALLENGE_TTL: TimeDelta = TimeDelta::minutes(5);
+
+/// Proof that a user passed the password step of logging in, exchanged along with a second
+/// factor for tokens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginChallenge {
+    #[serde(rename = "sub")]
+    pub user_id: u32,
+    #[serde(rename = "exp")]
+    pub expiry: usize,
+    aud: String,
+}
+
+impl LoginChallenge {
+    pub fn new(user_id: u32) -> Self {
+        LoginChallenge {
+            user_id,
+            expiry: (Utc::now() + CHALLENGE_TTL).timestamp() as usize,
+            aud: CHALLENGE_AUDIENCE.to_owned(),
+        }
+    }
+
+    pub fn encode(&self) -> jsonwebtoken::errors::Result<String> {
+        encode(
+            &Header::default(),
+            self,
+            &EncodingKey::from_secret(env_config().jwt_secret.as_bytes()),
         )
-        .map_err(|_| AuthError::InvalidToken)?;
+    }
 
-        Ok(token_data.claims)
+    /// Decode a challenge, `None` if it is invalid or expired
+    pub fn decode(token: &str) -> Option<Self> {
+        let mut validation = Validation::default();
+        validation.set_audience(&[CHALLENGE_AUDIENCE]);
+        decode::<LoginChallenge>(
+            token,
+            &DecodingKey::from_secret(env_config().jwt_secret.as_bytes()),
+            &validation,
+        )
+        .ok()
+        .map(|data| data.claims)
     }
 }
 
@@ -67,18 +209,13 @@ where
 pub enum AuthError {
     MissingCredentials,
     InvalidToken,
+    ExpiredToken,
+    SessionRevoked,
 }
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AuthError::MissingCredentials => (StatusCode::FORBIDDEN, "Missing credentials"),
-            AuthError::InvalidToken => (StatusCode::FORBIDDEN, "Invalid token"),
-        };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        ApiError::from(self).into_response()
     }
 }
 
@@ -102,6 +239,13 @@ pub fn verify_password(password: &str, hash: &str) -> bool {
         .is_ok()
 }
 
+/// Check a password against a throwaway hash, so logging in as an unknown user takes as long
+/// as with a wrong password
+pub fn verify_dummy_password(password: &str) {
+    static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| hash(String::new()));
+    verify_password(password, &DUMMY_HASH);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,6 +323,21 @@ mod tests {
         assert!(!verify_password("test_password", "not-a-valid-hash"));
     }
 
+    #[test]
+    fn test_claims_visibility() {
+        let claims = |role| Claims {
+            user_id: 7,
+            role,
+            issued_at: 0,
+            expiry: 0,
+            session_id: Some(1),
+        };
+
+        assert_eq!(claims(UserRole::Admin).visible_to(), None);
+        assert_eq!(claims(UserRole::Editor).visible_to(), Some(7));
+        assert_eq!(claims(UserRole::Viewer).visible_to(), Some(7));
+    }
+
     #[test]
     fn test_auth_error_display() {
         let missing_creds = AuthError::MissingCredentials;
@@ -1,48 +1,614 @@
-use std::{path::PathBuf, sync::OnceLock};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
-#[derive(Debug)]
+use axum::http::HeaderValue;
+use clap::{Args, Parser, ValueEnum};
+use reqwest::Url;
+use serde::{Deserialize, Serialize, Serializer};
+use tracing_subscriber::EnvFilter;
+
+/// Config file read when `--config` isn't given, if it exists
+const DEFAULT_CONFIG_FILE: &str = "stamon.toml";
+
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Stamon uptime monitor server.
+///
+/// Settings are read from flags, then the environment, then the config file.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file, defaults to `stamon.toml` when it exists
+    #[arg(short, long, env = "STAMON_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration, print it and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(flatten)]
+    pub layer: ConfigLayer,
+}
+
+/// One source of configuration, unset values fall through to the next source
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long, env = "BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+
+    /// Port to listen on [default: 3000]
+    #[arg(short, long, env = "PORT")]
+    pub port: Option<u16>,
+
+    /// Directory data is stored in [default: data]
+    #[arg(long, env = "DATA_PATH")]
+    pub data_path: Option<PathBuf>,
+
+    /// Sqlite database file [default: stamon.db in the data path]
+    #[arg(long, env = "DB_PATH")]
+    pub db_path: Option<PathBuf>,
+
+    /// Database url, replaces db_path [default: postgres://localhost/stamon with the postgres feature]
+    #[arg(long, env = "DB_URL")]
+    pub db_url: Option<String>,
+
+    /// Directory of the web frontend [default: assets]
+    #[arg(long, env = "ASSETS_PATH")]
+    pub assets_path: Option<PathBuf>,
+
+    /// Log filter, like `info` or `server=debug,tower_http=trace` [default: info]
+    #[arg(long, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+
+    /// Log output format [default: text]
+    #[arg(long, env = "LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Origins allowed to make cross-origin requests, comma separated
+    #[arg(long, env = "CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Option<Vec<String>>,
+
+    /// Seconds before a request is aborted [default: 10]
+    #[arg(long, env = "REQUEST_TIMEOUT")]
+    pub request_timeout: Option<u64>,
+
+    /// Checks run at the same time [default: 16]
+    #[arg(long, env = "WORKER_CONCURRENCY")]
+    pub worker_concurrency: Option<usize>,
+
+    /// Secret signing login tokens
+    #[arg(long, env = "JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+
+    /// PEM certificate chain, serves HTTPS when set with the key, reloaded when changed
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[arg(long, env = "TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Domains to get a certificate for with ACME, comma separated
+    #[arg(long, env = "ACME_DOMAINS", value_delimiter = ',')]
+    pub acme_domains: Option<Vec<String>>,
+
+    /// Contact email of the ACME account
+    #[arg(long, env = "ACME_EMAIL")]
+    pub acme_email: Option<String>,
+
+    /// ACME directory url [default: Let's Encrypt]
+    #[arg(long, env = "ACME_DIRECTORY")]
+    pub acme_directory: Option<String>,
+
+    /// Plain HTTP port redirecting to HTTPS and answering ACME challenges [default: 80 with ACME]
+    #[arg(long, env = "HTTP_PORT")]
+    pub http_port: Option<u16>,
+
+    /// Addresses of reverse proxies whose X-Forwarded-For header is trusted, comma separated
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Option<Vec<IpAddr>>,
+
+    /// Issuer url of the OpenID Connect provider, enables single sign-on
+    #[arg(long, env = "OIDC_ISSUER")]
+    pub oidc_issuer: Option<String>,
+
+    /// Client id registered with the provider
+    #[arg(long, env = "OIDC_CLIENT_ID")]
+    pub oidc_client_id: Option<String>,
+
+    /// Client secret, if the client is confidential
+    #[arg(long, env = "OIDC_CLIENT_SECRET", hide_env_values = true)]
+    pub oidc_client_secret: Option<String>,
+
+    /// Public url of `/api/oidc/callback`, as registered with the provider
+    #[arg(long, env = "OIDC_REDIRECT_URL")]
+    pub oidc_redirect_url: Option<String>,
+
+    /// Scopes requested, comma separated [default: openid,profile,email]
+    #[arg(long, env = "OIDC_SCOPES", value_delimiter = ',')]
+    pub oidc_scopes: Option<Vec<String>>,
+
+    /// Id token claim with the user's roles or groups [default: groups]
+    #[arg(long, env = "OIDC_ROLE_CLAIM")]
+    pub oidc_role_claim: Option<String>,
+
+    /// Claim values granting the admin role, comma separated
+    #[arg(long, env = "OIDC_ADMIN_VALUES", value_delimiter = ',')]
+    pub oidc_admin_values: Option<Vec<String>>,
+
+    /// Claim values granting the editor role, comma separated, others are viewers
+    #[arg(long, env = "OIDC_EDITOR_VALUES", value_delimiter = ',')]
+    pub oidc_editor_values: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl ConfigLayer {
+    /// Fill the values missing from `self` with the ones of `other`
+    fn or(self, other: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            bind_address: self.bind_address.or(other.bind_address),
+            port: self.port.or(other.port),
+            data_path: self.data_path.or(other.data_path),
+            db_path: self.db_path.or(other.db_path),
+            db_url: self.db_url.or(other.db_url),
+            assets_path: self.assets_path.or(other.assets_path),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            cors_origins: self.cors_origins.or(other.cors_origins),
+            request_timeout: self.request_timeout.or(other.request_timeout),
+            worker_concurrency: self.worker_concurrency.or(other.worker_concurrency),
+            jwt_secret: self.jwt_secret.or(other.jwt_secret),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            acme_domains: self.acme_domains.or(other.acme_domains),
+            acme_email: self.acme_email.or(other.acme_email),
+            acme_directory: self.acme_directory.or(other.acme_directory),
+            http_port: self.http_port.or(other.http_port),
+            trusted_proxies: self.trusted_proxies.or(other.trusted_proxies),
+            oidc_issuer: self.oidc_issuer.or(other.oidc_issuer),
+            oidc_client_id: self.oidc_client_id.or(other.oidc_client_id),
+            oidc_client_secret: self.oidc_client_secret.or(other.oidc_client_secret),
+            oidc_redirect_url: self.oidc_redirect_url.or(other.oidc_redirect_url),
+            oidc_scopes: self.oidc_scopes.or(other.oidc_scopes),
+            oidc_role_claim: self.oidc_role_claim.or(other.oidc_role_claim),
+            oidc_admin_values: self.oidc_admin_values.or(other.oidc_admin_values),
+            oidc_editor_values: self.oidc_editor_values.or(other.oidc_editor_values),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<ConfigLayer, Box<dyn std::error::Error>> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(toml::from_str(&content).map_err(|e| format!("{}: {e}", path.display()))?)
+    }
+}
+
+fn redacted<T, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("[redacted]")
+}
+
+/// A database url without its password
+fn redacted_password<S: Serializer>(url: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    match Url::parse(url) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("[redacted]"));
+            serializer.serialize_str(url.as_str())
+        }
+        _ => serializer.serialize_str(url),
+    }
+}
+
+/// The effective configuration
+#[derive(Debug, Serialize)]
 pub struct EnvConfig {
-    pub data_path: PathBuf,
+    pub bind_address: IpAddr,
+    pub port: u16,
 
+    pub data_path: PathBuf,
+    #[serde(serialize_with = "redacted_password")]
+    pub db_url: String,
     pub assets_path: PathBuf,
 
-    pub db_file: String,
-    pub port: u16,
+    pub log_level: String,
+    pub log_format: LogFormat,
+
+    pub cors_origins: Vec<String>,
+    /// Seconds before a request is aborted
+    pub request_timeout: u64,
+    pub worker_concurrency: usize,
+    /// Proxies the client address is taken from `X-Forwarded-For` for
+    pub trusted_proxies: Vec<IpAddr>,
 
+    #[serde(serialize_with = "redacted")]
     pub jwt_secret: String,
+
+    /// Built-in HTTPS, plain HTTP when `None`
+    pub tls: Option<TlsConfig>,
+
+    /// Single sign-on provider, only enabled when `oidc_issuer` is set
+    pub oidc: Option<OidcConfig>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Plain HTTP port redirecting to HTTPS
+    pub http_port: Option<u16>,
+    /// Issue the certificate with ACME, it is stored at `cert` and `key`
+    pub acme: Option<AcmeConfig>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub email: Option<String>,
+    pub directory: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(serialize_with = "redacted")]
+    pub client_secret: Option<String>,
+    /// Url of `/api/oidc/callback` as registered with the provider
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// Claim of the id token holding the user's roles or groups
+    pub role_claim: String,
+    /// Claim values granting the admin role
+    pub admin_values: Vec<String>,
+    /// Claim values granting the editor role, everyone else is a viewer
+    pub editor_values: Vec<String>,
+}
+
+impl OidcConfig {
+    /// Single sign-on settings of a layer, `None` unless the issuer is set
+    fn resolve(layer: &mut ConfigLayer) -> Result<Option<OidcConfig>, Box<dyn std::error::Error>> {
+        let Some(issuer) = layer.oidc_issuer.take() else {
+            return Ok(None);
+        };
+        let list = |values: Option<Vec<String>>| {
+            values
+                .unwrap_or_default()
+                .into_iter()
+                .map(|v| v.trim().to_owned())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+        };
+        let mut scopes = list(layer.oidc_scopes.take());
+        if scopes.is_empty() {
+            scopes = vec!["openid".into(), "profile".into(), "email".into()];
+        }
+
+        Ok(Some(OidcConfig {
+            issuer,
+            client_id: layer
+                .oidc_client_id
+                .take()
+                .ok_or("oidc_client_id: required with oidc_issuer")?,
+            client_secret: layer.oidc_client_secret.take(),
+            redirect_url: layer
+                .oidc_redirect_url
+                .take()
+                .ok_or("oidc_redirect_url: required with oidc_issuer")?,
+            scopes,
+            role_claim: layer.oidc_role_claim.take().unwrap_or("groups".to_string()),
+            admin_values: list(layer.oidc_admin_values.take()),
+            editor_values: list(layer.oidc_editor_values.take()),
+        }))
+    }
 }
 
 impl EnvConfig {
-    pub fn new() -> Result<EnvConfig, Box<dyn std::error::Error>> {
-        let data_path =
-            PathBuf::from(std::env::var("DATA_PATH").map_err(|e| format!("DATA_PATH: {e}"))?);
+    /// Read the config file and apply the flags and environment on top of it
+    pub fn load(cli: &Cli) -> Result<EnvConfig, Box<dyn std::error::Error>> {
+        let file = match &cli.config {
+            Some(path) => ConfigLayer::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                ConfigLayer::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => ConfigLayer::default(),
+        };
 
-        if !data_path.exists() {
-            std::fs::create_dir_all(&data_path)?;
+        Self::resolve(cli.layer.clone().or(file))
+    }
+
+    /// Fill in defaults and validate
+    fn resolve(mut layer: ConfigLayer) -> Result<EnvConfig, Box<dyn std::error::Error>> {
+        let oidc = OidcConfig::resolve(&mut layer)?;
+        let data_path = layer.data_path.unwrap_or("data".into());
+        let db_url = match layer.db_url {
+            Some(url) => url,
+            None if cfg!(feature = "postgres") => "postgres://localhost/stamon".to_string(),
+            None => {
+                let db_path = layer.db_path.unwrap_or(data_path.join("stamon.db"));
+                format!(
+                    "sqlite://{}",
+                    db_path.to_str().ok_or("db_path: not valid unicode")?
+                )
+            }
+        };
+        // the database is chosen when building, with the `postgres` feature
+        let scheme = if cfg!(feature = "postgres") {
+            "postgres"
+        } else {
+            "sqlite"
+        };
+        if !db_url.starts_with(scheme) {
+            return Err(format!("db_url: this build stores data in {scheme}").into());
         }
 
-        let assets_path =
-            PathBuf::from(std::env::var("ASSETS_PATH").unwrap_or("assets".to_string()));
+        let log_level = layer.log_level.unwrap_or("info".to_string());
+        EnvFilter::try_new(&log_level).map_err(|e| format!("log_level: {e}"))?;
 
-        let db_file = format!(
-            "sqlite://{}/stamon.db",
-            data_path.as_os_str().to_str().unwrap()
-        );
-        let jwt_secret = std::env::var("JWT_SECRET").map_err(|e| format!("JWT_SECRET: {e}"))?;
+        // the frontend dev server
+        let default_origins = if cfg!(debug_assertions) {
+            vec!["http://localhost:5173".to_string()]
+        } else {
+            vec![]
+        };
+        let cors_origins = layer.cors_origins.unwrap_or(default_origins);
+        for origin in &cors_origins {
+            if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || HeaderValue::from_str(origin).is_err()
+            {
+                return Err(format!("cors_origins: invalid origin {origin:?}").into());
+            }
+        }
+
+        let request_timeout = layer.request_timeout.unwrap_or(10);
+        if request_timeout == 0 {
+            return Err("request_timeout: must be at least 1 second".into());
+        }
+        let worker_concurrency = layer.worker_concurrency.unwrap_or(16);
+        if worker_concurrency == 0 {
+            return Err("worker_concurrency: must be at least 1".into());
+        }
+
+        let jwt_secret = layer
+            .jwt_secret
+            .filter(|s| !s.is_empty())
+            .ok_or("jwt_secret: set with JWT_SECRET or in the config file")?;
+
+        let acme = match layer.acme_domains {
+            Some(domains) if !domains.is_empty() => Some(AcmeConfig {
+                domains,
+                email: layer.acme_email,
+                directory: layer.acme_directory.unwrap_or(LETS_ENCRYPT.to_string()),
+            }),
+            _ => None,
+        };
+        let tls = match (layer.tls_cert, layer.tls_key, acme) {
+            (Some(cert), Some(key), acme) => Some(TlsConfig {
+                cert,
+                key,
+                http_port: layer.http_port.or(acme.as_ref().map(|_| 80)),
+                acme,
+            }),
+            (None, None, Some(acme)) => Some(TlsConfig {
+                cert: data_path.join("tls").join("cert.pem"),
+                key: data_path.join("tls").join("key.pem"),
+                http_port: Some(layer.http_port.unwrap_or(80)),
+                acme: Some(acme),
+            }),
+            (None, None, None) => None,
+            _ => return Err("tls_cert and tls_key must be set together".into()),
+        };
 
         Ok(EnvConfig {
+            bind_address: layer
+                .bind_address
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: layer.port.unwrap_or(3000),
             data_path,
-            assets_path,
-            db_file,
-            port: 3000,
+            db_url,
+            assets_path: layer.assets_path.unwrap_or("assets".into()),
+            log_level,
+            log_format: layer.log_format.unwrap_or_default(),
+            cors_origins,
+            request_timeout,
+            worker_concurrency,
+            trusted_proxies: layer.trusted_proxies.unwrap_or_default(),
             jwt_secret,
+            tls,
+            oidc,
         })
     }
 }
 
+static CONFIG: OnceLock<EnvConfig> = OnceLock::new();
+
+/// Use `config` for the rest of the run, it can only be set once
+pub fn init(config: EnvConfig) -> &'static EnvConfig {
+    CONFIG.get_or_init(|| config)
+}
+
+/// The configuration set with [`init`], or read from the environment when it wasn't
 pub fn env_config() -> &'static EnvConfig {
-    static CONFIG: OnceLock<EnvConfig> = OnceLock::new();
     CONFIG.get_or_init(|| {
-        EnvConfig::new().unwrap_or_else(|e| panic!("Error loading env config: {e}"))
+        EnvConfig::load(&Cli::parse_from(["stamon"]))
+            .unwrap_or_else(|e| panic!("Error loading env config: {e}"))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(toml: &str) -> ConfigLayer {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn layers_precedence() {
+        let file = layer(
+            r#"
+            port = 8080
+            bind_address = "127.0.0.1"
+            log_format = "json"
+            jwt_secret = "from-file"
+            "#,
+        );
+        let cli = ConfigLayer {
+            port: Some(9090),
+            ..Default::default()
+        };
+
+        let config = EnvConfig::resolve(cli.or(file)).unwrap();
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.bind_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.jwt_secret, "from-file");
+        // defaults
+        #[cfg(not(feature = "postgres"))]
+        assert_eq!(config.db_url, "sqlite://data/stamon.db");
+        assert_eq!(config.request_timeout, 10);
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(toml::from_str::<ConfigLayer>("prot = 8080").is_err());
+
+        let secret = || Some("secret".to_string());
+        assert!(EnvConfig::resolve(ConfigLayer::default()).is_err());
+        assert!(
+            EnvConfig::resolve(ConfigLayer {
+                jwt_secret: secret(),
+                cors_origins: Some(vec!["localhost".into()]),
+                ..Default::default()
+            })
+            .is_err()
+        );
+        assert!(
+            EnvConfig::resolve(ConfigLayer {
+                jwt_secret: secret(),
+                worker_concurrency: Some(0),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn tls_config() {
+        let secret = || Some("secret".to_string());
+        let config = EnvConfig::resolve(ConfigLayer {
+            jwt_secret: secret(),
+            acme_domains: Some(vec!["status.example.com".into()]),
+            ..Default::default()
+        })
+        .unwrap();
+        let tls = config.tls.unwrap();
+        // issued certificates are kept with the data
+        assert_eq!(tls.cert, PathBuf::from("data/tls/cert.pem"));
+        assert_eq!(tls.http_port, Some(80));
+        assert_eq!(tls.acme.unwrap().directory, LETS_ENCRYPT);
+
+        let config = EnvConfig::resolve(ConfigLayer {
+            jwt_secret: secret(),
+            tls_cert: Some("cert.pem".into()),
+            tls_key: Some("key.pem".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.http_port, None);
+        assert!(tls.acme.is_none());
+
+        assert!(
+            EnvConfig::resolve(ConfigLayer {
+                jwt_secret: secret(),
+                tls_cert: Some("cert.pem".into()),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn database_url() {
+        let with_url = |url: &str| {
+            EnvConfig::resolve(ConfigLayer {
+                jwt_secret: Some("secret".into()),
+                db_url: Some(url.into()),
+                ..Default::default()
+            })
+        };
+        // only the database the server was built for can be used
+        if cfg!(feature = "postgres") {
+            assert!(with_url("postgres://db/stamon").is_ok());
+            assert!(with_url("sqlite://stamon.db").is_err());
+        } else {
+            assert!(with_url("sqlite://stamon.db").is_ok());
+            assert!(with_url("postgres://db/stamon").is_err());
+        }
+
+        let url = "postgres://stamon:hunter2@db/stamon";
+        assert_eq!(
+            redacted_password(url, serde_json::value::Serializer).unwrap(),
+            "postgres://stamon:%5Bredacted%5D@db/stamon"
+        );
+    }
+
+    #[test]
+    fn print_config_hides_secrets() {
+        let config = EnvConfig::resolve(ConfigLayer {
+            jwt_secret: Some("secret".into()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let printed = toml::to_string(&config).unwrap();
+        assert!(printed.contains("jwt_secret = \"[redacted]\""));
+        assert!(!printed.contains("\"secret\""));
+    }
+
+    #[test]
+    fn oidc_config() {
+        let oidc = |toml: &str| EnvConfig::resolve(layer(toml)).map(|config| config.oidc);
+        assert!(oidc(r#"jwt_secret = "s""#).unwrap().is_none());
+
+        let config = oidc(
+            r#"
+            jwt_secret = "s"
+            oidc_issuer = "https://idp.example.com"
+            oidc_client_id = "stamon"
+            oidc_client_secret = "hunter2"
+            oidc_redirect_url = "https://status.example.com/api/oidc/callback"
+            oidc_admin_values = ["ops"]
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.client_id, "stamon");
+        assert_eq!(config.scopes, ["openid", "profile", "email"]);
+        assert_eq!(config.role_claim, "groups");
+        assert_eq!(config.admin_values, ["ops"]);
+        assert!(config.editor_values.is_empty());
+        let printed = toml::to_string(&config).unwrap();
+        assert!(!printed.contains("hunter2"));
+
+        let missing = oidc(
+            r#"
+            jwt_secret = "s"
+            oidc_issuer = "https://idp.example.com"
+            oidc_redirect_url = "https://status.example.com/api/oidc/callback"
+            "#,
+        );
+        assert!(missing.unwrap_err().to_string().contains("oidc_client_id"));
+    }
+}
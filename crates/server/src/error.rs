@@ -0,0 +1,234 @@
+use axum::{
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use tracing::error;
+
+use crate::{auth::AuthError, validation::FieldErrors};
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+/// Errors returned by the api handlers.
+///
+/// Every error has the same JSON body, `{"code": "not_found", "error": "Service not found"}`,
+/// clients can rely on `code` while `error` is meant for people.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    /// The request body or path could not be read, with the status axum rejected it with
+    InvalidRequest(StatusCode, String),
+    /// Some fields of the request body are invalid
+    Validation(FieldErrors),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// Seconds to wait before trying again
+    TooManyRequests(i64),
+    /// A service the server relies on, like the database, can't be reached
+    Unavailable(String),
+    /// Logged, the details are not sent to the client
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidRequest(status, _) => *status,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::InvalidRequest(..) => "invalid_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::InvalidRequest(_, message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Unavailable(message) => message,
+            ApiError::Validation(_) => "Invalid fields",
+            ApiError::TooManyRequests(_) => "Too many requests, try again later",
+            ApiError::Internal(_) => "Internal server error",
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Internal(e) => write!(f, "{e}"),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Internal(e) = &self {
+            error!("{e}");
+        }
+        let mut body = json!({ "code": self.code(), "error": self.message() });
+        match &self {
+            ApiError::Validation(fields) => body["fields"] = json!(fields),
+            ApiError::TooManyRequests(seconds) => body["retry_after"] = (*seconds).into(),
+            _ => (),
+        }
+
+        let mut res = (self.status(), axum::Json(body)).into_response();
+        match self {
+            ApiError::TooManyRequests(seconds) => {
+                res.headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+            }
+            ApiError::Unauthorized(_) => {
+                res.headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            _ => (),
+        }
+        res
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => ApiError::NotFound("Not found".into()),
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                ApiError::Conflict("Already exists".into())
+            }
+            sqlx::Error::Database(db) if db.is_foreign_key_violation() => {
+                ApiError::BadRequest("Refers to a missing item".into())
+            }
+            sqlx::Error::Database(db) if db.is_check_violation() => {
+                ApiError::BadRequest("Invalid value".into())
+            }
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                error!("{e}");
+                ApiError::Unavailable("Database unavailable".into())
+            }
+            _ => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<FieldErrors> for ApiError {
+    fn from(errors: FieldErrors) -> Self {
+        ApiError::Validation(errors)
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::MissingCredentials => ApiError::Unauthorized("Missing credentials".into()),
+            AuthError::InvalidToken => ApiError::Unauthorized("Invalid token".into()),
+            AuthError::ExpiredToken => ApiError::Unauthorized("Token expired".into()),
+            AuthError::SessionRevoked => ApiError::Unauthorized("Session revoked".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use serde_json::Value;
+
+    use super::*;
+
+    async fn body(err: ApiError) -> (StatusCode, Value) {
+        let res = err.into_response();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn error_envelope() {
+        let (status, value) = body(ApiError::NotFound("Service not found".into())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            value,
+            json!({ "code": "not_found", "error": "Service not found" })
+        );
+
+        // internal details are only logged
+        let (status, value) = body(ApiError::Internal("disk I/O error".into())).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(value["error"], "Internal server error");
+
+        let res = ApiError::TooManyRequests(30).into_response();
+        assert_eq!(res.headers()[header::RETRY_AFTER], "30");
+
+        let mut fields = FieldErrors::default();
+        fields.add("url", "must be an IP address");
+        let (status, value) = body(fields.into()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(value["fields"], json!({ "url": ["must be an IP address"] }));
+    }
+
+    #[test]
+    fn auth_errors() {
+        // clients refresh their token on 401, roles are checked with 403
+        for e in [
+            AuthError::MissingCredentials,
+            AuthError::InvalidToken,
+            AuthError::ExpiredToken,
+            AuthError::SessionRevoked,
+        ] {
+            let res = ApiError::from(e).into_response();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+
+    #[test]
+    fn sqlx_errors() {
+        assert_eq!(
+            ApiError::from(sqlx::Error::RowNotFound).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            ApiError::from(sqlx::Error::PoolTimedOut).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ApiError::from(sqlx::Error::ColumnNotFound("id".into())).code(),
+            "internal"
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn unique_violation_is_conflict(pool: crate::models::DbPool) {
+        let insert = || sqlx::query("INSERT INTO Tags (name) VALUES ('prod')").execute(&pool);
+        insert().await.unwrap();
+        let e = insert().await.unwrap_err();
+        assert_eq!(ApiError::from(e).code(), "conflict");
+    }
+}
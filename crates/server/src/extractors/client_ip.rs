@@ -0,0 +1,86 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, request::Parts},
+};
+
+use crate::{config::env_config, error::ApiError};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// Address of the client, as forwarded by the trusted proxies in front of the server
+pub struct ClientIp(pub IpAddr);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| ApiError::Internal(format!("Missing connection info: {e}")))?;
+        Ok(Self(client_ip(
+            addr.ip(),
+            &parts.headers,
+            &env_config().trusted_proxies,
+        )))
+    }
+}
+
+/// The peer address, unless it is a trusted proxy: then the last address of `X-Forwarded-For`
+/// that isn't one, as proxies append the address they received the request from
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for ip in forwarded.into_iter().rev() {
+        match ip.trim().parse() {
+            Ok(ip) if trusted_proxies.contains(&ip) => continue,
+            Ok(ip) => return ip,
+            // nothing before an invalid entry can be trusted
+            Err(_) => break,
+        }
+    }
+    peer
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn forwarded_client_ip() {
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("198.51.100.1, 203.0.113.7, 10.0.0.3"),
+        );
+        let trusted = ["10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap()];
+
+        // the first address can be set by the client, only the ones proxies added count
+        assert_eq!(client_ip(proxy, &headers, &trusted), client);
+        // the header is ignored unless the request comes from a trusted proxy
+        assert_eq!(client_ip(client, &headers, &trusted), client);
+        assert_eq!(client_ip(proxy, &headers, &[]), proxy);
+        assert_eq!(client_ip(proxy, &HeaderMap::new(), &trusted), proxy);
+
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("203.0.113.7, unknown"),
+        );
+        assert_eq!(client_ip(proxy, &headers, &trusted), proxy);
+    }
+}
@@ -1,10 +1,10 @@
 use axum::{
-    RequestPartsExt,
-    extract::{FromRequest, MatchedPath, Request, rejection::JsonRejection},
-    http::StatusCode,
+    extract::{FromRequest, Request, rejection::JsonRejection},
     response::{IntoResponse, Response},
 };
-use serde_json::{Value, json};
+use serde_json::Value;
+
+use crate::{error::ApiError, validation::Validate};
 
 // We define our own `Json` extractor that customizes the error from `axum::Json`
 pub struct Json<T>(pub T);
@@ -20,36 +20,35 @@ where
     axum::Json<T>: FromRequest<S, Rejection = JsonRejection>,
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, axum::Json<Value>);
+    type Rejection = ApiError;
 
     async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
-        let (mut parts, body) = req.into_parts();
-
-        // We can use other extractors to provide better rejection messages.
-        // For example, here we are using `axum::extract::MatchedPath` to
-        // provide a better error message.
-        //
-        // Have to run that first since `Json` extraction consumes the request.
-        let path = parts
-            .extract::<MatchedPath>()
-            .await
-            .map(|path| path.as_str().to_owned())
-            .ok();
-
-        let req = Request::from_parts(parts, body);
-
         match axum::Json::<T>::from_request(req, state).await {
             Ok(value) => Ok(Self(value.0)),
-            // convert the error from `axum::Json` into whatever we want
-            Err(rejection) => {
-                let payload = json!({
-                    "message": rejection.body_text(),
-                    "origin": "json_extractor",
-                    "path": path,
-                });
-
-                Err((rejection.status(), axum::Json(payload)))
-            }
+            // keep the status, like `415` for a missing content type, with our error body
+            Err(rejection) => Err(ApiError::InvalidRequest(
+                rejection.status(),
+                rejection.body_text(),
+            )),
         }
     }
 }
+
+/// Like [`Json`], and responds with `422` listing the invalid fields unless the value passes
+/// [`Validate`]
+pub struct ValidJson<T>(pub T);
+
+impl<S, T> FromRequest<S> for ValidJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = ApiError>,
+    T: Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        value.validate()?;
+        Ok(Self(value))
+    }
+}
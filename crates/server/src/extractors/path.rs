@@ -2,7 +2,9 @@ use axum::{
     extract::{FromRequestParts, path::ErrorKind, rejection::PathRejection},
     http::{StatusCode, request::Parts},
 };
-use serde::{Serialize, de::DeserializeOwned};
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
 
 // We define our own `Path` extractor that customizes the error from `axum::extract::Path`
 pub struct Path<T>(pub T);
@@ -13,90 +15,30 @@ where
     T: DeserializeOwned + Send,
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, axum::Json<PathError>);
+    type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         match axum::extract::Path::<T>::from_request_parts(parts, state).await {
             Ok(value) => Ok(Self(value.0)),
-            Err(rejection) => {
-                let (status, body) = match rejection {
-                    PathRejection::FailedToDeserializePathParams(inner) => {
-                        let mut status = StatusCode::BAD_REQUEST;
-
-                        let kind = inner.into_kind();
-                        let body = match &kind {
-                            ErrorKind::WrongNumberOfParameters { .. } => PathError {
-                                message: kind.to_string(),
-                                location: None,
-                            },
-
-                            ErrorKind::ParseErrorAtKey { key, .. } => PathError {
-                                message: kind.to_string(),
-                                location: Some(key.clone()),
-                            },
-
-                            ErrorKind::ParseErrorAtIndex { index, .. } => PathError {
-                                message: kind.to_string(),
-                                location: Some(index.to_string()),
-                            },
-
-                            ErrorKind::ParseError { .. } => PathError {
-                                message: kind.to_string(),
-                                location: None,
-                            },
-
-                            ErrorKind::InvalidUtf8InPathParam { key } => PathError {
-                                message: kind.to_string(),
-                                location: Some(key.clone()),
-                            },
-
-                            ErrorKind::UnsupportedType { .. } => {
-                                // this error is caused by the programmer using an unsupported type
-                                // (such as nested maps) so respond with `500` instead
-                                status = StatusCode::INTERNAL_SERVER_ERROR;
-                                PathError {
-                                    message: kind.to_string(),
-                                    location: None,
-                                }
-                            }
-
-                            ErrorKind::Message(msg) => PathError {
-                                message: msg.clone(),
-                                location: None,
-                            },
-
-                            _ => PathError {
-                                message: format!("Unhandled deserialization error: {kind}"),
-                                location: None,
-                            },
-                        };
-
-                        (status, body)
+            Err(PathRejection::FailedToDeserializePathParams(inner)) => {
+                match inner.into_kind() {
+                    // this error is caused by the programmer using an unsupported type
+                    // (such as nested maps) so respond with `500` instead
+                    kind @ ErrorKind::UnsupportedType { .. } => {
+                        Err(ApiError::Internal(kind.to_string()))
                     }
-                    PathRejection::MissingPathParams(error) => (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        PathError {
-                            message: error.to_string(),
-                            location: None,
-                        },
-                    ),
-                    _ => (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        PathError {
-                            message: format!("Unhandled path rejection: {rejection}"),
-                            location: None,
-                        },
-                    ),
-                };
-
-                Err((status, axum::Json(body)))
+                    ErrorKind::Message(msg) => {
+                        Err(ApiError::InvalidRequest(StatusCode::BAD_REQUEST, msg))
+                    }
+                    kind => Err(ApiError::InvalidRequest(
+                        StatusCode::BAD_REQUEST,
+                        kind.to_string(),
+                    )),
+                }
             }
+            Err(rejection) => Err(ApiError::Internal(format!(
+                "Unhandled path rejection: {rejection}"
+            ))),
         }
     }
 }
-
-#[derive(Serialize)]
-pub struct PathError {
-    message: String,
-    location: Option<String>,
-}
@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use regex::Regex;
+use scraper::{Html, Selector};
+use tracing::{debug, error, warn};
+
+use super::Service;
+use crate::models::{
+    DbPool,
+    log::{LogForCreate, Status},
+    snapshot::{ContentSnapshot, content_hash},
+};
+
+/// Body of the page of a service, checking the status code like http services do
+async fn fetch(svc: &Service) -> Result<String, String> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(u64::from(svc.timeout)))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let res = client.get(&svc.url).send().await.map_err(|e| {
+        warn!("Failed to get: {e}");
+        e.to_string()
+    })?;
+    let status = res.status();
+    let expected = match svc.expected_code {
+        Some(code) => status.as_u16() == code,
+        None => status.is_success(),
+    };
+    if !expected {
+        return Err(format!("Unexpected status {status}"));
+    }
+    res.text()
+        .await
+        .map_err(|e| format!("Failed to read the body: {e}"))
+}
+
+/// Whether the body contains the keyword, or matches it when it is a regex
+fn find_keyword(body: &str, keyword: &str, regex: bool) -> Result<bool, String> {
+    if regex {
+        let re = Regex::new(keyword).map_err(|e| format!("Invalid keyword regex: {e}"))?;
+        Ok(re.is_match(body))
+    } else {
+        Ok(body.contains(keyword))
+    }
+}
+
+/// The part of the body a content change service compares: the text of the elements matching
+/// the CSS selector, then what the pattern extracts from it, one match per line
+fn extract(
+    body: &str,
+    css_selector: Option<&str>,
+    pattern: Option<&str>,
+) -> Result<String, String> {
+    let mut content = match css_selector {
+        Some(selector) => {
+            let selector =
+                Selector::parse(selector).map_err(|e| format!("Invalid CSS selector: {e}"))?;
+            Html::parse_document(body)
+                .select(&selector)
+                .map(|element| element.text().collect::<String>().trim().to_owned())
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        None => body.to_owned(),
+    };
+    if let Some(pattern) = pattern {
+        let re = Regex::new(pattern).map_err(|e| format!("Invalid extract pattern: {e}"))?;
+        content = re
+            .captures_iter(&content)
+            .filter_map(|caps| caps.get(1).or_else(|| caps.get(0)))
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+    Ok(content)
+}
+
+#[tracing::instrument(skip(svc), fields(name = svc.name, url = svc.url))]
+pub async fn keyword(svc: Service) -> LogForCreate {
+    let now = Instant::now();
+    let time = Some(Utc::now());
+    let keyword = svc.keyword.as_deref().unwrap_or_default();
+
+    let (status, message) = match fetch(&svc).await {
+        Ok(body) => match find_keyword(&body, keyword, svc.keyword_regex) {
+            Ok(found) if found != svc.invert => (Status::Up, None),
+            Ok(true) => (Status::Down, Some(format!("Found {keyword}"))),
+            Ok(false) => (Status::Down, Some(format!("{keyword} not found"))),
+            Err(e) => (Status::Failed, Some(e)),
+        },
+        Err(e) => (Status::Down, Some(e)),
+    };
+    LogForCreate {
+        status,
+        message,
+        service_id: svc.id,
+        duration: now.elapsed().as_millis() as u32,
+        time,
+        ..Default::default()
+    }
+}
+
+#[tracing::instrument(skip(svc, pool), fields(name = svc.name, url = svc.url))]
+pub async fn content_change(svc: Service, pool: &DbPool) -> LogForCreate {
+    let now = Instant::now();
+    let time = Some(Utc::now());
+    let log = |status, message: Option<String>| LogForCreate {
+        status,
+        message,
+        service_id: svc.id,
+        duration: now.elapsed().as_millis() as u32,
+        time,
+        ..Default::default()
+    };
+
+    let body = match fetch(&svc).await {
+        Ok(body) => body,
+        Err(e) => return log(Status::Down, Some(e)),
+    };
+    let content = match extract(
+        &body,
+        svc.css_selector.as_deref(),
+        svc.extract_pattern.as_deref(),
+    ) {
+        Ok(content) => content,
+        Err(e) => return log(Status::Failed, Some(e)),
+    };
+
+    // the first snapshot is the baseline, nothing changed yet
+    let first = match ContentSnapshot::baseline(pool, svc.id).await {
+        Ok(Some(baseline)) if baseline.hash == content_hash(&content) => {
+            return log(Status::Up, None);
+        }
+        Ok(baseline) => baseline.is_none(),
+        Err(e) => {
+            error!("Failed to get the content baseline: {e}");
+            return log(Status::Failed, Some(e.to_string()));
+        }
+    };
+    match ContentSnapshot::insert(pool, svc.id, &content, svc.snapshot_limit).await {
+        Ok(_) if first => {
+            debug!("Saved the content baseline");
+            log(Status::Up, None)
+        }
+        Ok(_) => log(Status::Down, Some("Content changed".into())),
+        Err(e) => {
+            error!("Failed to save the content snapshot: {e}");
+            log(Status::Failed, Some(e.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords() {
+        let body = "<p>Status: all systems operational</p>";
+        assert_eq!(find_keyword(body, "operational", false), Ok(true));
+        assert_eq!(find_keyword(body, "outage", false), Ok(false));
+        assert_eq!(find_keyword(body, r"Status: \w+", true), Ok(true));
+        assert_eq!(find_keyword(body, "all.systems", false), Ok(false));
+        assert!(find_keyword(body, "(", true).is_err());
+    }
+
+    #[test]
+    fn extract_content() {
+        let body = r#"<html><body>
+            <span class="price"> 10 EUR </span><span>ad 123</span><span class="price">12 EUR</span>
+        </body></html>"#;
+        assert_eq!(
+            extract(body, Some(".price"), None),
+            Ok("10 EUR\n12 EUR".into())
+        );
+        assert_eq!(
+            extract(body, Some(".price"), Some(r"(\d+) EUR")),
+            Ok("10\n12".into())
+        );
+        // without a group the whole match is kept
+        assert_eq!(extract("a1 b2", None, Some(r"\w\d")), Ok("a1\nb2".into()));
+        assert_eq!(extract("same", None, None), Ok("same".into()));
+        assert!(extract(body, Some("<"), None).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use reqwest::Url;
+use sqlx::{
+    ConnectOptions, Connection, Executor, MySqlConnection, PgConnection, Row,
+    sqlite::SqliteConnectOptions,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::{debug, warn};
+
+use super::Service;
+use crate::{
+    models::{
+        log::{LogForCreate, Status},
+        service::ServiceType,
+    },
+    validation,
+};
+
+const DEFAULT_REDIS_PORT: u16 = 6379;
+/// Most bytes read for a Redis reply
+const MAX_REPLY_SIZE: u64 = 512 * 1024;
+/// Most elements of a Redis reply array
+const MAX_REPLY_ITEMS: i64 = 1024;
+/// Deepest nesting of Redis reply arrays
+const MAX_REPLY_DEPTH: u8 = 8;
+
+/// First column of a row as text, trying the types queries usually return
+macro_rules! scalar {
+    ($row:expr, $($ty:ty),+) => {{
+        let row = $row;
+        None$(.or_else(|| {
+            row.try_get::<Option<$ty>, _>(0)
+                .ok()
+                .map(|value| value.map_or_else(|| "NULL".to_owned(), |v| v.to_string()))
+        }))+
+        .ok_or_else(|| "Unsupported result type".to_owned())
+    }};
+}
+
+/// Connect with a sqlx driver and run the query in a read-only transaction, `None` when it
+/// returned no rows. The transaction is rolled back when the connection closes.
+macro_rules! sql_query {
+    ($connect:expr, $begin:expr, $query:expr) => {{
+        let mut conn = $connect.await.map_err(|e| e.to_string())?;
+        conn.execute($begin).await.map_err(|e| e.to_string())?;
+        // a prepared statement, so the query can't end the transaction
+        let row = sqlx::query($query)
+            .fetch_optional(&mut conn)
+            .await
+            .map_err(|e| e.to_string())?;
+        if let Err(e) = conn.close().await {
+            debug!("Failed to close the connection: {e}");
+        }
+        row.map(|row| scalar!(row, String, i64, i32, i16, i8, f64, f32, bool))
+            .transpose()
+    }};
+}
+
+/// Run the query of a database service, returning its scalar result
+async fn run(svc: &Service) -> Result<Option<String>, String> {
+    let query = svc.query.as_deref().filter(|q| !q.trim().is_empty());
+    let sql = query.unwrap_or("SELECT 1");
+    match svc.service_type {
+        ServiceType::Postgres => {
+            sql_query!(PgConnection::connect(&svc.url), "BEGIN READ ONLY", sql)
+        }
+        ServiceType::Mysql => sql_query!(
+            MySqlConnection::connect(&svc.url),
+            "START TRANSACTION READ ONLY",
+            sql
+        ),
+        ServiceType::Sqlite => {
+            // checked again in case the server's data moved since the service was saved
+            validation::sqlite_url(&svc.url)?;
+            let options = SqliteConnectOptions::from_str(&svc.url)
+                .map_err(|e| e.to_string())?
+                .read_only(true);
+            sql_query!(options.connect(), "BEGIN", sql)
+        }
+        ServiceType::Redis => redis(&svc.url, query.unwrap_or("PING")).await.map(Some),
+        _ => Err(format!("{:?} is not a database service", svc.service_type)),
+    }
+}
+
+/// Send a command to a Redis server, after authenticating and selecting the database of
+/// the url. The arguments of the command are separated by whitespace.
+async fn redis(url: &str, command: &str) -> Result<String, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid url: {e}"))?;
+    let host = url.host_str().ok_or("Missing host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let stream = TcpStream::connect((host, url.port().unwrap_or(DEFAULT_REDIS_PORT)))
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut stream = BufReader::new(stream);
+
+    if let Some(password) = url.password() {
+        match url.username() {
+            "" => redis_command(&mut stream, &["AUTH", password]).await?,
+            user => redis_command(&mut stream, &["AUTH", user, password]).await?,
+        };
+    }
+    let db = url.path().trim_start_matches('/');
+    if !db.is_empty() {
+        redis_command(&mut stream, &["SELECT", db]).await?;
+    }
+    let args: Vec<&str> = command.split_whitespace().collect();
+    redis_command(&mut stream, &args).await
+}
+
+async fn redis_command(stream: &mut BufReader<TcpStream>, args: &[&str]) -> Result<String, String> {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+    }
+    stream
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    read_reply(&mut (&mut *stream).take(MAX_REPLY_SIZE), 0).await
+}
+
+/// Read a RESP reply, arrays are joined one element per line and errors are returned as such
+async fn read_reply<R>(stream: &mut R, depth: u8) -> Result<String, String>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    let mut line = String::new();
+    stream
+        .read_line(&mut line)
+        .await
+        .map_err(|e| e.to_string())?;
+    let line = line.trim_end_matches("\r\n");
+    let (kind, rest) = line.split_at_checked(1).ok_or("Connection closed")?;
+    let len = || {
+        rest.parse::<i64>()
+            .map_err(|_| format!("Invalid reply length {rest}"))
+    };
+    match kind {
+        "+" | ":" => Ok(rest.to_owned()),
+        "-" => Err(rest.to_owned()),
+        "_" => Ok("NULL".to_owned()),
+        "$" => {
+            let Ok(len) = usize::try_from(len()?) else {
+                return Ok("NULL".to_owned());
+            };
+            if len as u64 > MAX_REPLY_SIZE {
+                return Err(format!("Reply of {len} bytes is too large"));
+            }
+            let mut data = vec![0; len + 2];
+            stream
+                .read_exact(&mut data)
+                .await
+                .map_err(|e| e.to_string())?;
+            data.truncate(len);
+            String::from_utf8(data).map_err(|_| "Reply is not UTF-8".to_owned())
+        }
+        "*" => {
+            let len = len()?;
+            if len > MAX_REPLY_ITEMS {
+                return Err(format!("Reply of {len} items is too large"));
+            }
+            if depth >= MAX_REPLY_DEPTH {
+                return Err("Reply is nested too deep".to_owned());
+            }
+            let mut items = Vec::new();
+            for _ in 0..len.max(0) {
+                items.push(Box::pin(read_reply(stream, depth + 1)).await?);
+            }
+            Ok(items.join("\n"))
+        }
+        _ => Err(format!("Unexpected reply {line}")),
+    }
+}
+
+#[tracing::instrument(skip(svc), fields(name = svc.name, service_type = ?svc.service_type))]
+pub async fn query(svc: Service) -> LogForCreate {
+    let now = Instant::now();
+    let time = Some(Utc::now());
+    let timeout = Duration::from_secs(u64::from(svc.timeout));
+
+    let result = match tokio::time::timeout(timeout, run(&svc)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Timed out after {}s", svc.timeout)),
+    };
+    let duration = now.elapsed().as_millis() as u32;
+    let (status, message) = match (result, svc.expected_payload.as_deref()) {
+        // the result is not shown, it could hold data the user can't otherwise see
+        (Ok(value), Some(expected)) if value.as_deref().map(str::trim) != Some(expected.trim()) => {
+            (
+                Status::Down,
+                Some(format!("Result does not match the expected {expected}")),
+            )
+        }
+        (Ok(_), _) => (Status::Up, None),
+        (Err(e), _) => {
+            warn!("Database check failed: {e}");
+            (Status::Down, Some(e))
+        }
+    };
+    LogForCreate {
+        status,
+        message,
+        service_id: svc.id,
+        duration,
+        time,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqliteConnection;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn service(service_type: ServiceType, url: &str, query: Option<&str>) -> Service {
+        Service {
+            service_type,
+            url: url.into(),
+            query: query.map(Into::into),
+            timeout: 5,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sqlite_query() {
+        let path = std::env::temp_dir().join(format!("stamon-monitor-{}.db", std::process::id()));
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        let mut conn = SqliteConnection::connect(&url).await.unwrap();
+        sqlx::query("CREATE TABLE jobs (name TEXT); INSERT INTO jobs VALUES ('backup')")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        conn.close().await.unwrap();
+
+        let svc = service(ServiceType::Sqlite, &url, None);
+        assert_eq!(run(&svc).await, Ok(Some("1".into())));
+
+        let svc = Service {
+            expected_payload: Some("2".into()),
+            ..service(ServiceType::Sqlite, &url, Some("SELECT count(*) FROM jobs"))
+        };
+        let log = query(svc).await;
+        assert_eq!(log.status, Status::Down);
+        assert_eq!(
+            log.message.as_deref(),
+            Some("Result does not match the expected 2")
+        );
+
+        let svc = Service {
+            expected_payload: Some("backup".into()),
+            ..service(ServiceType::Sqlite, &url, Some("SELECT name FROM jobs"))
+        };
+        assert_eq!(query(svc).await.status, Status::Up);
+
+        let svc = service(ServiceType::Sqlite, &url, Some("SELECT * FROM missing"));
+        assert_eq!(query(svc).await.status, Status::Down);
+
+        // queries can't change the database
+        let svc = service(ServiceType::Sqlite, &url, Some("DELETE FROM jobs"));
+        assert_eq!(query(svc).await.status, Status::Down);
+        let svc = service(
+            ServiceType::Sqlite,
+            &url,
+            Some("COMMIT; DELETE FROM jobs RETURNING name"),
+        );
+        assert_eq!(query(svc).await.status, Status::Down);
+        let svc = service(ServiceType::Sqlite, &url, Some("SELECT count(*) FROM jobs"));
+        assert_eq!(run(&svc).await, Ok(Some("1".into())));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Answers the commands of one connection like a Redis server with the password "secret"
+    async fn redis_stub() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            loop {
+                let mut header = String::new();
+                if stream.read_line(&mut header).await.unwrap() == 0 {
+                    return;
+                }
+                let count: usize = header.trim_end()[1..].parse().unwrap();
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    let (mut len, mut arg) = (String::new(), String::new());
+                    stream.read_line(&mut len).await.unwrap();
+                    stream.read_line(&mut arg).await.unwrap();
+                    args.push(arg.trim_end().to_owned());
+                }
+                let reply = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                    ["AUTH", "secret"] | ["SELECT", _] => "+OK\r\n",
+                    ["AUTH", _] => "-WRONGPASS invalid password\r\n",
+                    ["PING"] => "+PONG\r\n",
+                    ["GET", "missing"] => "$-1\r\n",
+                    ["GET", "huge"] => "$99999999999\r\n",
+                    ["GET", _] => "$5\r\nready\r\n",
+                    ["LRANGE", "huge", ..] => "*100000\r\n",
+                    ["LRANGE", "deep", ..] => {
+                        "*1\r\n*1\r\n*1\r\n*1\r\n*1\r\n*1\r\n*1\r\n*1\r\n*1\r\n"
+                    }
+                    ["LRANGE", ..] => "*2\r\n:1\r\n$1\r\n2\r\n",
+                    _ => "-ERR unknown command\r\n",
+                };
+                stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn redis_commands() {
+        let url = format!("redis://:secret@127.0.0.1:{}/2", redis_stub().await);
+        assert_eq!(redis(&url, "PING").await, Ok("PONG".into()));
+        let url = format!("redis://127.0.0.1:{}", redis_stub().await);
+        assert_eq!(redis(&url, "GET status").await, Ok("ready".into()));
+        let url = format!("redis://127.0.0.1:{}", redis_stub().await);
+        assert_eq!(redis(&url, "GET missing").await, Ok("NULL".into()));
+        let url = format!("redis://127.0.0.1:{}", redis_stub().await);
+        assert_eq!(redis(&url, "LRANGE l 0 -1").await, Ok("1\n2".into()));
+
+        // replies are not read past their limits
+        let url = format!("redis://127.0.0.1:{}", redis_stub().await);
+        assert!(redis(&url, "GET huge").await.is_err());
+        let url = format!("redis://127.0.0.1:{}", redis_stub().await);
+        assert!(redis(&url, "LRANGE huge 0 -1").await.is_err());
+        let url = format!("redis://127.0.0.1:{}", redis_stub().await);
+        assert_eq!(
+            redis(&url, "LRANGE deep 0 -1").await,
+            Err("Reply is nested too deep".into())
+        );
+
+        let url = format!("redis://:wrong@127.0.0.1:{}", redis_stub().await);
+        assert_eq!(
+            redis(&url, "PING").await,
+            Err("WRONGPASS invalid password".into())
+        );
+
+        let svc = Service {
+            expected_payload: Some("ready".into()),
+            ..service(
+                ServiceType::Redis,
+                &format!("redis://127.0.0.1:{}", redis_stub().await),
+                Some("GET status"),
+            )
+        };
+        assert_eq!(query(svc).await.status, Status::Up);
+    }
+}
@@ -0,0 +1,393 @@
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::Utc;
+use http::{HeaderMap, HeaderName, HeaderValue, Request, header};
+use reqwest::Url;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tracing::{debug, warn};
+
+use super::Service;
+use crate::models::log::{LogForCreate, Status};
+
+const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+/// `grpc.health.v1.HealthCheckResponse.ServingStatus`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ServingStatus {
+    Unknown,
+    Serving,
+    NotServing,
+    /// Only sent by the `Watch` RPC, handled in case a server sends it anyway
+    ServiceUnknown,
+}
+
+impl ServingStatus {
+    fn from_proto(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(ServingStatus::Unknown),
+            1 => Some(ServingStatus::Serving),
+            2 => Some(ServingStatus::NotServing),
+            3 => Some(ServingStatus::ServiceUnknown),
+            _ => None,
+        }
+    }
+}
+
+/// Append a protobuf varint
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut Bytes) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            return None;
+        }
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A length-prefixed `HealthCheckRequest { string service = 1; }` message
+fn encode_request(service: &str) -> Bytes {
+    let mut message = BytesMut::new();
+    if !service.is_empty() {
+        message.put_u8(0x0a);
+        put_varint(&mut message, service.len() as u64);
+        message.put_slice(service.as_bytes());
+    }
+    let mut frame = BytesMut::with_capacity(5 + message.len());
+    // not compressed
+    frame.put_u8(0);
+    frame.put_u32(message.len() as u32);
+    frame.put(message);
+    frame.freeze()
+}
+
+/// The status of a length-prefixed `HealthCheckResponse { ServingStatus status = 1; }`
+fn decode_response(mut frame: Bytes) -> Result<ServingStatus, String> {
+    if frame.len() < 5 {
+        return Err("Truncated response".into());
+    }
+    if frame.get_u8() != 0 {
+        return Err("Compressed responses are not supported".into());
+    }
+    let len = frame.get_u32() as usize;
+    if frame.len() < len {
+        return Err("Truncated response".into());
+    }
+    let mut message = frame.split_to(len);
+    // a missing field has the default value, UNKNOWN
+    let mut status = 0;
+    while message.has_remaining() {
+        let invalid = || "Invalid response".to_owned();
+        let key = get_varint(&mut message).ok_or_else(invalid)?;
+        match key & 0x07 {
+            0 => {
+                let value = get_varint(&mut message).ok_or_else(invalid)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }
+            1 => message.advance(8.min(message.remaining())),
+            2 => {
+                let len = get_varint(&mut message).ok_or_else(invalid)? as usize;
+                message.advance(len.min(message.remaining()));
+            }
+            5 => message.advance(4.min(message.remaining())),
+            _ => return Err(invalid()),
+        }
+    }
+    ServingStatus::from_proto(status).ok_or_else(|| format!("Unknown serving status {status}"))
+}
+
+/// The error of a `grpc-status` other than OK, from the headers of a trailers-only response
+/// or the trailers
+fn grpc_error(headers: &HeaderMap) -> Option<String> {
+    let status = headers.get("grpc-status")?.to_str().unwrap_or("2");
+    if status == "0" {
+        return None;
+    }
+    let message = headers
+        .get("grpc-message")
+        .and_then(|m| m.to_str().ok())
+        .unwrap_or_default();
+    Some(
+        format!("gRPC status {status} {message}")
+            .trim_end()
+            .to_owned(),
+    )
+}
+
+/// Call the health check over an established connection
+async fn call<T>(
+    io: T,
+    url: &Url,
+    service: &str,
+    metadata: &[(HeaderName, HeaderValue)],
+) -> Result<ServingStatus, String>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = h2::client::handshake(io)
+        .await
+        .map_err(|e| format!("HTTP/2 handshake failed: {e}"))?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("gRPC connection closed: {e}");
+        }
+    });
+
+    let uri = url
+        .join(HEALTH_CHECK_PATH)
+        .map_err(|e| format!("Invalid url: {e}"))?;
+    let mut request = Request::post(uri.as_str())
+        .header(header::CONTENT_TYPE, "application/grpc")
+        .header(header::TE, "trailers")
+        .body(())
+        .map_err(|e| e.to_string())?;
+    for (name, value) in metadata {
+        request.headers_mut().append(name, value.clone());
+    }
+
+    let mut client = client.ready().await.map_err(|e| e.to_string())?;
+    let (response, mut stream) = client
+        .send_request(request, false)
+        .map_err(|e| e.to_string())?;
+    stream
+        .send_data(encode_request(service), true)
+        .map_err(|e| e.to_string())?;
+
+    let response = response.await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Unexpected HTTP status {}", response.status()));
+    }
+    if let Some(e) = grpc_error(response.headers()) {
+        return Err(e);
+    }
+    let mut body = response.into_body();
+    let mut frame = BytesMut::new();
+    while let Some(data) = body.data().await {
+        let data = data.map_err(|e| e.to_string())?;
+        let _ = body.flow_control().release_capacity(data.len());
+        frame.put(data);
+    }
+    let trailers = body.trailers().await.map_err(|e| e.to_string())?;
+    if let Some(e) = trailers.as_ref().and_then(grpc_error) {
+        return Err(e);
+    }
+    decode_response(frame.freeze())
+}
+
+/// Connect to the server of the url, with TLS for https, and call the health check
+async fn check(
+    url: &Url,
+    service: &str,
+    metadata: &[(HeaderName, HeaderValue)],
+) -> Result<ServingStatus, String> {
+    let host = url.host_str().ok_or("Missing host")?;
+    // IPv6 addresses without their brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().ok_or("Missing port")?;
+    let tcp = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| e.to_string())?;
+    if url.scheme() == "https" {
+        let connector = native_tls::TlsConnector::builder()
+            .request_alpns(&["h2"])
+            .build()
+            .map_err(|e| e.to_string())?;
+        let tls = tokio_native_tls::TlsConnector::from(connector)
+            .connect(host, tcp)
+            .await
+            .map_err(|e| format!("TLS handshake failed: {e}"))?;
+        call(tls, url, service, metadata).await
+    } else {
+        call(tcp, url, service, metadata).await
+    }
+}
+
+#[tracing::instrument(skip(svc), fields(name = svc.name, url = svc.url))]
+pub async fn health_check(svc: Service) -> LogForCreate {
+    let now = Instant::now();
+    let time = Some(Utc::now());
+    let log = |status, message: Option<String>| LogForCreate {
+        status,
+        message,
+        service_id: svc.id,
+        duration: now.elapsed().as_millis() as u32,
+        time,
+        ..Default::default()
+    };
+
+    let url = match Url::parse(&svc.url) {
+        Ok(url) => url,
+        Err(e) => return log(Status::Failed, Some(format!("Invalid url: {e}"))),
+    };
+    // checked when the service is saved
+    let metadata: Vec<_> = svc
+        .grpc_metadata
+        .iter()
+        .flat_map(|metadata| metadata.iter())
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(value).ok()?,
+            ))
+        })
+        .collect();
+    let service = svc.grpc_service.as_deref().unwrap_or_default();
+
+    let timeout = Duration::from_secs(u64::from(svc.timeout));
+    let result = match tokio::time::timeout(timeout, check(&url, service, &metadata)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Timed out after {}s", svc.timeout)),
+    };
+    match result {
+        Ok(ServingStatus::Serving) => log(Status::Up, None),
+        Ok(ServingStatus::NotServing) => log(Status::Down, Some("NOT_SERVING".into())),
+        Ok(ServingStatus::ServiceUnknown) => log(Status::Down, Some("SERVICE_UNKNOWN".into())),
+        // the server can't tell, which says nothing about the service
+        Ok(ServingStatus::Unknown) => log(Status::Failed, Some("UNKNOWN".into())),
+        Err(e) => {
+            warn!("gRPC health check failed: {e}");
+            log(Status::Down, Some(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use http::Response;
+    use sqlx::types::Json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::models::service::ServiceType;
+
+    /// Serves the health checks of one connection: the server and "api" are serving,
+    /// "payments" is not, other services are not found and "authorization: Bearer token"
+    /// is required
+    async fn health_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut connection = h2::server::handshake(tcp).await.unwrap();
+            while let Some(Ok((request, mut respond))) = connection.accept().await {
+                assert_eq!(request.uri().path(), HEALTH_CHECK_PATH);
+                let authorized = request.headers().get("authorization")
+                    == Some(&HeaderValue::from_static("Bearer token"));
+                let mut body = request.into_body();
+                let mut frame = BytesMut::new();
+                while let Some(data) = body.data().await {
+                    frame.put(data.unwrap());
+                }
+                let service = String::from_utf8(frame[7.min(frame.len())..].to_vec()).unwrap();
+
+                let status = match service.as_str() {
+                    _ if !authorized => Err("16"),
+                    "" | "api" => Ok(1),
+                    "payments" => Ok(2),
+                    _ => Err("5"),
+                };
+                let response = Response::builder().header(header::CONTENT_TYPE, "application/grpc");
+                let mut trailers = HeaderMap::new();
+                match status {
+                    Ok(status) => {
+                        let mut stream = respond
+                            .send_response(response.body(()).unwrap(), false)
+                            .unwrap();
+                        stream
+                            .send_data(
+                                Bytes::copy_from_slice(&[0, 0, 0, 0, 2, 0x08, status]),
+                                false,
+                            )
+                            .unwrap();
+                        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                        stream.send_trailers(trailers).unwrap();
+                    }
+                    // trailers-only response
+                    Err(code) => {
+                        let response = response.header("grpc-status", code).body(()).unwrap();
+                        respond.send_response(response, true).unwrap();
+                    }
+                }
+            }
+        });
+        port
+    }
+
+    async fn check_service(name: Option<&str>, token: &str) -> LogForCreate {
+        let svc = Service {
+            service_type: ServiceType::Grpc,
+            url: format!("http://127.0.0.1:{}", health_server().await),
+            timeout: 5,
+            grpc_service: name.map(Into::into),
+            grpc_metadata: Some(Json(BTreeMap::from([(
+                "authorization".into(),
+                format!("Bearer {token}"),
+            )]))),
+            ..Default::default()
+        };
+        health_check(svc).await
+    }
+
+    #[tokio::test]
+    async fn serving_status() {
+        let log = check_service(None, "token").await;
+        assert_eq!(log.status, Status::Up);
+        assert_eq!(check_service(Some("api"), "token").await.status, Status::Up);
+
+        let log = check_service(Some("payments"), "token").await;
+        assert_eq!(log.status, Status::Down);
+        assert_eq!(log.message.as_deref(), Some("NOT_SERVING"));
+
+        let log = check_service(Some("billing"), "token").await;
+        assert_eq!(log.status, Status::Down);
+        assert_eq!(log.message.as_deref(), Some("gRPC status 5"));
+
+        let log = check_service(None, "wrong").await;
+        assert_eq!(log.message.as_deref(), Some("gRPC status 16"));
+    }
+
+    #[test]
+    fn health_messages() {
+        assert_eq!(&encode_request("")[..], [0, 0, 0, 0, 0]);
+        assert_eq!(
+            &encode_request("api")[..],
+            [0, 0, 0, 0, 5, 0x0a, 3, b'a', b'p', b'i']
+        );
+
+        let response = |message: &'static [u8]| {
+            let mut frame = BytesMut::from(&[0, 0, 0, 0, message.len() as u8][..]);
+            frame.put_slice(message);
+            decode_response(frame.freeze())
+        };
+        assert_eq!(response(&[0x08, 1]), Ok(ServingStatus::Serving));
+        assert_eq!(response(&[0x08, 2]), Ok(ServingStatus::NotServing));
+        // default value and unknown fields
+        assert_eq!(response(&[]), Ok(ServingStatus::Unknown));
+        assert_eq!(
+            response(&[0x12, 2, b'o', b'k', 0x08, 1]),
+            Ok(ServingStatus::Serving)
+        );
+        assert!(response(&[0x08, 9]).is_err());
+        assert!(decode_response(Bytes::from_static(&[0, 0, 0, 0, 4, 0x08])).is_err());
+    }
+}
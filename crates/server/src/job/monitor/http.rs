@@ -27,6 +27,7 @@ pub async fn get(svc: Service, tx: Sender<Event>) -> LogForCreate {
                             duration: now.elapsed().as_millis() as u32,
                             time,
                             message: Some(format!("Invalid expected payload template: {e}")),
+                            ..Default::default()
                         };
                     }
                 };
@@ -39,6 +40,7 @@ pub async fn get(svc: Service, tx: Sender<Event>) -> LogForCreate {
                             duration: now.elapsed().as_millis() as u32,
                             time,
                             message: Some(format!("Failed to parse response JSON: {e}")),
+                            ..Default::default()
                         };
                     }
                 };
@@ -49,6 +51,7 @@ pub async fn get(svc: Service, tx: Sender<Event>) -> LogForCreate {
                         duration: now.elapsed().as_millis() as u32,
                         time,
                         message: Some(format!("Expected: {json} Got: {data}")),
+                        ..Default::default()
                     };
                 }
             }
@@ -68,6 +71,8 @@ pub async fn get(svc: Service, tx: Sender<Event>) -> LogForCreate {
                     message: format!("Error: {}", e),
                     title: "Network Error".to_string(),
                     level: Level::Error,
+                    service_id: Some(svc.id),
+                    group_id: None,
                 }))
             {
                 error!("Failed to send notification: {:?}", e);
@@ -90,6 +95,7 @@ pub async fn get(svc: Service, tx: Sender<Event>) -> LogForCreate {
                 message: Some(format!("{e}")),
                 duration: now.elapsed().as_millis() as u32,
                 time,
+                ..Default::default()
             }
         }
     }
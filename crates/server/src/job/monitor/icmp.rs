@@ -0,0 +1,225 @@
+use std::{
+    io::{self, Read},
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU16, Ordering},
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+const HEADER_SIZE: usize = 8;
+
+/// Identifier of the next echo session, so concurrent checks don't take each other's replies
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(1);
+
+/// Type of ICMP socket the echo requests are sent with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    /// Unprivileged, allowed by `ping_group_range` on Linux
+    Datagram,
+    /// Needs `CAP_NET_RAW`
+    Raw,
+}
+
+#[derive(Debug, Clone)]
+pub struct EchoOptions {
+    pub count: u32,
+    /// Bytes of data after the ICMP header
+    pub size: usize,
+    /// Time allowed for all the packets
+    pub timeout: Duration,
+}
+
+/// An ICMP socket ready to send echo requests to one address
+pub struct Pinger {
+    socket: Socket,
+    kind: SocketKind,
+    addr: IpAddr,
+}
+
+fn open_socket(addr: IpAddr, kind: SocketKind) -> io::Result<Socket> {
+    let ty = match kind {
+        SocketKind::Datagram => Type::DGRAM,
+        SocketKind::Raw => Type::RAW,
+    };
+    if addr.is_ipv6() {
+        Socket::new(Domain::IPV6, ty, Some(Protocol::ICMPV6))
+    } else {
+        Socket::new(Domain::IPV4, ty, Some(Protocol::ICMPV4))
+    }
+}
+
+impl Pinger {
+    /// Open an unprivileged datagram socket, or a raw one when datagram sockets are not
+    /// allowed. Returns the error of the raw socket when neither can be opened.
+    pub fn open(addr: IpAddr, ttl: u32) -> io::Result<Self> {
+        let (socket, kind) = match open_socket(addr, SocketKind::Datagram) {
+            Ok(socket) => (socket, SocketKind::Datagram),
+            Err(_) => (open_socket(addr, SocketKind::Raw)?, SocketKind::Raw),
+        };
+        if addr.is_ipv6() {
+            socket.set_unicast_hops_v6(ttl)?;
+        } else {
+            socket.set_ttl(ttl)?;
+        }
+        Ok(Pinger { socket, kind, addr })
+    }
+
+    pub fn kind(&self) -> SocketKind {
+        self.kind
+    }
+
+    /// Send `count` echo requests one after the other, returning the round trip time of
+    /// each, `None` for packets that got no reply in time. Blocks.
+    pub fn echo(&self, options: &EchoOptions) -> io::Result<Vec<Option<Duration>>> {
+        let socket = &self.socket;
+        let v6 = self.addr.is_ipv6();
+        let dest = SockAddr::from(SocketAddr::new(self.addr, 0));
+        let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+        let count = options.count.max(1);
+        let per_packet = options.timeout / count;
+
+        let mut rtts = Vec::with_capacity(count as usize);
+        let mut buf = [0; 2048];
+        for seq in 1..=count as u16 {
+            let packet = echo_request(v6, ident, seq, options.size);
+            let start = Instant::now();
+            socket.send_to(&packet, &dest)?;
+
+            let deadline = start + per_packet;
+            let rtt = loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break None;
+                }
+                socket.set_read_timeout(Some(remaining))?;
+                let len = match (&*socket).read(&mut buf) {
+                    Ok(len) => len,
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break None;
+                    }
+                    Err(e) => return Err(e),
+                };
+                // raw sockets receive every reply, datagram sockets only their own
+                let matches = match echo_reply(v6, &buf[..len]) {
+                    Some((id, s)) if self.kind == SocketKind::Raw => (id, s) == (ident, seq),
+                    Some((_, s)) => s == seq,
+                    None => false,
+                };
+                if matches {
+                    break Some(start.elapsed());
+                }
+            };
+            rtts.push(rtt);
+        }
+        Ok(rtts)
+    }
+}
+
+fn echo_request(v6: bool, ident: u16, seq: u16, size: usize) -> Vec<u8> {
+    let mut packet = vec![0; HEADER_SIZE + size];
+    packet[0] = if v6 { ECHO_REQUEST_V6 } else { ECHO_REQUEST_V4 };
+    packet[4..6].copy_from_slice(&ident.to_be_bytes());
+    packet[6..8].copy_from_slice(&seq.to_be_bytes());
+    for (i, byte) in packet[HEADER_SIZE..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    // the kernel fills in the checksum of ICMPv6 packets
+    if !v6 {
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet
+}
+
+/// Identifier and sequence number of an echo reply, with or without the IPv4 header raw
+/// sockets receive. Datagram sockets replace the identifier with their own.
+fn echo_reply(v6: bool, packet: &[u8]) -> Option<(u16, u16)> {
+    let packet = match packet.first() {
+        Some(b) if !v6 && b >> 4 == 4 => packet.get(usize::from(b & 0x0f) * 4..)?,
+        _ => packet,
+    };
+    let reply = if v6 { ECHO_REPLY_V6 } else { ECHO_REPLY_V4 };
+    if packet.len() < HEADER_SIZE || packet[0] != reply || packet[1] != 0 {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([packet[4], packet[5]]),
+        u16::from_be_bytes([packet[6], packet[7]]),
+    ))
+}
+
+/// Internet checksum (RFC 1071)
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internet_checksum() {
+        // example from RFC 1071
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data), !0xddf2);
+
+        // a packet with its checksum filled in sums to zero
+        let packet = echo_request(false, 0x1234, 7, 21);
+        assert_eq!(packet.len(), HEADER_SIZE + 21);
+        assert_eq!(checksum(&packet), 0);
+    }
+
+    #[test]
+    fn echo_localhost() {
+        // not every environment allows ICMP sockets
+        let Ok(pinger) = Pinger::open(IpAddr::from([127, 0, 0, 1]), 64) else {
+            return;
+        };
+        let options = EchoOptions {
+            count: 2,
+            size: 56,
+            timeout: Duration::from_secs(2),
+        };
+        let rtts = pinger.echo(&options).unwrap();
+        assert_eq!(rtts.len(), 2);
+        assert!(rtts.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn parse_echo_reply() {
+        let mut reply = echo_request(false, 1, 3, 4);
+        reply[0] = ECHO_REPLY_V4;
+        assert_eq!(echo_reply(false, &reply), Some((1, 3)));
+
+        // raw sockets receive the IPv4 header too
+        let mut with_header = vec![0x45];
+        with_header.extend([0; 19]);
+        with_header.extend(&reply);
+        assert_eq!(echo_reply(false, &with_header), Some((1, 3)));
+
+        // requests and truncated packets are not replies
+        assert_eq!(echo_reply(false, &echo_request(false, 1, 3, 4)), None);
+        assert_eq!(echo_reply(false, &reply[..4]), None);
+
+        let mut reply = echo_request(true, 1, 9, 0);
+        reply[0] = ECHO_REPLY_V6;
+        assert_eq!(echo_reply(true, &reply), Some((1, 9)));
+    }
+}
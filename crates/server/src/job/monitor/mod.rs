@@ -4,59 +4,180 @@ use tracing::{debug, error};
 use crate::{
     AppState,
     models::{
-        log::{Log, Status},
+        group::Group,
+        log::{Log, LogForCreate, Status},
         service::{Service, ServiceType},
     },
     ws::{Event, Level, Notification},
 };
 
+mod content;
+mod database;
+mod grpc;
 mod http;
+mod icmp;
+mod mail;
 mod ping;
+pub mod push;
 
-pub async fn job_monitor(job: Service, wid: Data<WorkerId>, state: Data<AppState>) {
+pub async fn job_monitor(mut job: Service, wid: Data<WorkerId>, state: Data<AppState>) {
+    if job.cron_timezone.is_none() {
+        job.cron_timezone = Some(state.settings.borrow().default_timezone.name().to_owned());
+    }
     let status_log = match job.service_type {
         ServiceType::Ping => ping::ping(job.clone(), state.tx.clone()).await,
         ServiceType::Http => http::get(job.clone(), state.tx.clone()).await,
-        _ => todo!(),
+        ServiceType::Keyword => content::keyword(job.clone()).await,
+        ServiceType::ContentChange => content::content_change(job.clone(), &state.pool).await,
+        ServiceType::Postgres | ServiceType::Mysql | ServiceType::Sqlite | ServiceType::Redis => {
+            database::query(job.clone()).await
+        }
+        ServiceType::Grpc => grpc::health_check(job.clone()).await,
+        ServiceType::Smtp | ServiceType::Imap | ServiceType::Pop3 => mail::check(job.clone()).await,
+        ServiceType::Push => match push::check(&job) {
+            Some(status_log) => status_log,
+            // heartbeat received in time, nothing to record
+            None => return,
+        },
     };
-    if let Err(e) = state.tx.send(Event::Log(status_log.clone())) {
-        error!("Failed to send notification: {:?}", e);
-    }
     debug!(worker = wid.to_string(), "Service status {}", status_log);
 
-    // Check last status
-    match (job.last_status, status_log.status) {
-        (Status::Down, Status::Up) => {
-            if let Err(e) = state.tx.send(Event::Notification(Notification {
-                message: format!("Service {} back Up", job.name),
-                title: "Back Up".to_string(),
-                level: Level::Success,
-            })) {
-                error!("Failed to send notification: {:?}", e);
-            }
-        }
-        (Status::Up, Status::Down) => {
-            if let Err(e) = state.tx.send(Event::Notification(Notification {
-                message: format!("Service {} is Down", job.name),
-                title: "Service Down".to_string(),
-                level: Level::Warning,
-            })) {
-                error!("Failed to send notification: {:?}", e);
-            }
-        }
-        (Status::Failed, Status::Up | Status::Down) => {
-            if let Err(e) = state.tx.send(Event::Notification(Notification {
-                message: format!("Service {} check success", job.name),
-                title: "Monitor Success".to_string(),
-                level: Level::Info,
-            })) {
-                error!("Failed to send notification: {:?}", e);
+    report(&state, &job, status_log).await;
+}
+
+/// Broadcast a status log, notify on status transitions and persist it
+pub async fn report(state: &AppState, job: &Service, mut status_log: LogForCreate) {
+    // A failing service behind a parent that is down is unreachable rather than down
+    if matches!(status_log.status, Status::Down | Status::Failed) {
+        match Service::down_parent(&state.pool, job.id).await {
+            Ok(Some(parent)) => {
+                status_log.status = Status::Unreachable;
+                status_log.message = Some(format!("Depends on {} which is down", parent.name));
+                status_log.cause_id = Some(parent.id);
             }
+            Ok(None) => (),
+            Err(e) => error!("Failed to check service dependencies: {e}"),
         }
-        _ => (),
-    };
+    }
+
+    if let Err(e) = state.tx.send(Event::Log(status_log.clone())) {
+        error!("Failed to send notification: {:?}", e);
+    }
+
+    if let Some(notification) = transition(job, status_log.status)
+        && let Err(e) = state.tx.send(Event::Notification(notification))
+    {
+        error!("Failed to send notification: {:?}", e);
+    }
 
     if let Err(e) = Log::insert(&state.pool, status_log).await {
         error!("error {e}");
     };
+
+    if let Some(group_id) = job.group_id
+        && let Err(e) = report_groups(state, group_id).await
+    {
+        error!("Failed to update group status: {e}");
+    }
+}
+
+/// Notification for a change of status of a service.
+///
+/// Services become unreachable rather than down when a parent is down, they don't notify since
+/// their parent already did. Once the parent recovers, a service that is still down notifies as
+/// if it had just gone down, and one that is up again stays silent since no one was told it was
+/// down.
+fn transition(service: &Service, status: Status) -> Option<Notification> {
+    let name = &service.name;
+    let service_id = Some(service.id);
+    match (service.last_status, status) {
+        (Status::Down, Status::Up) => Some(Notification {
+            message: format!("Service {name} back Up"),
+            title: "Back Up".to_string(),
+            level: Level::Success,
+            service_id,
+            group_id: None,
+        }),
+        (Status::Up | Status::Unreachable, Status::Down) => Some(Notification {
+            message: format!("Service {name} is Down"),
+            title: "Service Down".to_string(),
+            level: Level::Warning,
+            service_id,
+            group_id: None,
+        }),
+        (Status::Failed, Status::Up | Status::Down) => Some(Notification {
+            message: format!("Service {name} check success"),
+            title: "Monitor Success".to_string(),
+            level: Level::Info,
+            service_id,
+            group_id: None,
+        }),
+        _ => None,
+    }
+}
+
+/// Recompute the status of a group and its parents, notifying on transitions
+async fn report_groups(state: &AppState, group_id: u32) -> sqlx::Result<()> {
+    for group in Group::ancestors(&state.pool, group_id).await? {
+        let status = group.status(&state.pool, None).await?;
+        if status == group.last_status {
+            continue;
+        }
+        Group::set_last_status(&state.pool, group.id, status).await?;
+
+        let notification = match (group.last_status, status) {
+            (_, Status::Down) => Notification {
+                message: format!("Group {} is Down", group.name),
+                title: "Group Down".to_string(),
+                level: Level::Warning,
+                service_id: None,
+                group_id: Some(group.id),
+            },
+            (Status::Down | Status::Failed, Status::Up) => Notification {
+                message: format!("Group {} back Up", group.name),
+                title: "Group Back Up".to_string(),
+                level: Level::Success,
+                service_id: None,
+                group_id: Some(group.id),
+            },
+            _ => continue,
+        };
+        if let Err(e) = state.tx.send(Event::Notification(notification)) {
+            error!("Failed to send notification: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_transitions() {
+        let title = |last_status, status| {
+            let service = Service {
+                name: "api".into(),
+                last_status,
+                ..Default::default()
+            };
+            transition(&service, status).map(|n| n.title)
+        };
+
+        assert_eq!(
+            title(Status::Up, Status::Down).as_deref(),
+            Some("Service Down")
+        );
+        assert_eq!(title(Status::Down, Status::Up).as_deref(), Some("Back Up"));
+        // the parent notified for its children
+        assert_eq!(title(Status::Up, Status::Unreachable), None);
+        assert_eq!(title(Status::Down, Status::Unreachable), None);
+        // the parent recovered but the service is still down
+        assert_eq!(
+            title(Status::Unreachable, Status::Down).as_deref(),
+            Some("Service Down")
+        );
+        assert_eq!(title(Status::Unreachable, Status::Up), None);
+        assert_eq!(title(Status::Down, Status::Down), None);
+    }
 }
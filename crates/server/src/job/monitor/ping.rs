@@ -1,75 +1,287 @@
-use std::{net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpStream},
+    str::FromStr,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
 
-use ping_rs::PingError;
+use hickory_resolver::{
+    TokioResolver,
+    config::{LookupIpStrategy, ResolverConfig},
+    name_server::TokioConnectionProvider,
+};
 use tokio::sync::broadcast::Sender;
 use tracing::{debug, error, warn};
 
-use super::Service;
+use super::{
+    Service,
+    icmp::{EchoOptions, Pinger, SocketKind},
+};
 use crate::{
-    models::log::{LogForCreate, Status},
+    models::{
+        log::{LogForCreate, PingMethod, Status},
+        service::IpPreference,
+    },
     ws::{Event, Level, Notification},
 };
 
+/// Resolver shared by ping checks, asks for both A and AAAA records and lets the
+/// preference of each service pick between them
+static RESOLVER: LazyLock<TokioResolver> = LazyLock::new(|| {
+    let mut builder = TokioResolver::builder_tokio().unwrap_or_else(|e| {
+        warn!("Failed to read the system DNS config, using the default: {e}");
+        TokioResolver::builder_with_config(
+            ResolverConfig::default(),
+            TokioConnectionProvider::default(),
+        )
+    });
+    builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    builder.build()
+});
+
+/// Summary of the replies to the packets of a check, round trip times are in milliseconds
+#[derive(Debug, PartialEq)]
+struct PingStats {
+    min: Option<u32>,
+    avg: Option<u32>,
+    max: Option<u32>,
+    /// Percentage of packets lost
+    loss: f64,
+}
+
+impl PingStats {
+    fn new(rtts: &[Option<Duration>]) -> Self {
+        let received: Vec<u32> = rtts
+            .iter()
+            .flatten()
+            .map(|d| d.as_millis() as u32)
+            .collect();
+        let loss = if rtts.is_empty() {
+            100.0
+        } else {
+            (rtts.len() - received.len()) as f64 * 100.0 / rtts.len() as f64
+        };
+        PingStats {
+            min: received.iter().min().copied(),
+            avg: (!received.is_empty())
+                .then(|| received.iter().sum::<u32>() / received.len() as u32),
+            max: received.iter().max().copied(),
+            loss,
+        }
+    }
+}
+
+/// The address to ping for an IP or a hostname
+async fn resolve(host: &str, preference: IpPreference) -> Result<IpAddr, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<IpAddr> = match IpAddr::from_str(host) {
+        Ok(addr) => vec![addr],
+        Err(_) => RESOLVER
+            .lookup_ip(host)
+            .await
+            .map_err(|e| format!("Failed to resolve {host}: {e}"))?
+            .iter()
+            .collect(),
+    };
+    pick_address(&addrs, preference).ok_or_else(|| format!("No usable address for {host}"))
+}
+
+fn pick_address(addrs: &[IpAddr], preference: IpPreference) -> Option<IpAddr> {
+    let v4 = addrs.iter().find(|addr| addr.is_ipv4());
+    let v6 = addrs.iter().find(|addr| addr.is_ipv6());
+    match preference {
+        IpPreference::Ipv4 => v4.or(v6),
+        IpPreference::Ipv6 => v6.or(v4),
+        IpPreference::Ipv4Only => v4,
+        IpPreference::Ipv6Only => v6,
+    }
+    .copied()
+}
+
+/// "Ping" with TCP connections, a refused connection still means the host is up
+fn tcp_connect(addr: SocketAddr, options: &EchoOptions) -> Vec<Option<Duration>> {
+    let count = options.count.max(1);
+    let per_attempt = options.timeout / count;
+    (0..count)
+        .map(|_| {
+            let start = Instant::now();
+            match TcpStream::connect_timeout(&addr, per_attempt) {
+                Ok(_) => Some(start.elapsed()),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Some(start.elapsed()),
+                Err(_) => None,
+            }
+        })
+        .collect()
+}
+
+/// Round trip times of the packets of a check, with ICMP if a socket can be opened or else
+/// TCP connections to `tcp_port`. Blocks.
+fn probe(
+    addr: IpAddr,
+    ttl: u32,
+    tcp_port: u16,
+    options: &EchoOptions,
+) -> io::Result<(PingMethod, Vec<Option<Duration>>)> {
+    match Pinger::open(addr, ttl) {
+        Ok(pinger) => {
+            let method = match pinger.kind() {
+                SocketKind::Datagram => PingMethod::Icmp,
+                SocketKind::Raw => PingMethod::IcmpRaw,
+            };
+            Ok((method, pinger.echo(options)?))
+        }
+        Err(e) => {
+            debug!("Can't open an ICMP socket, using TCP: {e}");
+            let addr = SocketAddr::new(addr, tcp_port);
+            Ok((PingMethod::Tcp, tcp_connect(addr, options)))
+        }
+    }
+}
+
 #[tracing::instrument(skip(svc, tx), fields(name = svc.name, url = svc.url))]
 pub async fn ping(svc: Service, tx: Sender<Event>) -> LogForCreate {
-    let addr = IpAddr::from_str(&svc.url).unwrap();
-    let data = [1, 2, 3, 4]; // ping data
-    let data_arc = Arc::new(&data[..]);
-    let options = ping_rs::PingOptions {
-        ttl: 128,
-        dont_fragment: true,
-    };
     let time = chrono::Utc::now();
-    match ping_rs::send_ping_async(
-        &addr,
-        Duration::from_secs(svc.timeout as u64),
-        data_arc,
-        Some(&options),
-    )
-    .await
-    {
-        Ok(reply) => {
-            debug!(
-                bytes = data.len(),
-                time = reply.rtt,
-                ttl = options.ttl,
-                "reply from {}:",
-                reply.address,
-            );
-            LogForCreate {
-                status: Status::Up,
-                duration: reply.rtt,
+    let addr = match resolve(&svc.url, svc.ip_preference).await {
+        Ok(addr) => addr,
+        Err(msg) => {
+            warn!("{msg}");
+            return LogForCreate {
+                status: Status::Down,
+                message: Some(msg),
                 service_id: svc.id,
                 time: Some(time),
                 ..Default::default()
-            }
+            };
         }
-        Err(PingError::OsError(_, msg)) => {
+    };
+    let options = EchoOptions {
+        count: svc.ping_count,
+        size: svc.packet_size as usize,
+        timeout: Duration::from_secs(u64::from(svc.timeout)),
+    };
+    let tcp_port = u16::try_from(svc.tcp_port).unwrap_or(80);
+
+    let probe = move || probe(addr, svc.ttl, tcp_port, &options);
+    let (method, rtts) = match tokio::task::spawn_blocking(probe).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
             if let Err(e) = tx.send(Event::Notification(Notification {
-                message: format!("Error: {}", msg),
+                message: format!("Error: {e}"),
                 title: "Network Error".to_string(),
                 level: Level::Error,
+                service_id: Some(svc.id),
+                group_id: None,
             })) {
                 error!("Failed to send notification: {:?}", e);
             };
-            warn!("Ping failed {}", msg);
-            LogForCreate {
+            warn!("Ping failed {e}");
+            return LogForCreate {
                 status: Status::Failed,
-                message: Some(msg),
+                message: Some(e.to_string()),
                 service_id: svc.id,
                 time: Some(time),
                 ..Default::default()
-            }
+            };
         }
         Err(e) => {
-            error!("{:?}", e);
-            LogForCreate {
-                status: Status::Down,
-                message: Some(format!("{:?}", e)),
+            error!("{e}");
+            return LogForCreate {
+                status: Status::Failed,
+                message: Some(e.to_string()),
                 service_id: svc.id,
                 time: Some(time),
                 ..Default::default()
-            }
+            };
         }
+    };
+
+    let stats = PingStats::new(&rtts);
+    debug!(%addr, ?method, ?stats, "ping");
+    let status = if stats.avg.is_none() || stats.loss >= f64::from(svc.loss_threshold) {
+        Status::Down
+    } else {
+        Status::Up
+    };
+    LogForCreate {
+        status,
+        message: (stats.loss > 0.0).then(|| format!("{addr}: {:.0}% packet loss", stats.loss)),
+        duration: stats.avg.unwrap_or_default(),
+        service_id: svc.id,
+        time: Some(time),
+        rtt_min: stats.min,
+        rtt_avg: stats.avg,
+        rtt_max: stats.max,
+        packet_loss: Some(stats.loss),
+        ping_method: Some(method),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn address_preference() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let both = [v6, v4];
+
+        assert_eq!(pick_address(&both, IpPreference::Ipv4), Some(v4));
+        assert_eq!(pick_address(&both, IpPreference::Ipv6), Some(v6));
+        assert_eq!(pick_address(&[v6], IpPreference::Ipv4), Some(v6));
+        assert_eq!(pick_address(&[v6], IpPreference::Ipv4Only), None);
+        assert_eq!(pick_address(&[v4], IpPreference::Ipv6Only), None);
+    }
+
+    #[tokio::test]
+    async fn resolve_literals() {
+        assert_eq!(
+            resolve("[::1]", IpPreference::Ipv4).await,
+            Ok(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+        assert!(resolve("10.0.0.1", IpPreference::Ipv6Only).await.is_err());
+    }
+
+    #[test]
+    fn tcp_fallback() {
+        let options = EchoOptions {
+            count: 2,
+            size: 0,
+            timeout: Duration::from_secs(2),
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        assert!(tcp_connect(open, &options).iter().all(Option::is_some));
+
+        // a refused connection is a reply from the host
+        drop(listener);
+        assert!(tcp_connect(open, &options).iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn packet_loss() {
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(
+            PingStats::new(&[ms(10), None, ms(20), ms(30)]),
+            PingStats {
+                min: Some(10),
+                avg: Some(20),
+                max: Some(30),
+                loss: 25.0
+            }
+        );
+        assert_eq!(
+            PingStats::new(&[None, None]),
+            PingStats {
+                min: None,
+                avg: None,
+                max: None,
+                loss: 100.0
+            }
+        );
     }
 }
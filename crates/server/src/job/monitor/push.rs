@@ -0,0 +1,172 @@
+use std::str::FromStr;
+
+use apalis_cron::Schedule;
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+
+use super::Service;
+use crate::models::log::{LogForCreate, Status};
+
+/// Check whether a push service has missed its heartbeat or overrun.
+///
+/// Returns `None` while the service is still within its expected schedule.
+pub fn check(svc: &Service) -> Option<LogForCreate> {
+    check_at(svc, Utc::now())
+}
+
+fn check_at(svc: &Service, now: DateTime<Utc>) -> Option<LogForCreate> {
+    let log = |status, message| {
+        Some(LogForCreate {
+            status,
+            service_id: svc.id,
+            message: Some(message),
+            time: Some(now),
+            ..Default::default()
+        })
+    };
+
+    if let Some(started_at) = svc.started_at
+        && let Some(max_runtime) = svc.max_runtime
+        && now > started_at + TimeDelta::seconds(max_runtime as i64)
+    {
+        return log(
+            Status::Down,
+            format!(
+                "Run started at {} exceeded max runtime of {max_runtime}s",
+                started_at.format("%Y-%m-%d %H:%M:%S UTC")
+            ),
+        );
+    }
+
+    let last_push = svc.last_push?;
+    let grace = TimeDelta::seconds(svc.grace_period as i64);
+    let expected = match &svc.cron {
+        Some(expr) => match next_run(expr, svc.cron_timezone.as_deref(), last_push) {
+            Ok(Some(t)) => t,
+            Ok(None) => return None,
+            Err(e) => return log(Status::Failed, e),
+        },
+        None => last_push + TimeDelta::seconds(svc.interval as i64),
+    };
+
+    if now <= expected + grace {
+        return None;
+    }
+
+    log(
+        Status::Down,
+        format!(
+            "No push received since {}, expected by {}",
+            last_push.format("%Y-%m-%d %H:%M:%S UTC"),
+            expected.format("%Y-%m-%d %H:%M:%S UTC")
+        ),
+    )
+}
+
+/// Next time after `after` the cron expression fires, evaluated in `timezone`
+pub fn next_run(
+    expr: &str,
+    timezone: Option<&str>,
+    after: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let schedule = Schedule::from_str(expr).map_err(|e| format!("Invalid cron expression: {e}"))?;
+    let tz = match timezone {
+        Some(tz) => Tz::from_str(tz).map_err(|e| format!("Invalid timezone: {e}"))?,
+        None => Tz::UTC,
+    };
+
+    Ok(schedule
+        .after(&after.with_timezone(&tz))
+        .next()
+        .map(|t| t.with_timezone(&Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::models::service::ServiceType;
+
+    fn push_service(last_push: Option<DateTime<Utc>>) -> Service {
+        Service {
+            name: "cron".into(),
+            last_status: Status::Up,
+            service_type: ServiceType::Push,
+            push_token: Some("token".into()),
+            grace_period: 30,
+            last_push,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn push_within_grace_period() {
+        let now = Utc::now();
+        let svc = push_service(Some(now - TimeDelta::seconds(80)));
+        assert!(check_at(&svc, now).is_none());
+    }
+
+    #[test]
+    fn push_missed() {
+        let now = Utc::now();
+        let svc = push_service(Some(now - TimeDelta::seconds(91)));
+        let log = check_at(&svc, now).expect("should be down");
+        assert!(matches!(log.status, Status::Down));
+        assert_eq!(log.service_id, 1);
+    }
+
+    #[test]
+    fn push_never_armed() {
+        let svc = push_service(None);
+        assert!(check_at(&svc, Utc::now()).is_none());
+    }
+
+    #[test]
+    fn cron_next_run_in_timezone() {
+        // Friday 2024-07-26 00:00 UTC is 03:00 in Nairobi, so the next weekday 02:00
+        // there is Monday 2024-07-29 02:00 EAT = 2024-07-28 23:00 UTC
+        let after = Utc.with_ymd_and_hms(2024, 7, 26, 0, 0, 0).unwrap();
+        let next = next_run("0 0 2 * * Mon-Fri", Some("Africa/Nairobi"), after).unwrap();
+        assert_eq!(
+            next,
+            Some(Utc.with_ymd_and_hms(2024, 7, 28, 23, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn cron_push_missed() {
+        let mut svc = push_service(Some(Utc.with_ymd_and_hms(2024, 7, 26, 2, 5, 0).unwrap()));
+        svc.cron = Some("0 0 2 * * *".into());
+        svc.grace_period = 600;
+
+        // next run at 02:00 the following day, late by less than the grace period
+        let now = Utc.with_ymd_and_hms(2024, 7, 27, 2, 9, 0).unwrap();
+        assert!(check_at(&svc, now).is_none());
+
+        let now = Utc.with_ymd_and_hms(2024, 7, 27, 2, 11, 0).unwrap();
+        let log = check_at(&svc, now).expect("should be down");
+        assert!(matches!(log.status, Status::Down));
+    }
+
+    #[test]
+    fn cron_invalid_expression() {
+        let mut svc = push_service(Some(Utc::now()));
+        svc.cron = Some("not a cron".into());
+        let log = check_at(&svc, Utc::now()).expect("should fail");
+        assert!(matches!(log.status, Status::Failed));
+    }
+
+    #[test]
+    fn run_exceeded_max_runtime() {
+        let now = Utc::now();
+        let mut svc = push_service(Some(now - TimeDelta::seconds(10)));
+        svc.started_at = Some(now - TimeDelta::seconds(10));
+        svc.max_runtime = Some(60);
+        assert!(check_at(&svc, now).is_none());
+
+        svc.started_at = Some(now - TimeDelta::seconds(61));
+        let log = check_at(&svc, now).expect("should be down");
+        assert!(matches!(log.status, Status::Down));
+    }
+}
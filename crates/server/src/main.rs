@@ -1,54 +1,87 @@
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use axum::{Router, http::HeaderValue, middleware, routing::get};
+use axum::{Extension, Router, http::HeaderValue, middleware, routing::get};
+use clap::Parser;
 use middlewares::log::request_logger;
-use sqlx::{Sqlite, SqlitePool, migrate::MigrateDatabase};
-use tokio::{net::TcpListener, sync::broadcast};
+use sqlx::migrate::MigrateDatabase;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+};
+use tower::util::option_layer;
 use tower_http::{
-    LatencyUnit,
     cors::{Any, CorsLayer},
     services::{ServeDir, ServeFile},
     timeout::TimeoutLayer as HttpTimeoutLayer,
-    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer as HttpTraceLayer},
 };
-use tracing::{Level, error, info};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 use ws::ws_handler;
 
-use crate::{config::env_config, routes::routes, ws::Event as WsEvent};
+use crate::{
+    config::{Cli, EnvConfig, LogFormat},
+    models::{Db, DbPool, settings::Settings},
+    routes::routes,
+    ws::Event as WsEvent,
+};
 
+mod acme;
 mod auth;
 mod config;
+mod error;
 mod extractors;
 mod job;
 mod middlewares;
 mod models;
 mod monitors;
+mod oidc;
 mod routes;
 mod service;
+mod tls;
 mod utils;
+mod validation;
 mod ws;
 
 type AppState = Arc<AppStateInner>;
 
 #[derive(Clone, Debug)]
 struct AppStateInner {
-    pool: SqlitePool,
+    pool: DbPool,
     tx: broadcast::Sender<WsEvent>,
+    /// Runtime settings, workers see changes as soon as they are saved
+    settings: watch::Sender<Settings>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    let env = match EnvConfig::load(&cli) {
+        Ok(env) => env,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1);
+        }
+    };
+    if cli.check_config {
+        print!("{}", toml::to_string(&env)?);
+        return Ok(());
+    }
+    let env = config::init(env);
 
-    let env = env_config();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(EnvFilter::try_new(&env.log_level)?);
+    match env.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
     info!(
         "Running stamon version: {}",
         option_env!("STAMON_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))
     );
 
-    if !Sqlite::database_exists(&env.db_file).await.unwrap_or(false) {
-        info!("Creating database {}", env.db_file);
-        match Sqlite::create_database(&env.db_file).await {
+    std::fs::create_dir_all(&env.data_path)?;
+    if !Db::database_exists(&env.db_url).await.unwrap_or(false) {
+        info!("Creating database {}", env.db_url);
+        match Db::create_database(&env.db_url).await {
             Ok(_) => info!("Create db success"),
             Err(error) => panic!("error: {}", error),
         }
@@ -56,12 +89,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         info!("Database already exists");
     }
 
-    let pool = SqlitePool::connect(&env.db_file)
+    let pool = DbPool::connect(&env.db_url)
         .await
-        .expect("Should open sqlite db");
+        .expect("Should open the database");
 
     // Run database migrations
     models::setup(&pool).await?;
+    let settings = Settings::load(&pool).await?;
 
     let serve_dir = ServeDir::new(&env.assets_path)
         .not_found_service(ServeFile::new(env.assets_path.join("404.html")));
@@ -69,10 +103,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (tx, _rx) = broadcast::channel(100);
 
     // build our application with a route
-    let state = Arc::new(AppStateInner { pool, tx });
+    let state = Arc::new(AppStateInner {
+        pool,
+        tx,
+        settings: watch::Sender::new(settings),
+    });
     let ws_route = Router::new()
         .route("/ws", get(ws_handler))
         .nest("/api", routes())
+        // the pool is also needed by the `Claims` extractor to check sessions
+        .layer(Extension(state.pool.clone()))
         .with_state(state.clone());
     let app = Router::new()
         .merge(ws_route)
@@ -91,12 +131,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             */
             // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
             // requests don't hang forever.
-            HttpTimeoutLayer::new(Duration::from_secs(10)),
-            #[cfg(debug_assertions)]
-            CorsLayer::new()
-                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
-                .allow_methods(Any)
-                .allow_headers(Any),
+            HttpTimeoutLayer::new(Duration::from_secs(env.request_timeout)),
+            option_layer((!env.cors_origins.is_empty()).then(|| {
+                CorsLayer::new()
+                    .allow_origin(
+                        env.cors_origins
+                            .iter()
+                            .map(|origin| origin.parse::<HeaderValue>().unwrap())
+                            .collect::<Vec<_>>(),
+                    )
+                    .allow_methods(Any)
+                    .allow_headers(Any)
+            })),
         ));
 
     // run our app with hyper, listening globally on env.port
@@ -107,24 +153,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 info!("Starting server in developer mode");
                 TcpListener::from_std(listener)
             }
-            None => TcpListener::bind(("0.0.0.0", env.port)).await,
+            None => TcpListener::bind((env.bind_address, env.port)).await,
         }?;
         info!(
             "listening on port {}",
             listener.local_addr().unwrap().port()
         );
-        axum::serve(
-            listener,
-            // this is required for ws to work, I don't know why.
-            app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .with_graceful_shutdown(async {
-            utils::shutdown_signal()
-                .await
-                .expect("failed to install Ctrl+C handler");
-            info!("Ctrl+C Received, Shutting down");
-        })
-        .await?;
+        // this is required for ws to work, I don't know why.
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
+        let Some(tls) = &env.tls else {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    utils::shutdown_signal()
+                        .await
+                        .expect("failed to install Ctrl+C handler");
+                    info!("Ctrl+C Received, Shutting down");
+                })
+                .await?;
+            return Ok(());
+        };
+
+        let rustls = tls::load(tls).await?;
+        tokio::spawn(tls::watch(tls.clone(), rustls.clone()));
+        let challenges = acme::Challenges::default();
+        tokio::spawn(acme::run(tls.clone(), challenges.clone(), rustls.clone()));
+
+        let handle = axum_server::Handle::new();
+        if let Some(http_port) = tls.http_port {
+            let redirect = tls::http_routes(challenges, env.port);
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                info!("listening on port {http_port} for http");
+                let server = axum_server::bind(SocketAddr::new(env.bind_address, http_port))
+                    .handle(handle)
+                    .serve(redirect.into_make_service());
+                if let Err(e) = server.await {
+                    error!("Http server exited with error: {e}");
+                }
+            });
+        }
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                utils::shutdown_signal()
+                    .await
+                    .expect("failed to install Ctrl+C handler");
+                info!("Ctrl+C Received, Shutting down");
+                handle.graceful_shutdown(Some(Duration::from_secs(10)));
+            }
+        });
+        axum_server::from_tcp_rustls(listener.into_std()?, rustls)
+            .handle(handle)
+            .serve(app)
+            .await?;
         Ok::<(), Box<dyn std::error::Error>>(())
     };
 
@@ -0,0 +1,229 @@
+use axum::{
+    Extension,
+    body::{Body, to_bytes},
+    extract::{MatchedPath, Request},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use tracing::error;
+
+use crate::{
+    auth::{AuthError, Claims},
+    error::ApiError,
+    models::{
+        DbPool,
+        audit::{AuditEntry, AuditEntryForCreate, diff, redact},
+        group::Group,
+        service::Service,
+        user::User,
+    },
+};
+
+/// Routes not audited, logins are recorded as auth events and pings are not changes
+const EXCLUDED: &[&str] = &[
+    "login",
+    "logout",
+    "logout-all",
+    "refresh",
+    "register",
+    "push",
+    "oidc",
+];
+
+/// Largest request body of a change
+const MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+struct Target {
+    entity: String,
+    entity_id: Option<String>,
+    action: String,
+}
+
+fn strip_api(path: &str) -> &str {
+    path.trim_start_matches("/api").trim_start_matches('/')
+}
+
+/// Work out what a request changes from the route it matched, like
+/// `/api/services/{id}/pause` for `/api/services/7/pause`
+fn target(method: &Method, matched: &str, path: &str, user_id: u32) -> Option<Target> {
+    let matched: Vec<&str> = strip_api(matched).split('/').collect();
+    let path: Vec<&str> = strip_api(path).split('/').collect();
+
+    let first = *matched.first()?;
+    if EXCLUDED.contains(&first) {
+        return None;
+    }
+    let entity = match first {
+        // the `/user` routes change the current user
+        "user" => "user",
+        plural => plural.strip_suffix('s').unwrap_or(plural),
+    };
+    let entity_id = match first {
+        "user" => Some(user_id.to_string()),
+        _ => matched
+            .iter()
+            .zip(&path)
+            .find(|(segment, _)| segment.starts_with('{'))
+            .map(|(_, value)| value.to_string()),
+    };
+    let action = matched
+        .iter()
+        .skip(1)
+        .filter(|segment| !segment.starts_with('{'))
+        .copied()
+        .collect::<Vec<_>>()
+        .join(".");
+    let action = if !action.is_empty() {
+        action
+    } else if method == Method::POST {
+        "create".into()
+    } else if method == Method::DELETE {
+        "delete".into()
+    } else {
+        "update".into()
+    };
+
+    Some(Target {
+        entity: entity.into(),
+        entity_id,
+        action,
+    })
+}
+
+/// The current state of an entity, for the kinds of entities we can look up
+async fn snapshot(pool: &DbPool, entity: &str, entity_id: Option<&str>) -> Option<Value> {
+    let id: u32 = entity_id?.parse().ok()?;
+    let value = match entity {
+        "service" => serde_json::to_value(Service::get(pool, id).await.ok()??),
+        "group" => serde_json::to_value(Group::get(pool, id).await.ok()??),
+        "user" => serde_json::to_value(User::get(pool, id).await.ok()?),
+        _ => return None,
+    };
+    let mut value = value.ok()?;
+    redact(&mut value);
+    Some(value)
+}
+
+/// Middleware recording successful changes made by logged in users in the audit log
+pub async fn audit_log(
+    claims: Result<Claims, AuthError>,
+    Extension(pool): Extension<DbPool>,
+    matched: Option<MatchedPath>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+    let (Ok(claims), Some(matched)) = (claims, matched) else {
+        return next.run(req).await;
+    };
+    let Some(target) = target(
+        req.method(),
+        matched.as_str(),
+        req.uri().path(),
+        claims.user_id,
+    ) else {
+        return next.run(req).await;
+    };
+
+    // Keep the request body, it is what changed when the entity can't be looked up
+    let (parts, body) = req.into_parts();
+    // forwarding a truncated body would make it a different request
+    let Ok(bytes) = to_bytes(body, MAX_BODY).await else {
+        return ApiError::InvalidRequest(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body too large".into(),
+        )
+        .into_response();
+    };
+    let mut body = serde_json::from_slice::<Value>(&bytes).ok();
+    if let Some(body) = &mut body {
+        redact(body);
+    }
+    let req = Request::from_parts(parts, Body::from(bytes));
+
+    let before = snapshot(&pool, &target.entity, target.entity_id.as_deref()).await;
+    let res = next.run(req).await;
+    if !res.status().is_success() {
+        return res;
+    }
+    let after = snapshot(&pool, &target.entity, target.entity_id.as_deref()).await;
+
+    let (before, after) = match diff(before, after) {
+        // the change was not to the entity itself, like its dependencies
+        (Some(b), Some(a)) if b == a => (None, body),
+        (None, None) => (None, body),
+        changes => changes,
+    };
+    let entry = AuditEntryForCreate {
+        user_id: claims.user_id,
+        action: target.action,
+        entity: target.entity,
+        entity_id: target.entity_id,
+        before,
+        after,
+    };
+    if let Err(e) = AuditEntry::insert(&pool, entry).await {
+        error!("Error recording audit entry: {e}");
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target_of(method: Method, matched: &str, path: &str) -> Option<Target> {
+        target(&method, matched, path, 1)
+    }
+
+    #[test]
+    fn target_from_route() {
+        assert_eq!(
+            target_of(Method::PUT, "/api/services/{id}", "/api/services/7"),
+            Some(Target {
+                entity: "service".into(),
+                entity_id: Some("7".into()),
+                action: "update".into(),
+            })
+        );
+        assert_eq!(
+            target_of(
+                Method::POST,
+                "/api/services/{id}/pause",
+                "/api/services/7/pause"
+            ),
+            Some(Target {
+                entity: "service".into(),
+                entity_id: Some("7".into()),
+                action: "pause".into(),
+            })
+        );
+        assert_eq!(
+            target_of(Method::POST, "/api/services", "/api/services"),
+            Some(Target {
+                entity: "service".into(),
+                entity_id: None,
+                action: "create".into(),
+            })
+        );
+        assert_eq!(
+            target_of(Method::PUT, "/api/user/password", "/api/user/password"),
+            Some(Target {
+                entity: "user".into(),
+                entity_id: Some("1".into()),
+                action: "password".into(),
+            })
+        );
+        assert_eq!(target_of(Method::POST, "/api/login", "/api/login"), None);
+        assert_eq!(
+            target_of(Method::POST, "/api/push/{token}", "/api/push/abc"),
+            None
+        );
+    }
+}
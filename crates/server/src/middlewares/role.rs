@@ -1,23 +1,33 @@
-use axum::{body::Body, extract::Request, http::StatusCode, middleware::Next, response::Response};
-use serde_json::{Value, json};
+use axum::{body::Body, extract::Request, middleware::Next, response::Response};
 use tracing::debug;
 
-use crate::{auth::Claims, extractors::json::Json, models::user::UserRole};
+use crate::{auth::Claims, error::ApiError, models::user::UserRole};
 
 // Middleware for filtering admin users
 pub async fn require_admin_role(
     claims: Claims,
     req: Request<Body>,
     next: Next,
-) -> Result<Response, (StatusCode, Json<Value>)> {
+) -> Result<Response, ApiError> {
     // Check if claims exist in the request extensions
     if let UserRole::Admin = claims.role {
         Ok(next.run(req).await)
     } else {
         debug!("User not admin");
-        Err((
-            StatusCode::UNAUTHORIZED,
-            Json(json!({ "error": "Unauthorized" })),
-        ))
+        Err(ApiError::Forbidden("Forbidden".into()))
+    }
+}
+
+// Middleware for filtering users that can make changes, viewers are read-only
+pub async fn require_editor_role(
+    claims: Claims,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if let UserRole::Admin | UserRole::Editor = claims.role {
+        Ok(next.run(req).await)
+    } else {
+        debug!("User is a viewer");
+        Err(ApiError::Forbidden("Forbidden".into()))
     }
 }
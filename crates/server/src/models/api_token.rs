@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use super::{DbPool, user::UserRole};
+use crate::utils::{hash_token, random_token};
+
+/// Prefix of api tokens, tells them apart from JWTs in the `Authorization` header
+pub const TOKEN_PREFIX: &str = "stm_";
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Read-only access
+    Read,
+    /// Manage services, tags and groups
+    Write,
+    /// Everything the user can do
+    Admin,
+}
+
+impl TokenScope {
+    /// The role a user has when using a token with this scope, never more than their own
+    pub fn limit(self, role: UserRole) -> UserRole {
+        match (self, role) {
+            (_, UserRole::Viewer) | (TokenScope::Read, _) => UserRole::Viewer,
+            (TokenScope::Write, _) => UserRole::Editor,
+            (TokenScope::Admin, role) => role,
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct ApiToken {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    #[sqlx(try_from = "i64")]
+    pub user_id: u32,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiTokenForCreate {
+    pub name: String,
+    pub scope: TokenScope,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    /// Create a token, returning its id and the token to give to the user.
+    ///
+    /// Only a hash of the token is stored, it can't be shown again.
+    pub async fn create(
+        pool: &DbPool,
+        user_id: u32,
+        token: ApiTokenForCreate,
+    ) -> sqlx::Result<(u32, String)> {
+        let secret = format!("{TOKEN_PREFIX}{}", random_token(40));
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            r#"INSERT INTO ApiTokens (user_id, name, token_hash, scope, expires_at)
+               VALUES ($1, $2, $3, $4, $5)
+               RETURNING id"#,
+        )
+        .bind(i64::from(user_id))
+        .bind(token.name)
+        .bind(hash_token(&secret))
+        .bind(token.scope)
+        .bind(token.expires_at)
+        .fetch_one(pool)
+        .await?;
+
+        Ok((id as u32, secret))
+    }
+
+    /// Look up the user and effective role of a token, recording that it was used.
+    ///
+    /// Returns `None` if the token is unknown, expired or its user was disabled.
+    pub async fn authenticate(
+        pool: &DbPool,
+        token: &str,
+    ) -> sqlx::Result<Option<(ApiToken, UserRole)>> {
+        let now = Utc::now();
+        let token = sqlx::query_as::<_, ApiToken>(
+            r#"UPDATE ApiTokens SET last_used_at = $1
+               WHERE token_hash = $2 AND (expires_at IS NULL OR expires_at > $3)
+               RETURNING *"#,
+        )
+        .bind(now)
+        .bind(hash_token(token))
+        .bind(now)
+        .fetch_optional(pool)
+        .await?;
+        let Some(token) = token else {
+            return Ok(None);
+        };
+
+        let role = sqlx::query_as::<_, (UserRole,)>(
+            "SELECT role FROM Users WHERE id = $1 AND active = true",
+        )
+        .bind(i64::from(token.user_id))
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(role.map(|(role,)| {
+            let role = token.scope.limit(role);
+            (token, role)
+        }))
+    }
+
+    pub async fn list(pool: &DbPool, user_id: u32) -> sqlx::Result<Vec<ApiToken>> {
+        sqlx::query_as("SELECT * FROM ApiTokens WHERE user_id = $1 ORDER BY created_at DESC")
+            .bind(i64::from(user_id))
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn revoke(pool: &DbPool, token_id: u32, user_id: u32) -> sqlx::Result<u64> {
+        let result = sqlx::query("DELETE FROM ApiTokens WHERE id = $1 AND user_id = $2")
+            .bind(i64::from(token_id))
+            .bind(i64::from(user_id))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn new_token(scope: TokenScope, expires_at: Option<DateTime<Utc>>) -> ApiTokenForCreate {
+        ApiTokenForCreate {
+            name: "ci".into(),
+            scope,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn scope_limits_role() {
+        assert_eq!(TokenScope::Read.limit(UserRole::Admin), UserRole::Viewer);
+        assert_eq!(TokenScope::Write.limit(UserRole::Admin), UserRole::Editor);
+        assert_eq!(TokenScope::Write.limit(UserRole::Viewer), UserRole::Viewer);
+        assert_eq!(TokenScope::Admin.limit(UserRole::Editor), UserRole::Editor);
+        assert_eq!(TokenScope::Admin.limit(UserRole::Admin), UserRole::Admin);
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn authenticate_token(pool: DbPool) -> sqlx::Result<()> {
+        let (id, secret) = ApiToken::create(&pool, 1, new_token(TokenScope::Write, None)).await?;
+        assert!(secret.starts_with(TOKEN_PREFIX));
+
+        let (token, role) = ApiToken::authenticate(&pool, &secret)
+            .await?
+            .expect("token should be valid");
+        assert_eq!(token.id, id);
+        assert_eq!(role, UserRole::Editor);
+        assert!(ApiToken::list(&pool, 1).await?[0].last_used_at.is_some());
+
+        assert!(
+            ApiToken::authenticate(&pool, "stm_unknown")
+                .await?
+                .is_none()
+        );
+
+        // only the owner can revoke a token
+        assert_eq!(ApiToken::revoke(&pool, id, 3).await?, 0);
+        assert_eq!(ApiToken::revoke(&pool, id, 1).await?, 1);
+        assert!(ApiToken::authenticate(&pool, &secret).await?.is_none());
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn expired_or_disabled_token(pool: DbPool) -> sqlx::Result<()> {
+        let expired = Some(Utc::now() - TimeDelta::hours(1));
+        let (_, secret) = ApiToken::create(&pool, 1, new_token(TokenScope::Read, expired)).await?;
+        assert!(ApiToken::authenticate(&pool, &secret).await?.is_none());
+
+        // user2 is inactive
+        let (_, secret) = ApiToken::create(&pool, 2, new_token(TokenScope::Admin, None)).await?;
+        assert!(ApiToken::authenticate(&pool, &secret).await?.is_none());
+
+        Ok(())
+    }
+}
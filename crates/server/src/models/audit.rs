@@ -0,0 +1,243 @@
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{FromRow, types::Json};
+
+use super::{DbPool, NullableInt};
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct AuditEntry {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    /// The user who made the change
+    #[sqlx(try_from = "NullableInt")]
+    pub user_id: Option<u32>,
+    pub action: String,
+    pub entity: String,
+    pub entity_id: Option<String>,
+    pub before: Option<Json<Value>>,
+    pub after: Option<Json<Value>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditEntryForCreate {
+    pub user_id: u32,
+    pub action: String,
+    pub entity: String,
+    pub entity_id: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub user_id: Option<u32>,
+    pub action: Option<String>,
+    pub entity: Option<String>,
+    pub entity_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// Keep only the fields that differ between two objects.
+///
+/// Values that are not both objects are kept whole.
+pub fn diff(before: Option<Value>, after: Option<Value>) -> (Option<Value>, Option<Value>) {
+    let (Some(Value::Object(before)), Some(Value::Object(after))) = (&before, &after) else {
+        return (before, after);
+    };
+    let changed = |a: &Map<String, Value>, b: &Map<String, Value>| -> Map<String, Value> {
+        a.iter()
+            .filter(|(k, v)| b.get(*k) != Some(*v))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    };
+
+    (
+        Some(Value::Object(changed(before, after))),
+        Some(Value::Object(changed(after, before))),
+    )
+}
+
+/// Hide secrets like passwords and tokens, recursively. Urls keep everything but their
+/// credentials, like `postgres://user:pass@db/app`.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if key == "code"
+                    || ["password", "secret", "token"]
+                        .iter()
+                        .any(|s| key.contains(s))
+                {
+                    *value = Value::String("[redacted]".into());
+                } else if key.contains("url")
+                    && let Value::String(url) = value
+                {
+                    strip_credentials(url);
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+fn strip_credentials(value: &mut String) {
+    let Ok(mut url) = Url::parse(value) else {
+        return;
+    };
+    if url.username().is_empty() && url.password().is_none() {
+        return;
+    }
+    if url.set_username("").is_ok() && url.set_password(None).is_ok() {
+        *value = url.into();
+    }
+}
+
+impl AuditEntry {
+    pub async fn insert(pool: &DbPool, entry: AuditEntryForCreate) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO AuditLog (user_id, action, entity, entity_id, before, after, created_at)
+               VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+        )
+        .bind(i64::from(entry.user_id))
+        .bind(entry.action)
+        .bind(entry.entity)
+        .bind(entry.entity_id)
+        .bind(entry.before.map(Json))
+        .bind(entry.after.map(Json))
+        .bind(Utc::now())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn list(pool: &DbPool, filter: &AuditFilter) -> sqlx::Result<Vec<AuditEntry>> {
+        sqlx::query_as(
+            r#"SELECT *
+               FROM AuditLog
+               WHERE ($1 IS NULL OR user_id = $2)
+                 AND ($3 IS NULL OR action = $4)
+                 AND ($5 IS NULL OR entity = $6)
+                 AND ($7 IS NULL OR entity_id = $8)
+                 AND ($9 IS NULL OR created_at >= $10)
+                 AND ($11 IS NULL OR created_at < $12)
+               ORDER BY created_at DESC, id DESC
+               LIMIT $13 OFFSET $14"#,
+        )
+        .bind(filter.user_id.map(i64::from))
+        .bind(filter.user_id.map(i64::from))
+        .bind(&filter.action)
+        .bind(&filter.action)
+        .bind(&filter.entity)
+        .bind(&filter.entity)
+        .bind(&filter.entity_id)
+        .bind(&filter.entity_id)
+        .bind(filter.since)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(filter.until)
+        .bind(i64::from(filter.limit.unwrap_or(100)))
+        .bind(i64::from(filter.offset.unwrap_or(0)))
+        .fetch_all(pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diff_changed_fields() {
+        let before = json!({ "name": "api", "interval": 60, "active": true });
+        let after = json!({ "name": "api", "interval": 30, "active": false });
+
+        let (before, after) = diff(Some(before), Some(after));
+        assert_eq!(before, Some(json!({ "interval": 60, "active": true })));
+        assert_eq!(after, Some(json!({ "interval": 30, "active": false })));
+
+        // creations and deletions keep everything
+        let (before, after) = diff(None, Some(json!({ "name": "api" })));
+        assert_eq!(before, None);
+        assert_eq!(after, Some(json!({ "name": "api" })));
+    }
+
+    #[test]
+    fn redact_secrets() {
+        let mut value = json!({
+            "username": "jane",
+            "password": "hunter2",
+            "push_token": "abc",
+            "nested": [{ "client_secret": "x", "code": "123456" }],
+            "url": "postgres://app:hunter2@db:5432/app",
+            "webhook_url": "redis://:secret@cache:6379/0",
+        });
+        redact(&mut value);
+
+        assert_eq!(
+            value,
+            json!({
+                "username": "jane",
+                "password": "[redacted]",
+                "push_token": "[redacted]",
+                "nested": [{ "client_secret": "[redacted]", "code": "[redacted]" }],
+                "url": "postgres://db:5432/app",
+                "webhook_url": "redis://cache:6379/0",
+            })
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn filter_entries(pool: DbPool) -> sqlx::Result<()> {
+        let entry = |user_id, action: &str, entity_id: &str| AuditEntryForCreate {
+            user_id,
+            action: action.into(),
+            entity: "service".into(),
+            entity_id: Some(entity_id.into()),
+            before: Some(json!({ "active": true })),
+            after: Some(json!({ "active": false })),
+        };
+        AuditEntry::insert(&pool, entry(1, "pause", "1")).await?;
+        AuditEntry::insert(&pool, entry(3, "update", "1")).await?;
+        AuditEntry::insert(&pool, entry(1, "update", "2")).await?;
+
+        let all = AuditEntry::list(&pool, &AuditFilter::default()).await?;
+        assert_eq!(all.len(), 3);
+        // newest first
+        assert_eq!(all[0].entity_id.as_deref(), Some("2"));
+        assert_eq!(
+            all[2].after.as_ref().map(|a| &a.0),
+            Some(&json!({ "active": false }))
+        );
+
+        let filter = AuditFilter {
+            entity: Some("service".into()),
+            entity_id: Some("1".into()),
+            ..Default::default()
+        };
+        assert_eq!(AuditEntry::list(&pool, &filter).await?.len(), 2);
+
+        let filter = AuditFilter {
+            user_id: Some(1),
+            action: Some("update".into()),
+            ..Default::default()
+        };
+        let entries = AuditEntry::list(&pool, &filter).await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].entity_id.as_deref(), Some("2"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use super::{DbPool, NullableInt};
+
+/// Failed logins are only counted for this long, it is also how long a lockout lasts
+pub const LOCKOUT: TimeDelta = TimeDelta::minutes(15);
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuthEventKind {
+    LoginSuccess,
+    LoginFailure,
+    /// Too many failed logins for a username
+    Lockout,
+    PasswordChange,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct AuthEvent {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    pub kind: AuthEventKind,
+    #[sqlx(try_from = "NullableInt")]
+    pub user_id: Option<u32>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthEventForCreate {
+    pub kind: AuthEventKind,
+    pub user_id: Option<u32>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthEventFilter {
+    pub user_id: Option<u32>,
+    pub kind: Option<AuthEventKind>,
+    pub limit: Option<u32>,
+}
+
+/// How failed logins slow down further attempts
+struct Throttle {
+    /// Failures allowed before attempts are delayed
+    free_attempts: u32,
+    /// Failures after which attempts are refused until [`LOCKOUT`] has passed
+    lockout_after: u32,
+}
+
+const USERNAME_THROTTLE: Throttle = Throttle {
+    free_attempts: 3,
+    lockout_after: 10,
+};
+
+// Higher, many users may share an address
+const IP_THROTTLE: Throttle = Throttle {
+    free_attempts: 10,
+    lockout_after: 50,
+};
+
+impl Throttle {
+    /// How long to wait before the next attempt, the delay doubles with every failure
+    fn retry_after(
+        &self,
+        failures: u32,
+        last_failure: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Option<TimeDelta> {
+        if failures < self.free_attempts {
+            return None;
+        }
+        let delay = if failures >= self.lockout_after {
+            LOCKOUT
+        } else {
+            let exp = (failures - self.free_attempts).min(20);
+            TimeDelta::seconds(1 << exp).min(LOCKOUT)
+        };
+        let wait = last_failure + delay - now;
+        (wait > TimeDelta::zero()).then_some(wait)
+    }
+}
+
+impl AuthEvent {
+    pub async fn insert(pool: &DbPool, event: AuthEventForCreate) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO AuthEvents (kind, user_id, username, ip, detail, created_at)
+               VALUES ($1, $2, $3, $4, $5, $6)"#,
+        )
+        .bind(event.kind)
+        .bind(event.user_id.map(i64::from))
+        .bind(event.username)
+        .bind(event.ip)
+        .bind(event.detail)
+        .bind(Utc::now())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a failed login, and a lockout if it was one failure too many
+    pub async fn insert_failure(pool: &DbPool, event: AuthEventForCreate) -> sqlx::Result<()> {
+        let lockout = AuthEventForCreate {
+            kind: AuthEventKind::Lockout,
+            detail: None,
+            ..event.clone()
+        };
+        let username = event.username.clone();
+        Self::insert(pool, event).await?;
+
+        if let Some(username) = username {
+            let (failures, _) = Self::username_failures(pool, &username).await?;
+            if failures == USERNAME_THROTTLE.lockout_after {
+                Self::insert(pool, lockout).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Recent failures for a username since its last successful login
+    async fn username_failures(
+        pool: &DbPool,
+        username: &str,
+    ) -> sqlx::Result<(u32, Option<DateTime<Utc>>)> {
+        sqlx::query_as(
+            r#"SELECT COUNT(*), MAX(created_at)
+               FROM AuthEvents
+               WHERE kind = 'login_failure' AND username = $1 AND created_at > $2
+                 AND created_at > COALESCE((
+                     SELECT MAX(created_at) FROM AuthEvents
+                     WHERE kind = 'login_success' AND username = $1
+                 ), $2)"#,
+        )
+        .bind(username)
+        .bind(Utc::now() - LOCKOUT)
+        .fetch_one(pool)
+        .await
+        .map(|(count, last): (i64, _)| (count as u32, last))
+    }
+
+    /// Recent failures from an address, successful logins don't reset these
+    async fn ip_failures(pool: &DbPool, ip: &str) -> sqlx::Result<(u32, Option<DateTime<Utc>>)> {
+        sqlx::query_as(
+            r#"SELECT COUNT(*), MAX(created_at)
+               FROM AuthEvents
+               WHERE kind = 'login_failure' AND ip = $1 AND created_at > $2"#,
+        )
+        .bind(ip)
+        .bind(Utc::now() - LOCKOUT)
+        .fetch_one(pool)
+        .await
+        .map(|(count, last): (i64, _)| (count as u32, last))
+    }
+
+    /// How long a login for `username` from `ip` has to wait, `None` if it can be tried now
+    pub async fn login_delay(
+        pool: &DbPool,
+        username: &str,
+        ip: &str,
+    ) -> sqlx::Result<Option<TimeDelta>> {
+        let now = Utc::now();
+        let by_username = match Self::username_failures(pool, username).await? {
+            (failures, Some(last)) => USERNAME_THROTTLE.retry_after(failures, last, now),
+            _ => None,
+        };
+        let by_ip = match Self::ip_failures(pool, ip).await? {
+            (failures, Some(last)) => IP_THROTTLE.retry_after(failures, last, now),
+            _ => None,
+        };
+
+        Ok(by_username.max(by_ip))
+    }
+
+    pub async fn list(pool: &DbPool, filter: &AuthEventFilter) -> sqlx::Result<Vec<AuthEvent>> {
+        sqlx::query_as(
+            r#"SELECT *
+               FROM AuthEvents
+               WHERE ($1 IS NULL OR user_id = $2) AND ($3 IS NULL OR kind = $4)
+               ORDER BY created_at DESC, id DESC
+               LIMIT $5"#,
+        )
+        .bind(filter.user_id.map(i64::from))
+        .bind(filter.user_id.map(i64::from))
+        .bind(filter.kind)
+        .bind(filter.kind)
+        .bind(i64::from(filter.limit.unwrap_or(100)))
+        .fetch_all(pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: AuthEventKind, username: &str, ip: &str) -> AuthEventForCreate {
+        AuthEventForCreate {
+            kind,
+            user_id: None,
+            username: Some(username.into()),
+            ip: Some(ip.into()),
+            detail: None,
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let now = Utc::now();
+        let throttle = &USERNAME_THROTTLE;
+
+        assert_eq!(throttle.retry_after(2, now, now), None);
+        assert_eq!(
+            throttle.retry_after(3, now, now),
+            Some(TimeDelta::seconds(1))
+        );
+        assert_eq!(
+            throttle.retry_after(5, now, now),
+            Some(TimeDelta::seconds(4))
+        );
+        assert_eq!(throttle.retry_after(10, now, now), Some(LOCKOUT));
+        // the delay counts from the last failure
+        let last = now - TimeDelta::seconds(3);
+        assert_eq!(
+            throttle.retry_after(5, last, now),
+            Some(TimeDelta::seconds(1))
+        );
+        assert_eq!(throttle.retry_after(4, last, now), None);
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn throttle_failed_logins(pool: DbPool) -> sqlx::Result<()> {
+        for _ in 0..3 {
+            assert!(
+                AuthEvent::login_delay(&pool, "admin", "10.0.0.1")
+                    .await?
+                    .is_none()
+            );
+            AuthEvent::insert_failure(
+                &pool,
+                event(AuthEventKind::LoginFailure, "admin", "10.0.0.1"),
+            )
+            .await?;
+        }
+        assert!(
+            AuthEvent::login_delay(&pool, "admin", "10.0.0.1")
+                .await?
+                .is_some()
+        );
+        // other usernames from another address are not affected
+        assert!(
+            AuthEvent::login_delay(&pool, "other", "10.0.0.2")
+                .await?
+                .is_none()
+        );
+
+        // a successful login resets the count for the username
+        AuthEvent::insert(
+            &pool,
+            event(AuthEventKind::LoginSuccess, "admin", "10.0.0.3"),
+        )
+        .await?;
+        assert!(
+            AuthEvent::login_delay(&pool, "admin", "10.0.0.3")
+                .await?
+                .is_none()
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn lockout_is_recorded(pool: DbPool) -> sqlx::Result<()> {
+        for _ in 0..USERNAME_THROTTLE.lockout_after {
+            AuthEvent::insert_failure(
+                &pool,
+                event(AuthEventKind::LoginFailure, "admin", "10.0.0.1"),
+            )
+            .await?;
+        }
+        // locked out from any address
+        let delay = AuthEvent::login_delay(&pool, "admin", "10.0.0.9").await?;
+        assert!(delay.is_some_and(|d| d > LOCKOUT - TimeDelta::minutes(1)));
+
+        let filter = AuthEventFilter {
+            kind: Some(AuthEventKind::Lockout),
+            ..Default::default()
+        };
+        let lockouts = AuthEvent::list(&pool, &filter).await?;
+        assert_eq!(lockouts.len(), 1);
+        assert_eq!(lockouts[0].username.as_deref(), Some("admin"));
+
+        let all = AuthEvent::list(&pool, &AuthEventFilter::default()).await?;
+        assert_eq!(all.len(), USERNAME_THROTTLE.lockout_after as usize + 1);
+
+        Ok(())
+    }
+}
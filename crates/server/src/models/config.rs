@@ -1,8 +1,11 @@
 use chrono::{DateTime, Utc};
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{Executor, FromRow};
+
+use super::{Db, DbPool};
 
 #[derive(Debug, FromRow)]
 pub struct Config {
+    #[sqlx(try_from = "i64")]
     pub id: u16,
     pub name: String,
     pub value: String,
@@ -12,43 +15,57 @@ pub struct Config {
 
 impl Config {
     pub async fn insert(
-        pool: &SqlitePool,
+        pool: &DbPool,
         name: String,
         value: String,
         category: Option<String>,
     ) -> sqlx::Result<u64> {
-        // Construct the base query
-        let mut query = "INSERT INTO Configs (name, value, last_updated".to_string();
-        if category.is_some() {
-            query.push_str(", category");
-        }
-        query.push_str(") VALUES (?, ?, ?");
-        if category.is_some() {
-            query.push_str(", ?");
-        }
-        query.push(')');
-
-        // Create a query builder and bind parameters
-        let mut query_builder = sqlx::query(&query).bind(name).bind(value).bind(Utc::now());
-
-        if let Some(category_value) = category {
-            query_builder = query_builder.bind(category_value);
-        }
-
-        // Execute the query
-        let result = query_builder.execute(pool).await?;
+        let result = sqlx::query(
+            "INSERT INTO Configs (name, value, last_updated, category) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(name)
+        .bind(value)
+        .bind(Utc::now())
+        .bind(category)
+        .execute(pool)
+        .await?;
+
         Ok(result.rows_affected())
     }
 
-    pub async fn get_by_name(pool: &SqlitePool, name: &str) -> sqlx::Result<Option<Config>> {
-        sqlx::query_as("SELECT * FROM Configs WHERE name = ?")
+    pub async fn get_by_name(pool: &DbPool, name: &str) -> sqlx::Result<Option<Config>> {
+        sqlx::query_as("SELECT * FROM Configs WHERE name = $1")
             .bind(name)
             .fetch_optional(pool)
             .await
     }
 
-    pub async fn list_by_category(pool: &SqlitePool, category: &str) -> sqlx::Result<Vec<Config>> {
-        sqlx::query_as("SELECT * FROM Configs WHERE category = ?")
+    /// Insert or replace the value of a config
+    pub async fn set<'e>(
+        executor: impl Executor<'e, Database = Db>,
+        name: &str,
+        value: &str,
+        category: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            r#"INSERT INTO Configs (name, value, category, last_updated) VALUES ($1, $2, $3, $4)
+               ON CONFLICT (name) DO UPDATE
+               SET value = excluded.value,
+                   category = excluded.category,
+                   last_updated = CURRENT_TIMESTAMP"#,
+        )
+        .bind(name)
+        .bind(value)
+        .bind(category)
+        .bind(Utc::now())
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_by_category(pool: &DbPool, category: &str) -> sqlx::Result<Vec<Config>> {
+        sqlx::query_as("SELECT * FROM Configs WHERE category = $1")
             .bind(category)
             .fetch_all(pool)
             .await
@@ -57,12 +74,27 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use sqlx::SqlitePool;
-
     use super::*;
 
-    #[sqlx::test]
-    async fn insert_config_without_category(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn set_updates_timestamp(pool: DbPool) -> sqlx::Result<()> {
+        let long_ago = Utc::now() - chrono::TimeDelta::days(1);
+        Config::set(&pool, "retention_days", "30", Some("settings")).await?;
+        sqlx::query("UPDATE Configs SET last_updated = $1")
+            .bind(long_ago)
+            .execute(&pool)
+            .await?;
+        Config::set(&pool, "retention_days", "60", Some("settings")).await?;
+
+        let config = Config::get_by_name(&pool, "retention_days").await?.unwrap();
+        assert_eq!(config.value, "60");
+        assert!(config.last_updated > long_ago + chrono::TimeDelta::hours(23));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn insert_config_without_category(pool: DbPool) -> sqlx::Result<()> {
         let count = Config::insert(
             &pool,
             "test_setting".to_string(),
@@ -84,8 +116,8 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn insert_config_with_category(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn insert_config_with_category(pool: DbPool) -> sqlx::Result<()> {
         let count = Config::insert(
             &pool,
             "notification_enabled".to_string(),
@@ -107,16 +139,28 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn get_nonexistent_config(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn get_nonexistent_config(pool: DbPool) -> sqlx::Result<()> {
         let config = Config::get_by_name(&pool, "nonexistent").await?;
         assert!(config.is_none());
 
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn list_configs_by_category(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn set_config_replaces_value(pool: DbPool) -> sqlx::Result<()> {
+        Config::set(&pool, "site_title", "\"Stamon\"", Some("settings")).await?;
+        Config::set(&pool, "site_title", "\"Status\"", Some("settings")).await?;
+
+        let configs = Config::list_by_category(&pool, "settings").await?;
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].value, "\"Status\"");
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn list_configs_by_category(pool: DbPool) -> sqlx::Result<()> {
         // Insert multiple configs in the same category
         Config::insert(
             &pool,
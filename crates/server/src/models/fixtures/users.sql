@@ -1,5 +1,5 @@
 INSERT INTO Users (username, password, role, active, timezone) VALUES
-('user1', 'password123', 'admin', 1, 'Africa/Nairobi'),
-('user2', 'password789', 'admin', 0, 'America/New_York'),
-('user3', 'passwordabc', 'viewer', 1, 'Europe/Berlin'),
-('user4', 'passwordxyz', 'admin', 0, 'Asia/Kolkata');
+('user1', 'password123', 'admin', TRUE, 'Africa/Nairobi'),
+('user2', 'password789', 'admin', FALSE, 'America/New_York'),
+('user3', 'passwordabc', 'viewer', TRUE, 'Europe/Berlin'),
+('user4', 'passwordxyz', 'admin', FALSE, 'Asia/Kolkata');
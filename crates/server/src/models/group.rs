@@ -0,0 +1,324 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, QueryBuilder, Type};
+
+use crate::build_update_query;
+
+use super::{DbPool, NullableInt, log::Status, service::push_visible_services};
+
+/// How the status of a group is computed from its members
+#[derive(Debug, Clone, Copy, Type, Default, Serialize, Deserialize)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    /// The group has the worst status of any of its members
+    #[default]
+    Worst,
+    /// The group is up while at least `quorum` members are up
+    Quorum,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Group {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    pub name: String,
+    #[sqlx(try_from = "NullableInt")]
+    pub parent_id: Option<u32>,
+    pub aggregate: Aggregate,
+    #[sqlx(try_from = "NullableInt")]
+    pub quorum: Option<u32>,
+    pub last_status: Status,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GroupForCreate {
+    pub name: String,
+    pub parent_id: Option<u32>,
+    pub aggregate: Option<Aggregate>,
+    pub quorum: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GroupForUpdate {
+    pub name: Option<String>,
+    pub parent_id: Option<u32>,
+    pub aggregate: Option<Aggregate>,
+    pub quorum: Option<u32>,
+}
+
+/// Compute the status of a group from the statuses of its members
+pub fn aggregate_status(statuses: &[Status], aggregate: Aggregate, quorum: Option<u32>) -> Status {
+    if statuses.is_empty() {
+        return Status::Pending;
+    }
+    match aggregate {
+        Aggregate::Worst => statuses
+            .iter()
+            .copied()
+            .max_by_key(|s| match s {
+                Status::Up => 0,
+                Status::Pending => 1,
+                Status::Failed => 2,
+                Status::Down | Status::Unreachable => 3,
+            })
+            .unwrap_or_default(),
+        Aggregate::Quorum => {
+            let up = statuses.iter().filter(|s| **s == Status::Up).count() as u32;
+            // without a quorum a majority of members must be up
+            let quorum = quorum.unwrap_or(statuses.len() as u32 / 2 + 1);
+            if up >= quorum {
+                Status::Up
+            } else if statuses
+                .iter()
+                .any(|s| matches!(s, Status::Down | Status::Failed | Status::Unreachable))
+            {
+                Status::Down
+            } else {
+                Status::Pending
+            }
+        }
+    }
+}
+
+impl Group {
+    pub async fn insert(pool: &DbPool, group: GroupForCreate) -> sqlx::Result<u64> {
+        let result = sqlx::query(
+            "INSERT INTO ServiceGroups (name, parent_id, aggregate, quorum) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(group.name)
+        .bind(group.parent_id.map(i64::from))
+        .bind(group.aggregate.unwrap_or_default())
+        .bind(group.quorum.map(i64::from))
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get(pool: &DbPool, group_id: u32) -> sqlx::Result<Option<Group>> {
+        sqlx::query_as("SELECT * FROM ServiceGroups WHERE id = $1")
+            .bind(i64::from(group_id))
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn list(pool: &DbPool) -> sqlx::Result<Vec<Group>> {
+        sqlx::query_as("SELECT * FROM ServiceGroups ORDER BY name")
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn update(
+        pool: &DbPool,
+        group_id: u32,
+        update_data: GroupForUpdate,
+    ) -> sqlx::Result<u64> {
+        let mut query = QueryBuilder::new("UPDATE ServiceGroups SET ");
+        let has_updates = build_update_query!(query, update_data, {
+            name,
+            parent_id as i64,
+            aggregate,
+            quorum as i64
+        });
+        if !has_updates {
+            // No updates were provided
+            return Ok(0);
+        }
+        query.push(" WHERE id = ").push_bind(i64::from(group_id));
+
+        let result = query.build().execute(pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Delete a group, its services and subgroups are left ungrouped
+    pub async fn delete(pool: &DbPool, group_id: u32) -> sqlx::Result<u64> {
+        let result = sqlx::query("DELETE FROM ServiceGroups WHERE id = $1")
+            .bind(i64::from(group_id))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Whether the services in this group and all of its subgroups are active, with their
+    /// statuses. Only the services visible to `visible_to` are included unless it is `None`.
+    async fn members(
+        pool: &DbPool,
+        group_id: u32,
+        visible_to: Option<u32>,
+    ) -> sqlx::Result<Vec<(bool, Status)>> {
+        let mut query = QueryBuilder::new(
+            r#"WITH RECURSIVE tree(id) AS (
+                SELECT "#,
+        );
+        query.push_bind(i64::from(group_id)).push(
+            r#"
+                UNION
+                SELECT g.id FROM ServiceGroups g JOIN tree t ON g.parent_id = t.id
+            )
+            SELECT active, last_status
+            FROM Services
+            WHERE group_id IN (SELECT id FROM tree)"#,
+        );
+        if let Some(user_id) = visible_to {
+            query.push(" AND id IN (");
+            push_visible_services(&mut query, user_id);
+            query.push(")");
+        }
+        query.build_query_as().fetch_all(pool).await
+    }
+
+    /// Statuses of the active services in this group and all of its subgroups, only those
+    /// visible to `visible_to` unless it is `None`
+    pub async fn member_statuses(
+        pool: &DbPool,
+        group_id: u32,
+        visible_to: Option<u32>,
+    ) -> sqlx::Result<Vec<Status>> {
+        let members = Group::members(pool, group_id, visible_to).await?;
+        Ok(members
+            .into_iter()
+            .filter_map(|(active, status)| active.then_some(status))
+            .collect())
+    }
+
+    /// The current aggregate status of this group, from the services visible to `visible_to`
+    /// unless it is `None`
+    pub async fn status(&self, pool: &DbPool, visible_to: Option<u32>) -> sqlx::Result<Status> {
+        let statuses = Group::member_statuses(pool, self.id, visible_to).await?;
+        Ok(aggregate_status(&statuses, self.aggregate, self.quorum))
+    }
+
+    /// Whether a user can see this group: it holds one of their services or no service at all
+    pub async fn is_visible_to(&self, pool: &DbPool, user_id: u32) -> sqlx::Result<bool> {
+        Ok(!Group::members(pool, self.id, Some(user_id))
+            .await?
+            .is_empty()
+            || Group::members(pool, self.id, None).await?.is_empty())
+    }
+
+    /// The group with the given id followed by its parents up to the root
+    pub async fn ancestors(pool: &DbPool, group_id: u32) -> sqlx::Result<Vec<Group>> {
+        sqlx::query_as(
+            r#"WITH RECURSIVE tree(id, parent_id, depth) AS (
+                SELECT id, parent_id, 0 FROM ServiceGroups WHERE id = $1
+                UNION
+                SELECT g.id, g.parent_id, t.depth + 1
+                FROM ServiceGroups g JOIN tree t ON g.id = t.parent_id
+                WHERE t.depth < 32
+            )
+            SELECT g.*
+            FROM tree t
+            JOIN ServiceGroups g ON g.id = t.id
+            ORDER BY t.depth"#,
+        )
+        .bind(i64::from(group_id))
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn set_last_status(
+        pool: &DbPool,
+        group_id: u32,
+        status: Status,
+    ) -> sqlx::Result<u64> {
+        let result = sqlx::query("UPDATE ServiceGroups SET last_status = $1 WHERE id = $2")
+            .bind(status)
+            .bind(i64::from(group_id))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worst_of_status() {
+        let statuses = [Status::Up, Status::Down, Status::Pending];
+        assert_eq!(
+            aggregate_status(&statuses, Aggregate::Worst, None),
+            Status::Down
+        );
+        assert_eq!(
+            aggregate_status(&[Status::Up, Status::Up], Aggregate::Worst, None),
+            Status::Up
+        );
+        assert_eq!(
+            aggregate_status(&[], Aggregate::Worst, None),
+            Status::Pending
+        );
+    }
+
+    #[test]
+    fn quorum_status() {
+        let statuses = [Status::Up, Status::Up, Status::Down];
+        assert_eq!(
+            aggregate_status(&statuses, Aggregate::Quorum, Some(2)),
+            Status::Up
+        );
+        assert_eq!(
+            aggregate_status(&statuses, Aggregate::Quorum, Some(3)),
+            Status::Down
+        );
+        // defaults to a majority
+        assert_eq!(
+            aggregate_status(&statuses, Aggregate::Quorum, None),
+            Status::Up
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn nested_group_status(pool: DbPool) -> sqlx::Result<()> {
+        Group::insert(
+            &pool,
+            GroupForCreate {
+                name: "infra".into(),
+                ..Default::default()
+            },
+        )
+        .await?;
+        Group::insert(
+            &pool,
+            GroupForCreate {
+                name: "databases".into(),
+                parent_id: Some(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // service 2 is up, service 5 is down
+        sqlx::query("UPDATE Services SET group_id = 1 WHERE id = 2")
+            .execute(&pool)
+            .await?;
+        sqlx::query("UPDATE Services SET group_id = 2 WHERE id = 5")
+            .execute(&pool)
+            .await?;
+
+        let parent = Group::get(&pool, 1).await?.unwrap();
+        assert_eq!(Group::member_statuses(&pool, 1, None).await?.len(), 2);
+        assert_eq!(parent.status(&pool, None).await?, Status::Down);
+
+        let child = Group::get(&pool, 2).await?.unwrap();
+        assert_eq!(Group::member_statuses(&pool, 2, None).await?.len(), 1);
+        assert_eq!(child.status(&pool, None).await?, Status::Down);
+
+        // user 2 only sees service 2
+        assert_eq!(parent.status(&pool, Some(2)).await?, Status::Up);
+        assert!(parent.is_visible_to(&pool, 2).await?);
+        assert!(!child.is_visible_to(&pool, 2).await?);
+        assert!(child.is_visible_to(&pool, 1).await?);
+
+        let ancestors = Group::ancestors(&pool, 2).await?;
+        assert_eq!(
+            ancestors.iter().map(|g| g.id).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+
+use super::{
+    DbPool, NullableInt,
+    user::{User, UserForRegister, UserRole},
+};
+use crate::utils::{hash_token, random_token};
+
+/// Id of the row inserted last on this connection
+#[cfg(not(feature = "postgres"))]
+const LAST_INSERT_ID: &str = "SELECT last_insert_rowid()";
+#[cfg(feature = "postgres")]
+const LAST_INSERT_ID: &str = "SELECT lastval()";
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct Invitation {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    pub role: UserRole,
+    #[sqlx(try_from = "i64")]
+    pub created_by: u32,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    #[sqlx(try_from = "NullableInt")]
+    pub used_by: Option<u32>,
+}
+
+impl Invitation {
+    /// Create an invitation, returning the token to send to the invitee.
+    ///
+    /// Only a hash of the token is stored.
+    pub async fn create(
+        pool: &DbPool,
+        role: UserRole,
+        created_by: u32,
+        expires_at: DateTime<Utc>,
+    ) -> sqlx::Result<String> {
+        let token = random_token(32);
+        sqlx::query(
+            "INSERT INTO Invitations (token_hash, role, created_by, expires_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(hash_token(&token))
+        .bind(role)
+        .bind(i64::from(created_by))
+        .bind(expires_at)
+        .execute(pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Invitations that have not been used or expired yet
+    pub async fn list_pending(pool: &DbPool) -> sqlx::Result<Vec<Invitation>> {
+        sqlx::query_as(
+            r#"SELECT *
+               FROM Invitations
+               WHERE used_at IS NULL AND expires_at > $1
+               ORDER BY created_at DESC"#,
+        )
+        .bind(Utc::now())
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn delete(pool: &DbPool, invitation_id: u32) -> sqlx::Result<u64> {
+        let result = sqlx::query("DELETE FROM Invitations WHERE id = $1")
+            .bind(i64::from(invitation_id))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Register a user with an invitation token, returning the new user id.
+    ///
+    /// Returns `None` if the token is unknown, used or expired. The role of the
+    /// invitation overrides any role in `user`.
+    pub async fn accept(
+        pool: &DbPool,
+        token: &str,
+        mut user: UserForRegister,
+    ) -> sqlx::Result<Option<u32>> {
+        let mut tx = pool.begin().await?;
+
+        let invitation = sqlx::query_as::<_, Invitation>(
+            r#"SELECT *
+               FROM Invitations
+               WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2"#,
+        )
+        .bind(hash_token(token))
+        .bind(Utc::now())
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(invitation) = invitation else {
+            return Ok(None);
+        };
+
+        user.role = Some(invitation.role);
+        User::insert(&mut *tx, user).await?;
+        let (user_id,) = sqlx::query_as::<_, (i64,)>(LAST_INSERT_ID)
+            .fetch_one(&mut *tx)
+            .await?;
+        let user_id = user_id as u32;
+
+        sqlx::query("UPDATE Invitations SET used_at = $1, used_by = $2 WHERE id = $3")
+            .bind(Utc::now())
+            .bind(i64::from(user_id))
+            .bind(i64::from(invitation.id))
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(Some(user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn new_user(username: &str) -> UserForRegister {
+        UserForRegister {
+            username: username.into(),
+            role: Some(UserRole::Admin),
+            password: "password".into(),
+            timezone: None,
+        }
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn accept_invitation_once(pool: DbPool) -> sqlx::Result<()> {
+        let expires_at = Utc::now() + TimeDelta::days(1);
+        let token = Invitation::create(&pool, UserRole::Viewer, 1, expires_at).await?;
+        assert_eq!(Invitation::list_pending(&pool).await?.len(), 1);
+
+        let user_id = Invitation::accept(&pool, &token, new_user("invited"))
+            .await?
+            .expect("invitation should be valid");
+        let user = User::get(&pool, user_id).await?;
+        assert_eq!(user.username, "invited");
+        // the role comes from the invitation
+        assert_eq!(user.role, UserRole::Viewer);
+
+        assert!(
+            Invitation::accept(&pool, &token, new_user("again"))
+                .await?
+                .is_none()
+        );
+        assert!(Invitation::list_pending(&pool).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn expired_invitation(pool: DbPool) -> sqlx::Result<()> {
+        let expires_at = Utc::now() - TimeDelta::minutes(1);
+        let token = Invitation::create(&pool, UserRole::Editor, 1, expires_at).await?;
+
+        assert!(
+            Invitation::accept(&pool, &token, new_user("late"))
+                .await?
+                .is_none()
+        );
+        assert!(
+            Invitation::accept(&pool, "unknown", new_user("nobody"))
+                .await?
+                .is_none()
+        );
+
+        Ok(())
+    }
+}
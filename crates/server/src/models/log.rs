@@ -3,12 +3,20 @@ use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use sqlx::{
-    SqlitePool,
+    QueryBuilder,
     prelude::{FromRow, Type},
 };
 
-#[derive(Debug, Clone, Copy, Type, Default, Deserialize_repr, Serialize_repr)]
-#[repr(u8)]
+use super::{DbPool, NullableInt, service::push_visible_services};
+
+/// Delete logs older than a time, SQLite stores times as text in more than one format
+#[cfg(not(feature = "postgres"))]
+const DELETE_BEFORE: &str = "DELETE FROM Logs WHERE datetime(time) < datetime($1)";
+#[cfg(feature = "postgres")]
+const DELETE_BEFORE: &str = "DELETE FROM Logs WHERE time < $1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Type, Default, Deserialize_repr, Serialize_repr)]
+#[repr(i32)]
 pub enum Status {
     #[default]
     Pending = 0,
@@ -16,16 +24,45 @@ pub enum Status {
     Down = 2,
     /// There was an internal error
     Failed = 3,
+    /// Not checked because a service it depends on is down
+    Unreachable = 4,
+}
+
+/// How a ping check reached the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Type, Serialize, Deserialize)]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum PingMethod {
+    /// Unprivileged datagram ICMP socket
+    Icmp,
+    IcmpRaw,
+    /// TCP connections, when ICMP sockets are not permitted
+    Tcp,
 }
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 pub struct Log {
     pub id: i64,
+    #[sqlx(try_from = "i64")]
     pub service_id: u32,
     pub status: Status,
     pub message: Option<String>,
     pub time: DateTime<Utc>,
+    #[sqlx(try_from = "i64")]
     pub duration: u32,
+    /// Parent service that made this service unreachable
+    #[sqlx(try_from = "NullableInt")]
+    pub cause_id: Option<u32>,
+    /// Round trip times of a ping check in milliseconds
+    #[sqlx(try_from = "NullableInt")]
+    pub rtt_min: Option<u32>,
+    #[sqlx(try_from = "NullableInt")]
+    pub rtt_avg: Option<u32>,
+    #[sqlx(try_from = "NullableInt")]
+    pub rtt_max: Option<u32>,
+    /// Percentage of ping packets lost
+    pub packet_loss: Option<f64>,
+    pub ping_method: Option<PingMethod>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -35,129 +72,182 @@ pub struct LogForCreate {
     pub message: Option<String>,
     pub time: Option<DateTime<Utc>>,
     pub duration: u32,
+    pub cause_id: Option<u32>,
+    pub rtt_min: Option<u32>,
+    pub rtt_avg: Option<u32>,
+    pub rtt_max: Option<u32>,
+    pub packet_loss: Option<f64>,
+    pub ping_method: Option<PingMethod>,
 }
 
 #[derive(Debug, FromRow, Serialize)]
 pub struct Incident {
+    #[sqlx(try_from = "i64")]
     service_id: u32,
     service_name: String,
     service_url: String,
     status: Status,
     date: NaiveDate,
+    #[sqlx(try_from = "i64")]
     count: u32,
     messages: String,
     start: DateTime<Utc>,
     end: DateTime<Utc>,
+    /// Dependent services that were unreachable because of this incident
+    dependents: Option<String>,
 }
 
 impl Log {
-    pub async fn insert(pool: &SqlitePool, log: LogForCreate) -> sqlx::Result<u64> {
-        // Construct the base query
-        let mut query = "INSERT INTO Logs (service_id, status, duration".to_string();
-        if log.message.is_some() {
-            query.push_str(", message");
-        }
-        if log.time.is_some() {
-            query.push_str(", time");
-        }
-        query.push_str(") VALUES (?, ?, ?");
-        if log.message.is_some() {
-            query.push_str(", ?");
-        }
-        if log.time.is_some() {
-            query.push_str(", ?");
-        }
-        query.push(')');
-
-        // Create a query builder and bind parameters
-        let mut query_builder = sqlx::query(&query)
-            .bind(log.service_id)
-            .bind(log.status)
-            .bind(log.duration);
-
-        if let Some(message) = log.message {
-            query_builder = query_builder.bind(message);
-        }
-        if let Some(time) = log.time {
-            query_builder = query_builder.bind(time);
-        }
+    pub async fn insert(pool: &DbPool, log: LogForCreate) -> sqlx::Result<u64> {
+        let result = sqlx::query(
+            r#"INSERT INTO Logs (
+                service_id, status, duration, message, time, cause_id, rtt_min, rtt_avg, rtt_max,
+                packet_loss, ping_method
+            )
+            VALUES ($1, $2, $3, $4, COALESCE($5, CURRENT_TIMESTAMP), $6, $7, $8, $9, $10, $11)"#,
+        )
+        .bind(i64::from(log.service_id))
+        .bind(log.status)
+        .bind(i64::from(log.duration))
+        .bind(log.message)
+        .bind(log.time)
+        .bind(log.cause_id.map(i64::from))
+        .bind(log.rtt_min.map(i64::from))
+        .bind(log.rtt_avg.map(i64::from))
+        .bind(log.rtt_max.map(i64::from))
+        .bind(log.packet_loss)
+        .bind(log.ping_method)
+        .execute(pool)
+        .await?;
 
-        // Execute the query
-        let result = query_builder.execute(pool).await?;
         Ok(result.rows_affected())
     }
 
-    pub async fn incidents(pool: &SqlitePool, limit: Option<u32>) -> sqlx::Result<Vec<Incident>> {
-        let incidents = sqlx::query_as::<_, Incident>(
+    /// Incidents grouped per service and day, limited to services visible to `visible_to`
+    pub async fn incidents(
+        pool: &DbPool,
+        limit: Option<u32>,
+        visible_to: Option<u32>,
+    ) -> sqlx::Result<Vec<Incident>> {
+        let mut query = QueryBuilder::new(
             r#"SELECT
-                s.name AS service_name,
-                s.url AS service_url,
-                l.service_id,
-                l.status,
-                DATE(l.time) AS date,
-                COUNT(*) AS count,
-                GROUP_CONCAT(l.message, '; ') AS messages,
-                MIN(l.time) AS start,
-                MAX(l.time) AS end
-            FROM Logs l
-            JOIN Services s ON l.service_id = s.id
-            WHERE l.status > 1
-            GROUP BY l.service_id, l.status, date
+                i.*,
+                (
+                    SELECT string_agg(name, ',')
+                    FROM (
+                        SELECT DISTINCT ds.name
+                        FROM Logs dl
+                        JOIN Services ds ON dl.service_id = ds.id
+                        WHERE dl.cause_id = i.service_id
+                          AND dl.status = 4
+                          AND DATE(dl.time) = i.date
+                    ) AS d
+                ) AS dependents
+            FROM (
+                SELECT
+                    s.name AS service_name,
+                    s.url AS service_url,
+                    l.service_id,
+                    l.status,
+                    DATE(l.time) AS date,
+                    COUNT(*) AS count,
+                    string_agg(l.message, '; ') AS messages,
+                    MIN(l.time) AS start,
+                    MAX(l.time) AS "end"
+                FROM Logs l
+                JOIN Services s ON l.service_id = s.id
+                -- unreachable logs are attributed to the parent's incident
+                WHERE l.status IN (2, 3)"#,
+        );
+        if let Some(user_id) = visible_to {
+            query.push(" AND l.service_id IN (");
+            push_visible_services(&mut query, user_id);
+            query.push(")");
+        }
+        query
+            .push(
+                r#"
+                GROUP BY l.service_id, s.name, s.url, l.status, DATE(l.time)
+            ) AS i
             ORDER BY date DESC
-            LIMIT ?;"#,
-        )
-        .bind(limit.unwrap_or(20))
-        .fetch_all(pool)
-        .await?
-        .iter()
-        .map(|i| {
-            let messages = i
-                .messages
-                .split("; ")
-                .unique()
-                .collect::<Vec<&str>>()
-                .join("; ");
-            Incident {
-                messages,
-                service_id: i.service_id,
-                service_name: i.service_name.clone(),
-                service_url: i.service_url.clone(),
-                status: i.status,
-                date: i.date,
-                count: i.count,
-                start: i.start,
-                end: i.end,
-            }
-        })
-        .collect();
+            LIMIT "#,
+            )
+            .push_bind(i64::from(limit.unwrap_or(20)));
+        let incidents = query
+            .build_query_as::<Incident>()
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|i| {
+                let messages = i
+                    .messages
+                    .split("; ")
+                    .unique()
+                    .collect::<Vec<&str>>()
+                    .join("; ");
+                Incident {
+                    messages,
+                    service_id: i.service_id,
+                    service_name: i.service_name.clone(),
+                    service_url: i.service_url.clone(),
+                    status: i.status,
+                    date: i.date,
+                    count: i.count,
+                    start: i.start,
+                    end: i.end,
+                    dependents: i.dependents.clone(),
+                }
+            })
+            .collect();
 
         Ok(incidents)
     }
 
-    pub async fn list_all(pool: &SqlitePool, limit: Option<u32>) -> sqlx::Result<Vec<Log>> {
-        let logs = sqlx::query_as::<_, Log>(r#"SELECT * FROM Logs ORDER BY id DESC LIMIT ?"#)
-            .bind(limit.unwrap_or(100))
-            .fetch_all(pool)
-            .await?;
+    /// Latest logs of all services, limited to services visible to `visible_to`
+    pub async fn list_all(
+        pool: &DbPool,
+        limit: Option<u32>,
+        visible_to: Option<u32>,
+    ) -> sqlx::Result<Vec<Log>> {
+        let mut query = QueryBuilder::new("SELECT * FROM Logs");
+        if let Some(user_id) = visible_to {
+            query.push(" WHERE service_id IN (");
+            push_visible_services(&mut query, user_id);
+            query.push(")");
+        }
+        query
+            .push(" ORDER BY id DESC LIMIT ")
+            .push_bind(i64::from(limit.unwrap_or(100)));
+        let logs = query.build_query_as().fetch_all(pool).await?;
 
         Ok(logs)
     }
 
     pub async fn list(
-        pool: &SqlitePool,
+        pool: &DbPool,
         service_id: u32,
         limit: Option<u32>,
     ) -> sqlx::Result<Vec<Log>> {
         let logs = sqlx::query_as::<_, Log>(
-            r#"SELECT * FROM Logs WHERE service_id = ? ORDER BY id DESC LIMIT ?"#,
+            r#"SELECT * FROM Logs WHERE service_id = $1 ORDER BY id DESC LIMIT $2"#,
         )
-        .bind(service_id)
-        .bind(limit.unwrap_or(100))
+        .bind(i64::from(service_id))
+        .bind(i64::from(limit.unwrap_or(100)))
         .fetch_all(pool)
         .await?;
 
         Ok(logs)
     }
+
+    /// Delete logs older than `before`, returning how many were deleted
+    pub async fn delete_before(pool: &DbPool, before: DateTime<Utc>) -> sqlx::Result<u64> {
+        let result = sqlx::query(DELETE_BEFORE)
+            .bind(before)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
 }
 
 impl std::fmt::Display for LogForCreate {
@@ -168,12 +258,22 @@ impl std::fmt::Display for LogForCreate {
 
 #[cfg(test)]
 mod tests {
-    use sqlx::SqlitePool;
-
     use super::*;
 
-    #[sqlx::test(fixtures("users", "services"))]
-    async fn insert_log(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn delete_old_logs(pool: DbPool) -> sqlx::Result<()> {
+        let before = "2024-07-27T10:12:00Z".parse().unwrap();
+        assert_eq!(Log::delete_before(&pool, before).await?, 3);
+        assert_eq!(Log::list_all(&pool, None, None).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn insert_log(pool: DbPool) -> sqlx::Result<()> {
         let count = Log::insert(
             &pool,
             LogForCreate {
@@ -182,6 +282,8 @@ mod tests {
                 message: Some("message".to_string()),
                 time: Some(Utc::now()),
                 duration: 10,
+                cause_id: None,
+                ..Default::default()
             },
         )
         .await?;
@@ -191,9 +293,38 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_logs(pool: SqlitePool) -> sqlx::Result<()> {
-        let logs = Log::list_all(&pool, None).await?;
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn insert_ping_log(pool: DbPool) -> sqlx::Result<()> {
+        Log::insert(
+            &pool,
+            LogForCreate {
+                service_id: 1,
+                status: Status::Up,
+                duration: 12,
+                rtt_min: Some(9),
+                rtt_avg: Some(12),
+                rtt_max: Some(15),
+                packet_loss: Some(25.0),
+                ping_method: Some(PingMethod::Tcp),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let log = Log::list(&pool, 1, None).await?.remove(0);
+        assert_eq!((log.rtt_min, log.rtt_max), (Some(9), Some(15)));
+        assert_eq!(log.packet_loss, Some(25.0));
+        assert_eq!(log.ping_method, Some(PingMethod::Tcp));
+
+        Ok(())
+    }
+
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_logs(pool: DbPool) -> sqlx::Result<()> {
+        let logs = Log::list_all(&pool, None, None).await?;
 
         dbg!(&logs);
 
@@ -202,9 +333,12 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_logs_limit(pool: SqlitePool) -> sqlx::Result<()> {
-        let logs = Log::list_all(&pool, Some(2)).await?;
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_logs_limit(pool: DbPool) -> sqlx::Result<()> {
+        let logs = Log::list_all(&pool, Some(2), None).await?;
 
         dbg!(&logs);
 
@@ -213,9 +347,12 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_logs_order(pool: SqlitePool) -> sqlx::Result<()> {
-        let logs = Log::list_all(&pool, Some(2)).await?;
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_logs_order(pool: DbPool) -> sqlx::Result<()> {
+        let logs = Log::list_all(&pool, Some(2), None).await?;
 
         dbg!(&logs);
 
@@ -226,8 +363,11 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_service_logs(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_service_logs(pool: DbPool) -> sqlx::Result<()> {
         let logs = Log::list(&pool, 2, Some(2)).await?;
 
         dbg!(&logs);
@@ -239,9 +379,12 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_incidents(pool: SqlitePool) -> sqlx::Result<()> {
-        let incidents = Log::incidents(&pool, None).await?;
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_incidents(pool: DbPool) -> sqlx::Result<()> {
+        let incidents = Log::incidents(&pool, None, None).await?;
 
         dbg!(&incidents);
 
@@ -250,8 +393,8 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services"))]
-    async fn insert_log_with_message(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn insert_log_with_message(pool: DbPool) -> sqlx::Result<()> {
         let count = Log::insert(
             &pool,
             LogForCreate {
@@ -260,6 +403,8 @@ mod tests {
                 message: Some("Service is healthy".to_string()),
                 time: None, // Should use current time
                 duration: 150,
+                cause_id: None,
+                ..Default::default()
             },
         )
         .await?;
@@ -277,8 +422,8 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services"))]
-    async fn insert_log_without_message(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn insert_log_without_message(pool: DbPool) -> sqlx::Result<()> {
         let count = Log::insert(
             &pool,
             LogForCreate {
@@ -287,6 +432,8 @@ mod tests {
                 message: None,
                 time: Some(Utc::now()),
                 duration: 0,
+                cause_id: None,
+                ..Default::default()
             },
         )
         .await?;
@@ -304,8 +451,8 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services"))]
-    async fn test_status_enum_values(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn test_status_enum_values(pool: DbPool) -> sqlx::Result<()> {
         // Test all status variants
         let statuses = [Status::Pending, Status::Up, Status::Down, Status::Failed];
 
@@ -318,6 +465,8 @@ mod tests {
                     message: Some(format!("Test status {}", i)),
                     time: None,
                     duration: i as u32 * 10,
+                    cause_id: None,
+                    ..Default::default()
                 },
             )
             .await?;
@@ -332,8 +481,11 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_logs_with_limit(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_logs_with_limit(pool: DbPool) -> sqlx::Result<()> {
         let logs = Log::list(&pool, 2, Some(1)).await?;
 
         // Should return only 1 log due to limit
@@ -342,8 +494,11 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_logs_no_limit(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_logs_no_limit(pool: DbPool) -> sqlx::Result<()> {
         let logs = Log::list(&pool, 2, None).await?;
 
         // Should return all logs for service 2 (3 logs in fixtures)
@@ -352,9 +507,12 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_all_logs(pool: SqlitePool) -> sqlx::Result<()> {
-        let logs = Log::list_all(&pool, None).await?;
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_all_logs(pool: DbPool) -> sqlx::Result<()> {
+        let logs = Log::list_all(&pool, None, None).await?;
 
         // Should return all logs in database (5 logs in fixtures)
         assert_eq!(logs.len(), 5);
@@ -362,9 +520,12 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users", "services", "logs"))]
-    async fn list_incidents_with_limit(pool: SqlitePool) -> sqlx::Result<()> {
-        let incidents = Log::incidents(&pool, Some(1)).await?;
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_incidents_with_limit(pool: DbPool) -> sqlx::Result<()> {
+        let incidents = Log::incidents(&pool, Some(1), None).await?;
 
         // Should respect the limit
         assert!(incidents.len() <= 1);
@@ -372,12 +533,59 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn list_logs_empty_database(pool: SqlitePool) -> sqlx::Result<()> {
-        let logs = Log::list_all(&pool, None).await?;
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn incidents_attributed_to_parent(pool: DbPool) -> sqlx::Result<()> {
+        Log::insert(
+            &pool,
+            LogForCreate {
+                service_id: 1,
+                status: Status::Unreachable,
+                message: Some("Service Two is down".to_string()),
+                time: Some("2024-07-27T10:06:00Z".parse().unwrap()),
+                duration: 0,
+                cause_id: Some(2),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let incidents = Log::incidents(&pool, None, None).await?;
+
+        // the unreachable log does not create an incident of its own
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].service_id, 2);
+        assert_eq!(incidents[0].dependents.as_deref(), Some("Service One"));
+
+        Ok(())
+    }
+
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures("users", "services", "logs")
+    )]
+    async fn list_logs_visible_to_user(pool: DbPool) -> sqlx::Result<()> {
+        // user 1 owns service 1, service 2 belongs to user 2
+        let logs = Log::list_all(&pool, None, Some(1)).await?;
+        assert_eq!(logs.len(), 2);
+        assert!(logs.iter().all(|l| l.service_id == 1));
+
+        let incidents = Log::incidents(&pool, None, Some(1)).await?;
+        assert!(incidents.is_empty());
+        let incidents = Log::incidents(&pool, None, Some(2)).await?;
+        assert_eq!(incidents.len(), 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn list_logs_empty_database(pool: DbPool) -> sqlx::Result<()> {
+        let logs = Log::list_all(&pool, None, None).await?;
         assert_eq!(logs.len(), 0);
 
-        let incidents = Log::incidents(&pool, None).await?;
+        let incidents = Log::incidents(&pool, None, None).await?;
         assert_eq!(incidents.len(), 0);
 
         Ok(())
@@ -1,53 +1,122 @@
-use sqlx::SqlitePool;
+use sqlx::{Database, Decode, Pool, QueryBuilder, Type, error::BoxDynError, migrate::Migrator};
 use tracing::info;
 
 pub use self::user::{UserForLogin, UserForRegister};
 
+pub mod api_token;
+pub mod audit;
+pub mod auth_event;
+#[allow(dead_code)]
 pub mod config;
+pub mod group;
+pub mod invitation;
 pub mod log;
+#[allow(dead_code)]
 pub mod notification;
 pub mod service;
+pub mod session;
+pub mod settings;
+pub mod snapshot;
+pub mod tag;
+pub mod team;
+pub mod two_factor;
 pub mod user;
 
-pub async fn setup(pool: &SqlitePool) -> Result<(), sqlx::Error> {
-    sqlx::query("PRAGMA journal_mode = 'WAL';")
-        .execute(pool)
-        .await?;
-    sqlx::query("PRAGMA temp_store = 2;").execute(pool).await?;
-    sqlx::query("PRAGMA synchronous = NORMAL;")
-        .execute(pool)
-        .await?;
-    sqlx::query("PRAGMA cache_size = 64000;")
-        .execute(pool)
-        .await?;
+/// Database the data is stored in, PostgreSQL when built with the `postgres` feature
+#[cfg(not(feature = "postgres"))]
+pub type Db = sqlx::Sqlite;
+#[cfg(feature = "postgres")]
+pub type Db = sqlx::Postgres;
+
+pub type DbPool = Pool<Db>;
+pub type DbConnection = <Db as Database>::Connection;
+
+/// Queue of the background jobs, stored in the same database
+#[cfg(not(feature = "postgres"))]
+pub type JobStorage<T> = apalis_sql::sqlite::SqliteStorage<T>;
+#[cfg(feature = "postgres")]
+pub type JobStorage<T> = apalis_sql::postgres::PostgresStorage<T>;
+
+/// Migrations of the database in use, including the tables of the job queue
+#[cfg(not(feature = "postgres"))]
+pub static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+#[cfg(feature = "postgres")]
+pub static MIGRATOR: Migrator = sqlx::migrate!("migrations/postgres");
+
+pub async fn setup(pool: &DbPool) -> Result<(), sqlx::Error> {
+    #[cfg(not(feature = "postgres"))]
+    {
+        sqlx::query("PRAGMA journal_mode = 'WAL';")
+            .execute(pool)
+            .await?;
+        sqlx::query("PRAGMA temp_store = 2;").execute(pool).await?;
+        sqlx::query("PRAGMA synchronous = NORMAL;")
+            .execute(pool)
+            .await?;
+        sqlx::query("PRAGMA cache_size = 64000;")
+            .execute(pool)
+            .await?;
+    }
     info!("Running database migrations");
-    sqlx::migrate!().run(pool).await?;
+    MIGRATOR.run(pool).await?;
     Ok(())
 }
 
-#[macro_export]
-macro_rules! build_query_bind {
-    ($query_builder:ident, $update_data:ident, {
-        $($field:ident),*
-    }) => {
-        $(
-            if let Some(value) = $update_data.$field {
-                $query_builder = $query_builder.bind(value);
-            }
-        )*
-    };
+/// Push ` IN (...)` with a bind for each id
+pub fn push_in(query: &mut QueryBuilder<'_, Db>, ids: &[u32]) {
+    query.push(" IN (");
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(i64::from(*id));
+    }
+    query.push(")");
 }
 
+/// Nullable integer column, Postgres has no unsigned types so `Option<u32>` fields are
+/// decoded with `#[sqlx(try_from = "NullableInt")]`
+pub struct NullableInt(Option<i64>);
+
+impl Type<Db> for NullableInt {
+    fn type_info() -> <Db as Database>::TypeInfo {
+        <i64 as Type<Db>>::type_info()
+    }
+
+    fn compatible(ty: &<Db as Database>::TypeInfo) -> bool {
+        <i64 as Type<Db>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, Db> for NullableInt {
+    fn decode(value: <Db as Database>::ValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Self(Decode::<Db>::decode(value)?))
+    }
+}
+
+impl<T: TryFrom<i64>> TryFrom<NullableInt> for Option<T> {
+    type Error = T::Error;
+
+    fn try_from(value: NullableInt) -> Result<Self, Self::Error> {
+        value.0.map(T::try_from).transpose()
+    }
+}
+
+/// Push `field = value` for each field of `$update_data` that is set, integer fields are
+/// converted with `as` to a type the database can store. Evaluates to whether any was set.
 #[macro_export]
 macro_rules! build_update_query {
-    ($query:ident, $has_updates:ident, $update_data:ident, {
-        $($field:ident),*
-    }) => {
+    ($query:ident, $update_data:ident, {
+        $($field:ident $(as $ty:ty)?),*
+    }) => {{
+        let mut fields = $query.separated(", ");
+        let mut has_updates = false;
         $(
-            if $update_data.$field.is_some() {
-                $query.push_str(concat!(stringify!($field), " = ?, "));
-                $has_updates = true
+            if let Some(value) = $update_data.$field {
+                $(let value = <$ty>::from(value);)?
+                fields.push(concat!(stringify!($field), " = "));
+                fields.push_bind_unseparated(value);
+                has_updates = true;
             }
         )*
-    };
+        has_updates
+    }};
 }
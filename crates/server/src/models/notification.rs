@@ -5,7 +5,9 @@ use sqlx::prelude::FromRow;
 #[derive(Debug, FromRow)]
 pub struct Notification {
     pub id: u64,
+    #[sqlx(try_from = "i64")]
     pub entity_id: u32,
+    #[sqlx(try_from = "i64")]
     pub status_log_id: u32,
     pub message: Option<String>,
     pub sent_at: DateTime<Utc>,
@@ -13,7 +15,9 @@ pub struct Notification {
 
 #[derive(Debug, Clone, FromRow, Serialize)]
 pub struct NotificationForCreate {
+    #[sqlx(try_from = "i64")]
     pub entity_id: u32,
+    #[sqlx(try_from = "i64")]
     pub status_log_id: u32,
     pub message: Option<String>,
     pub sent_at: DateTime<Utc>,
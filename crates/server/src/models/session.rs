@@ -0,0 +1,240 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+
+use super::{DbPool, user::UserRole};
+use crate::utils::{hash_token, random_token};
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct Session {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    #[sqlx(try_from = "i64")]
+    pub user_id: u32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+pub struct SessionForCreate {
+    pub user_id: u32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Session {
+    /// Start a session, returning its id and the refresh token for it.
+    ///
+    /// Only a hash of the refresh token is stored.
+    pub async fn create(pool: &DbPool, session: SessionForCreate) -> sqlx::Result<(u32, String)> {
+        let token = random_token(48);
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            r#"INSERT INTO Sessions (user_id, refresh_hash, user_agent, ip, expires_at)
+               VALUES ($1, $2, $3, $4, $5)
+               RETURNING id"#,
+        )
+        .bind(i64::from(session.user_id))
+        .bind(hash_token(&token))
+        .bind(session.user_agent)
+        .bind(session.ip)
+        .bind(session.expires_at)
+        .fetch_one(pool)
+        .await?;
+
+        Ok((id as u32, token))
+    }
+
+    /// Exchange a refresh token for a new one, extending the session.
+    ///
+    /// Returns `None` if the token is unknown, revoked or expired. Presenting a
+    /// token that was already rotated means it leaked, so the session it belonged
+    /// to is revoked.
+    pub async fn rotate(
+        pool: &DbPool,
+        token: &str,
+        expires_at: DateTime<Utc>,
+    ) -> sqlx::Result<Option<(Session, String)>> {
+        let hash = hash_token(token);
+        let now = Utc::now();
+        let new_token = random_token(48);
+
+        let session = sqlx::query_as::<_, Session>(
+            r#"UPDATE Sessions
+               SET refresh_hash = $1, previous_hash = refresh_hash, last_used_at = $2, expires_at = $3
+               WHERE refresh_hash = $4 AND revoked_at IS NULL AND expires_at > $5
+               RETURNING *"#,
+        )
+        .bind(hash_token(&new_token))
+        .bind(now)
+        .bind(expires_at)
+        .bind(&hash)
+        .bind(now)
+        .fetch_optional(pool)
+        .await?;
+
+        if session.is_none() {
+            sqlx::query(
+                "UPDATE Sessions SET revoked_at = $1 WHERE previous_hash = $2 AND revoked_at IS NULL",
+            )
+            .bind(now)
+            .bind(&hash)
+            .execute(pool)
+            .await?;
+        }
+
+        Ok(session.map(|s| (s, new_token)))
+    }
+
+    /// The current role of the session's user, `None` if the session was revoked,
+    /// has expired or the user was disabled.
+    pub async fn active_role(
+        pool: &DbPool,
+        session_id: u32,
+        user_id: u32,
+    ) -> sqlx::Result<Option<UserRole>> {
+        let role = sqlx::query_as::<_, (UserRole,)>(
+            r#"SELECT u.role
+               FROM Sessions s
+               JOIN Users u ON u.id = s.user_id
+               WHERE s.id = $1 AND s.user_id = $2 AND s.revoked_at IS NULL AND s.expires_at > $3
+                 AND u.active = true"#,
+        )
+        .bind(i64::from(session_id))
+        .bind(i64::from(user_id))
+        .bind(Utc::now())
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(role.map(|(role,)| role))
+    }
+
+    /// Sessions of a user that can still be refreshed
+    pub async fn list_active(pool: &DbPool, user_id: u32) -> sqlx::Result<Vec<Session>> {
+        sqlx::query_as(
+            r#"SELECT *
+               FROM Sessions
+               WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > $2
+               ORDER BY last_used_at DESC"#,
+        )
+        .bind(i64::from(user_id))
+        .bind(Utc::now())
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn revoke(pool: &DbPool, session_id: u32, user_id: u32) -> sqlx::Result<u64> {
+        let result = sqlx::query(
+            "UPDATE Sessions SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL",
+        )
+        .bind(Utc::now())
+        .bind(i64::from(session_id))
+        .bind(i64::from(user_id))
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn revoke_by_token(pool: &DbPool, token: &str) -> sqlx::Result<u64> {
+        let result = sqlx::query(
+            "UPDATE Sessions SET revoked_at = $1 WHERE refresh_hash = $2 AND revoked_at IS NULL",
+        )
+        .bind(Utc::now())
+        .bind(hash_token(token))
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Revoke every session of a user, optionally keeping the current one
+    pub async fn revoke_all(pool: &DbPool, user_id: u32, except: Option<u32>) -> sqlx::Result<u64> {
+        let result = sqlx::query(
+            r#"UPDATE Sessions SET revoked_at = $1
+               WHERE user_id = $2 AND revoked_at IS NULL AND ($3 IS NULL OR id != $3)"#,
+        )
+        .bind(Utc::now())
+        .bind(i64::from(user_id))
+        .bind(except.map(i64::from))
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn new_session(user_id: u32) -> SessionForCreate {
+        SessionForCreate {
+            user_id,
+            expires_at: Utc::now() + TimeDelta::days(1),
+            ..Default::default()
+        }
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn rotate_refresh_token(pool: DbPool) -> sqlx::Result<()> {
+        let (id, token) = Session::create(&pool, new_session(1)).await?;
+        assert_eq!(
+            Session::active_role(&pool, id, 1).await?,
+            Some(UserRole::Admin)
+        );
+
+        let expires_at = Utc::now() + TimeDelta::days(2);
+        let (session, new_token) = Session::rotate(&pool, &token, expires_at)
+            .await?
+            .expect("token should be valid");
+        assert_eq!(session.id, id);
+        assert_ne!(new_token, token);
+
+        // reusing the rotated token revokes the whole session
+        assert!(Session::rotate(&pool, &token, expires_at).await?.is_none());
+        assert!(Session::active_role(&pool, id, 1).await?.is_none());
+        assert!(
+            Session::rotate(&pool, &new_token, expires_at)
+                .await?
+                .is_none()
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn revoke_sessions(pool: DbPool) -> sqlx::Result<()> {
+        let (first, _) = Session::create(&pool, new_session(1)).await?;
+        let (second, token) = Session::create(&pool, new_session(1)).await?;
+        let (other, _) = Session::create(&pool, new_session(3)).await?;
+        assert_eq!(Session::list_active(&pool, 1).await?.len(), 2);
+
+        // a user can only revoke their own sessions
+        assert_eq!(Session::revoke(&pool, other, 1).await?, 0);
+
+        assert_eq!(Session::revoke_all(&pool, 1, Some(second)).await?, 1);
+        assert!(Session::active_role(&pool, first, 1).await?.is_none());
+        assert!(Session::active_role(&pool, second, 1).await?.is_some());
+
+        assert_eq!(Session::revoke_by_token(&pool, &token).await?, 1);
+        assert!(Session::list_active(&pool, 1).await?.is_empty());
+        assert!(Session::active_role(&pool, other, 3).await?.is_some());
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn disabled_user_session(pool: DbPool) -> sqlx::Result<()> {
+        // user2 is inactive
+        let (id, _) = Session::create(&pool, new_session(2)).await?;
+        assert!(Session::active_role(&pool, id, 2).await?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tracing::warn;
+
+use super::{DbPool, config::Config};
+
+/// Category of the `Configs` rows holding the settings
+const CATEGORY: &str = "settings";
+
+/// Settings editable at runtime by admins
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub site_title: String,
+    /// Timezone of push schedules that don't set their own
+    pub default_timezone: Tz,
+    /// Days logs are kept for, 0 keeps them forever
+    pub retention_days: u32,
+    /// Check interval in seconds of services created without one
+    pub default_interval: u16,
+    /// Address the site is reachable at, used for links in notifications
+    pub public_url: Option<String>,
+    pub smtp: SmtpSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmtpSettings {
+    pub host: Option<String>,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address of notification emails
+    pub from: Option<String>,
+    pub tls: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            site_title: "Stamon".into(),
+            default_timezone: Tz::UTC,
+            retention_days: 0,
+            default_interval: 60,
+            public_url: None,
+            smtp: SmtpSettings::default(),
+        }
+    }
+}
+
+impl Default for SmtpSettings {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 587,
+            username: None,
+            password: None,
+            from: None,
+            tls: true,
+        }
+    }
+}
+
+/// Apply a JSON merge patch (RFC 7396), `null` values remove keys
+fn merge(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!("target is an object");
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+impl Settings {
+    /// Read the settings, missing or invalid ones get their default value
+    pub async fn load(pool: &DbPool) -> sqlx::Result<Settings> {
+        let mut settings = serde_json::to_value(Settings::default()).unwrap_or_default();
+        for config in Config::list_by_category(pool, CATEGORY).await? {
+            let mut patched = settings.clone();
+            let valid = serde_json::from_str::<Value>(&config.value).is_ok_and(|value| {
+                merge(&mut patched, json!({ &config.name: value }));
+                serde_json::from_value::<Settings>(patched.clone()).is_ok()
+            });
+            if valid {
+                settings = patched;
+            } else {
+                warn!("Ignoring invalid setting {}: {}", config.name, config.value);
+            }
+        }
+
+        Ok(serde_json::from_value(settings).unwrap_or_default())
+    }
+
+    /// Store every setting, each one is a row of the `Configs` table
+    pub async fn save(&self, pool: &DbPool) -> sqlx::Result<()> {
+        let Ok(Value::Object(values)) = serde_json::to_value(self) else {
+            unreachable!("settings serialize to an object");
+        };
+
+        let mut tx = pool.begin().await?;
+        for (name, value) in values {
+            Config::set(&mut *tx, &name, &value.to_string(), Some(CATEGORY)).await?;
+        }
+        tx.commit().await
+    }
+
+    /// Settings with `patch` merged in, fields left out keep their value
+    pub fn patched(&self, patch: Value) -> Result<Settings, String> {
+        let mut value = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        merge(&mut value, patch);
+        let settings: Settings = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.site_title.trim().is_empty() {
+            return Err("Site title can't be empty".into());
+        }
+        if self.default_interval == 0 {
+            return Err("Default interval must be at least 1 second".into());
+        }
+        if let Some(url) = &self.public_url
+            && !(url.starts_with("http://") || url.starts_with("https://"))
+        {
+            return Err("Public url must start with http:// or https://".into());
+        }
+        if self.smtp.port == 0 {
+            return Err("SMTP port can't be 0".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_settings() {
+        let settings = Settings::default();
+
+        let patched = settings
+            .patched(json!({ "site_title": "Status", "smtp": { "host": "mail.example.com" } }))
+            .unwrap();
+        assert_eq!(patched.site_title, "Status");
+        assert_eq!(patched.smtp.host.as_deref(), Some("mail.example.com"));
+        // fields left out are kept
+        assert_eq!(patched.smtp.port, 587);
+        assert_eq!(patched.retention_days, 0);
+
+        // null resets to the default
+        let patched = patched
+            .patched(json!({ "smtp": { "host": null } }))
+            .unwrap();
+        assert_eq!(patched.smtp.host, None);
+
+        assert!(
+            settings
+                .patched(json!({ "default_timezone": "Mars/Olympus" }))
+                .is_err()
+        );
+        assert!(settings.patched(json!({ "default_interval": 0 })).is_err());
+        assert!(
+            settings
+                .patched(json!({ "public_url": "status.example.com" }))
+                .is_err()
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn save_and_load(pool: DbPool) -> sqlx::Result<()> {
+        assert_eq!(Settings::load(&pool).await?, Settings::default());
+
+        let settings = Settings::default()
+            .patched(json!({
+                "default_timezone": "Europe/Paris",
+                "retention_days": 7,
+                "smtp": { "host": "mail.example.com", "password": "hunter2" },
+            }))
+            .unwrap();
+        settings.save(&pool).await?;
+        assert_eq!(Settings::load(&pool).await?, settings);
+
+        // a broken row only loses its own value
+        Config::set(&pool, "retention_days", "\"forever\"", Some(CATEGORY)).await?;
+        let loaded = Settings::load(&pool).await?;
+        assert_eq!(loaded.retention_days, 0);
+        assert_eq!(loaded.default_timezone, Tz::Europe__Paris);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use sqlx::FromRow;
+
+use super::DbPool;
+
+/// Content of a content change monitor at one check
+#[derive(Debug, FromRow, Serialize)]
+pub struct ContentSnapshot {
+    pub id: i64,
+    #[sqlx(try_from = "i64")]
+    pub service_id: u32,
+    /// SHA-256 of the content, compared between checks
+    pub hash: String,
+    pub content: String,
+    pub time: DateTime<Utc>,
+}
+
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+impl ContentSnapshot {
+    /// The newest snapshot of a service, the one new content is compared to
+    pub async fn baseline(pool: &DbPool, service_id: u32) -> sqlx::Result<Option<ContentSnapshot>> {
+        sqlx::query_as(
+            "SELECT * FROM ContentSnapshots WHERE service_id = $1 ORDER BY id DESC LIMIT 1",
+        )
+        .bind(i64::from(service_id))
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Save new content as the baseline, keeping only the last `keep` snapshots
+    pub async fn insert(
+        pool: &DbPool,
+        service_id: u32,
+        content: &str,
+        keep: u32,
+    ) -> sqlx::Result<ContentSnapshot> {
+        let mut tx = pool.begin().await?;
+        let snapshot = sqlx::query_as::<_, ContentSnapshot>(
+            r#"INSERT INTO ContentSnapshots (service_id, hash, content, time)
+               VALUES ($1, $2, $3, $4)
+               RETURNING *"#,
+        )
+        .bind(i64::from(service_id))
+        .bind(content_hash(content))
+        .bind(content)
+        .bind(Utc::now())
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"DELETE FROM ContentSnapshots
+               WHERE service_id = $1 AND id NOT IN (
+                   SELECT id FROM ContentSnapshots WHERE service_id = $2 ORDER BY id DESC LIMIT $3
+               )"#,
+        )
+        .bind(i64::from(service_id))
+        .bind(i64::from(service_id))
+        .bind(i64::from(keep.max(1)))
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(snapshot)
+    }
+
+    /// Snapshots of a service, newest first
+    pub async fn list(pool: &DbPool, service_id: u32) -> sqlx::Result<Vec<ContentSnapshot>> {
+        sqlx::query_as("SELECT * FROM ContentSnapshots WHERE service_id = $1 ORDER BY id DESC")
+            .bind(i64::from(service_id))
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn get(
+        pool: &DbPool,
+        service_id: u32,
+        id: i64,
+    ) -> sqlx::Result<Option<ContentSnapshot>> {
+        sqlx::query_as("SELECT * FROM ContentSnapshots WHERE service_id = $1 AND id = $2")
+            .bind(i64::from(service_id))
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Unified diff from this snapshot to a newer one
+    pub fn diff(&self, newer: &ContentSnapshot) -> String {
+        TextDiff::from_lines(&self.content, &newer.content)
+            .unified_diff()
+            .header(&self.time.to_rfc3339(), &newer.time.to_rfc3339())
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn keep_last_snapshots(pool: DbPool) -> sqlx::Result<()> {
+        assert!(ContentSnapshot::baseline(&pool, 1).await?.is_none());
+
+        for content in ["one", "two", "three"] {
+            ContentSnapshot::insert(&pool, 1, content, 2).await?;
+        }
+        let snapshots = ContentSnapshot::list(&pool, 1).await?;
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].content, "three");
+        assert_eq!(snapshots[0].hash, content_hash("three"));
+
+        let baseline = ContentSnapshot::baseline(&pool, 1).await?.unwrap();
+        assert_eq!(baseline.id, snapshots[0].id);
+        // snapshots belong to their service
+        assert!(ContentSnapshot::get(&pool, 2, baseline.id).await?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unified_diff() {
+        let snapshot = |content: &str| ContentSnapshot {
+            id: 1,
+            service_id: 1,
+            hash: content_hash(content),
+            content: content.into(),
+            time: DateTime::UNIX_EPOCH,
+        };
+        let diff = snapshot("price: 10\nstock: 3\n").diff(&snapshot("price: 12\nstock: 3\n"));
+        assert!(diff.contains("-price: 10\n+price: 12\n"));
+    }
+}
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, QueryBuilder};
+
+use super::{DbPool, push_in, service::push_visible_services};
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Tag {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    pub name: String,
+    pub color: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TagForCreate {
+    pub name: String,
+    pub color: Option<String>,
+}
+
+/// A tag together with the service it is attached to
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct ServiceTag {
+    #[sqlx(try_from = "i64")]
+    pub service_id: u32,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub tag: Tag,
+}
+
+impl Tag {
+    pub async fn insert(pool: &DbPool, tag: TagForCreate) -> sqlx::Result<u64> {
+        let result = sqlx::query("INSERT INTO Tags (name, color) VALUES ($1, $2)")
+            .bind(tag.name)
+            .bind(tag.color)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// List the tags visible to `visible_to`, all of them when `None`: those on one of its
+    /// services and those not on any service yet
+    pub async fn list(pool: &DbPool, visible_to: Option<u32>) -> sqlx::Result<Vec<Tag>> {
+        let mut query = QueryBuilder::new("SELECT * FROM Tags");
+        if let Some(user_id) = visible_to {
+            query.push(
+                r#" WHERE id NOT IN (SELECT tag_id FROM ServiceTags)
+                OR id IN (SELECT tag_id FROM ServiceTags WHERE service_id IN ("#,
+            );
+            push_visible_services(&mut query, user_id);
+            query.push("))");
+        }
+        query.push(" ORDER BY name");
+        query.build_query_as().fetch_all(pool).await
+    }
+
+    pub async fn delete(pool: &DbPool, tag_id: u32) -> sqlx::Result<u64> {
+        let result = sqlx::query("DELETE FROM Tags WHERE id = $1")
+            .bind(i64::from(tag_id))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// List the tags of every service
+    pub async fn list_service_tags(pool: &DbPool) -> sqlx::Result<Vec<ServiceTag>> {
+        sqlx::query_as(
+            r#"SELECT st.service_id, t.id, t.name, t.color
+               FROM ServiceTags st
+               JOIN Tags t ON st.tag_id = t.id
+               ORDER BY t.name"#,
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn list_for_service(pool: &DbPool, service_id: u32) -> sqlx::Result<Vec<Tag>> {
+        sqlx::query_as(
+            r#"SELECT t.*
+               FROM ServiceTags st
+               JOIN Tags t ON st.tag_id = t.id
+               WHERE st.service_id = $1
+               ORDER BY t.name"#,
+        )
+        .bind(i64::from(service_id))
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Replace the tags of the given services with `tag_ids`
+    pub async fn set_for_services(
+        pool: &DbPool,
+        service_ids: &[u32],
+        tag_ids: &[u32],
+    ) -> sqlx::Result<u64> {
+        if service_ids.is_empty() {
+            return Ok(0);
+        }
+        let mut tx = pool.begin().await?;
+
+        let mut delete = QueryBuilder::new("DELETE FROM ServiceTags WHERE service_id");
+        push_in(&mut delete, service_ids);
+        delete.build().execute(&mut *tx).await?;
+
+        let mut count = 0;
+        for service_id in service_ids {
+            for tag_id in tag_ids {
+                count +=
+                    sqlx::query("INSERT INTO ServiceTags (service_id, tag_id) VALUES ($1, $2)")
+                        .bind(i64::from(*service_id))
+                        .bind(i64::from(*tag_id))
+                        .execute(&mut *tx)
+                        .await?
+                        .rows_affected();
+            }
+        }
+
+        tx.commit().await?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn tag_services(pool: DbPool) -> sqlx::Result<()> {
+        Tag::insert(
+            &pool,
+            TagForCreate {
+                name: "prod".into(),
+                color: Some("#ff0000".into()),
+            },
+        )
+        .await?;
+        Tag::insert(
+            &pool,
+            TagForCreate {
+                name: "db".into(),
+                color: None,
+            },
+        )
+        .await?;
+
+        let count = Tag::set_for_services(&pool, &[1, 2], &[1, 2]).await?;
+        assert_eq!(count, 4);
+
+        let tags = Tag::list_for_service(&pool, 1).await?;
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].name, "db");
+
+        // retagging replaces existing tags
+        Tag::set_for_services(&pool, &[1], &[1]).await?;
+        let tags = Tag::list_for_service(&pool, 1).await?;
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].color.as_deref(), Some("#ff0000"));
+
+        assert_eq!(Tag::list_service_tags(&pool).await?.len(), 3);
+
+        Tag::insert(
+            &pool,
+            TagForCreate {
+                name: "unused".into(),
+                color: None,
+            },
+        )
+        .await?;
+        let names = |tags: Vec<Tag>| tags.into_iter().map(|t| t.name).collect::<Vec<_>>();
+        assert_eq!(
+            names(Tag::list(&pool, None).await?),
+            ["db", "prod", "unused"]
+        );
+        // "db" is only on service 2, which user 1 can't see
+        assert_eq!(names(Tag::list(&pool, Some(1)).await?), ["prod", "unused"]);
+        assert_eq!(
+            names(Tag::list(&pool, Some(2)).await?),
+            ["db", "prod", "unused"]
+        );
+
+        // deleting a tag removes it from services
+        Tag::delete(&pool, 1).await?;
+        assert!(Tag::list_for_service(&pool, 1).await?.is_empty());
+
+        Ok(())
+    }
+}
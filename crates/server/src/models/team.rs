@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use super::{DbPool, user::User};
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Team {
+    #[sqlx(try_from = "i64")]
+    pub id: u32,
+    pub name: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TeamForCreate {
+    pub name: String,
+}
+
+impl Team {
+    pub async fn insert(pool: &DbPool, team: TeamForCreate) -> sqlx::Result<u64> {
+        let result = sqlx::query("INSERT INTO Teams (name) VALUES ($1)")
+            .bind(team.name)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn list(pool: &DbPool) -> sqlx::Result<Vec<Team>> {
+        sqlx::query_as("SELECT * FROM Teams ORDER BY name")
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn delete(pool: &DbPool, team_id: u32) -> sqlx::Result<u64> {
+        let result = sqlx::query("DELETE FROM Teams WHERE id = $1")
+            .bind(i64::from(team_id))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn members(pool: &DbPool, team_id: u32) -> sqlx::Result<Vec<User>> {
+        sqlx::query_as(
+            r#"SELECT u.*
+               FROM TeamMembers tm
+               JOIN Users u ON tm.user_id = u.id
+               WHERE tm.team_id = $1
+               ORDER BY u.username"#,
+        )
+        .bind(i64::from(team_id))
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Replace the members of a team
+    pub async fn set_members(pool: &DbPool, team_id: u32, user_ids: &[u32]) -> sqlx::Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("DELETE FROM TeamMembers WHERE team_id = $1")
+            .bind(i64::from(team_id))
+            .execute(&mut *tx)
+            .await?;
+
+        let mut count = 0;
+        for user_id in user_ids {
+            count += sqlx::query("INSERT INTO TeamMembers (team_id, user_id) VALUES ($1, $2)")
+                .bind(i64::from(team_id))
+                .bind(i64::from(*user_id))
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+
+        tx.commit().await?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn team_members(pool: DbPool) -> sqlx::Result<()> {
+        Team::insert(
+            &pool,
+            TeamForCreate {
+                name: "data".into(),
+            },
+        )
+        .await?;
+
+        assert_eq!(Team::set_members(&pool, 1, &[1, 3]).await?, 2);
+        let members = Team::members(&pool, 1).await?;
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].username, "user1");
+
+        Team::delete(&pool, 1).await?;
+        assert!(Team::list(&pool).await?.is_empty());
+        assert!(Team::members(&pool, 1).await?.is_empty());
+
+        Ok(())
+    }
+}
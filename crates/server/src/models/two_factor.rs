@@ -0,0 +1,250 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::utils::{hash_token, random_token};
+
+use super::DbPool;
+
+const ISSUER: &str = "Stamon";
+const STEP: u64 = 30;
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct TwoFactor {
+    #[sqlx(try_from = "i64")]
+    pub user_id: u32,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+fn totp(secret: &str, username: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_owned()).to_bytes().ok()?;
+    Some(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        1,
+        STEP,
+        secret,
+        Some(ISSUER.to_owned()),
+        username.to_owned(),
+    ))
+}
+
+/// The time step `code` is valid for at `now`, allowing one step of clock skew
+fn code_step(secret: &str, code: &str, now: u64) -> Option<i64> {
+    let totp = totp(secret, "")?;
+    [now.saturating_sub(STEP), now, now + STEP]
+        .into_iter()
+        .find(|&time| totp.generate(time) == code)
+        .map(|time| (time / STEP) as i64)
+}
+
+impl TwoFactor {
+    pub async fn get(pool: &DbPool, user_id: u32) -> sqlx::Result<Option<TwoFactor>> {
+        sqlx::query_as("SELECT * FROM TwoFactor WHERE user_id = $1")
+            .bind(i64::from(user_id))
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn is_enabled(pool: &DbPool, user_id: u32) -> sqlx::Result<bool> {
+        Ok(Self::get(pool, user_id).await?.is_some_and(|t| t.enabled))
+    }
+
+    /// Generate a new secret for the user to enrol, returning it and its otpauth uri.
+    ///
+    /// The secret is only used for logging in once confirmed with [`TwoFactor::enable`].
+    pub async fn begin(
+        pool: &DbPool,
+        user_id: u32,
+        username: &str,
+    ) -> sqlx::Result<(String, String)> {
+        let Secret::Encoded(secret) = Secret::generate_secret().to_encoded() else {
+            unreachable!("secret is encoded");
+        };
+        sqlx::query(
+            r#"INSERT INTO TwoFactor (user_id, secret) VALUES ($1, $2)
+               ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret, last_step = 0
+               WHERE TwoFactor.enabled = false"#,
+        )
+        .bind(i64::from(user_id))
+        .bind(&secret)
+        .execute(pool)
+        .await?;
+
+        let uri = totp(&secret, username)
+            .map(|totp| totp.get_url())
+            .unwrap_or_default();
+        Ok((secret, uri))
+    }
+
+    /// Check a TOTP code, each code is only accepted once
+    pub async fn verify_code(pool: &DbPool, user_id: u32, code: &str) -> sqlx::Result<bool> {
+        let Some(two_factor) = Self::get(pool, user_id).await? else {
+            return Ok(false);
+        };
+        let now = Utc::now().timestamp() as u64;
+        let Some(step) = code_step(&two_factor.secret, code, now) else {
+            return Ok(false);
+        };
+
+        let result = sqlx::query(
+            "UPDATE TwoFactor SET last_step = $1 WHERE user_id = $2 AND last_step < $3",
+        )
+        .bind(step)
+        .bind(i64::from(user_id))
+        .bind(step)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Check and use up a recovery code
+    pub async fn use_recovery_code(pool: &DbPool, user_id: u32, code: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query(
+            r#"UPDATE RecoveryCodes SET used_at = $1
+               WHERE id = (
+                   SELECT id FROM RecoveryCodes
+                   WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL
+                   LIMIT 1
+               )"#,
+        )
+        .bind(Utc::now())
+        .bind(i64::from(user_id))
+        .bind(hash_token(code))
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn recovery_codes_left(pool: &DbPool, user_id: u32) -> sqlx::Result<u32> {
+        let (count,) = sqlx::query_as::<_, (i64,)>(
+            "SELECT COUNT(*) FROM RecoveryCodes WHERE user_id = $1 AND used_at IS NULL",
+        )
+        .bind(i64::from(user_id))
+        .fetch_one(pool)
+        .await?;
+
+        Ok(count as u32)
+    }
+
+    /// Turn on two factor for logins, returning a fresh set of recovery codes
+    pub async fn enable(pool: &DbPool, user_id: u32) -> sqlx::Result<Vec<String>> {
+        sqlx::query("UPDATE TwoFactor SET enabled = true WHERE user_id = $1")
+            .bind(i64::from(user_id))
+            .execute(pool)
+            .await?;
+
+        Self::regenerate_recovery_codes(pool, user_id).await
+    }
+
+    /// Replace the recovery codes of a user, only hashes of the codes are stored
+    pub async fn regenerate_recovery_codes(
+        pool: &DbPool,
+        user_id: u32,
+    ) -> sqlx::Result<Vec<String>> {
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| random_token(12)).collect();
+
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE FROM RecoveryCodes WHERE user_id = $1")
+            .bind(i64::from(user_id))
+            .execute(&mut *tx)
+            .await?;
+        for code in &codes {
+            sqlx::query("INSERT INTO RecoveryCodes (user_id, code_hash) VALUES ($1, $2)")
+                .bind(i64::from(user_id))
+                .bind(hash_token(code))
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(codes)
+    }
+
+    pub async fn disable(pool: &DbPool, user_id: u32) -> sqlx::Result<u64> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE FROM RecoveryCodes WHERE user_id = $1")
+            .bind(i64::from(user_id))
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM TwoFactor WHERE user_id = $1")
+            .bind(i64::from(user_id))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // base32 of the RFC 6238 test secret "12345678901234567890"
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn rfc6238_test_vectors() {
+        assert_eq!(code_step(SECRET, "287082", 59), Some(1));
+        assert_eq!(code_step(SECRET, "081804", 1111111109), Some(37037036));
+        // one step of skew either way
+        assert_eq!(
+            code_step(SECRET, "081804", 1111111109 + STEP),
+            Some(37037036)
+        );
+        assert_eq!(code_step(SECRET, "081804", 1111111109 + 2 * STEP), None);
+        assert_eq!(code_step(SECRET, "000000", 59), None);
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn enrol_and_verify(pool: DbPool) -> sqlx::Result<()> {
+        let (secret, uri) = TwoFactor::begin(&pool, 1, "user1").await?;
+        assert!(uri.starts_with("otpauth://totp/Stamon:user1?"));
+        assert!(!TwoFactor::is_enabled(&pool, 1).await?);
+
+        let code = totp(&secret, "user1")
+            .unwrap()
+            .generate(Utc::now().timestamp() as u64);
+        assert!(TwoFactor::verify_code(&pool, 1, &code).await?);
+        // the same code can't be replayed
+        assert!(!TwoFactor::verify_code(&pool, 1, &code).await?);
+
+        let codes = TwoFactor::enable(&pool, 1).await?;
+        assert!(TwoFactor::is_enabled(&pool, 1).await?);
+        assert_eq!(codes.len(), RECOVERY_CODES);
+
+        // an enabled secret is not replaced by starting over
+        TwoFactor::begin(&pool, 1, "user1").await?;
+        assert_eq!(TwoFactor::get(&pool, 1).await?.unwrap().secret, secret);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn recovery_codes(pool: DbPool) -> sqlx::Result<()> {
+        TwoFactor::begin(&pool, 1, "user1").await?;
+        let codes = TwoFactor::enable(&pool, 1).await?;
+
+        assert!(TwoFactor::use_recovery_code(&pool, 1, &codes[0]).await?);
+        assert!(!TwoFactor::use_recovery_code(&pool, 1, &codes[0]).await?);
+        // codes belong to one user
+        assert!(!TwoFactor::use_recovery_code(&pool, 3, &codes[1]).await?);
+        assert_eq!(
+            TwoFactor::recovery_codes_left(&pool, 1).await?,
+            RECOVERY_CODES as u32 - 1
+        );
+
+        TwoFactor::disable(&pool, 1).await?;
+        assert!(!TwoFactor::is_enabled(&pool, 1).await?);
+        assert!(!TwoFactor::use_recovery_code(&pool, 1, &codes[1]).await?);
+
+        Ok(())
+    }
+}
@@ -1,18 +1,30 @@
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use sqlx::{Executor, QueryBuilder};
 
-use crate::auth::hash;
+use crate::{
+    auth::hash,
+    build_update_query,
+    utils::random_token,
+    validation::{self, FieldErrors, Validate},
+};
 
-#[derive(sqlx::Type, Debug, Serialize, Deserialize)]
-#[sqlx(rename_all = "lowercase")]
+use super::{Db, DbPool};
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub enum UserRole {
+    /// Full access, including users and teams
     Admin,
+    /// Can manage services they own or that are shared with them
+    Editor,
+    /// Read-only access to services they own or that are shared with them
     Viewer,
 }
 
 #[derive(sqlx::FromRow, Serialize)]
 pub struct User {
+    #[sqlx(try_from = "i64")]
     pub id: u32,
     pub username: String,
     #[serde(skip_serializing)]
@@ -30,14 +42,56 @@ pub struct UserForRegister {
     pub timezone: Option<String>,
 }
 
+/// Changes an admin can make to a user
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserForUpdate {
+    pub role: Option<UserRole>,
+    pub active: Option<bool>,
+    pub timezone: Option<String>,
+}
+
 #[derive(sqlx::FromRow, Serialize, Deserialize)]
 pub struct UserForLogin {
     pub username: String,
     pub password: String,
 }
 
+/// Shortest password accepted, the same as the web app asks for
+pub const MIN_PASSWORD_LENGTH: usize = 6;
+
+impl Validate for UserForRegister {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::default();
+        errors.check("username", validation::not_blank(&self.username));
+        errors.check("username", validation::max_length(&self.username, 64));
+        if self.password.chars().count() < MIN_PASSWORD_LENGTH {
+            errors.add(
+                "password",
+                format!("must be at least {MIN_PASSWORD_LENGTH} characters"),
+            );
+        }
+        if let Some(tz) = &self.timezone {
+            errors.check("timezone", validation::timezone(tz));
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for UserForUpdate {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::default();
+        if let Some(tz) = &self.timezone {
+            errors.check("timezone", validation::timezone(tz));
+        }
+        errors.into_result()
+    }
+}
+
 impl User {
-    pub async fn insert(pool: &SqlitePool, user: UserForRegister) -> sqlx::Result<u64> {
+    pub async fn insert<'e>(
+        executor: impl Executor<'e, Database = Db>,
+        user: UserForRegister,
+    ) -> sqlx::Result<u64> {
         // Default role to Viewer if not provided
         let role = user.role.unwrap_or(UserRole::Viewer);
 
@@ -46,9 +100,9 @@ impl User {
         if user.timezone.is_some() {
             query.push_str(", timezone");
         }
-        query.push_str(") VALUES (?, ?, ?, ?");
+        query.push_str(") VALUES ($1, $2, $3, $4");
         if user.timezone.is_some() {
-            query.push_str(", ?");
+            query.push_str(", $5");
         }
         query.push(')');
 
@@ -64,30 +118,130 @@ impl User {
         }
 
         // Execute the query
-        let result = query_builder.execute(pool).await?;
+        let result = query_builder.execute(executor).await?;
         Ok(result.rows_affected())
     }
 
-    pub async fn get(pool: &SqlitePool, user_id: u32) -> sqlx::Result<User> {
-        sqlx::query_as("SELECT * FROM Users WHERE id = ?")
-            .bind(user_id)
+    pub async fn get(pool: &DbPool, user_id: u32) -> sqlx::Result<User> {
+        sqlx::query_as("SELECT * FROM Users WHERE id = $1")
+            .bind(i64::from(user_id))
             .fetch_one(pool)
             .await
     }
 
-    pub async fn list(pool: &SqlitePool) -> sqlx::Result<Vec<User>> {
+    pub async fn get_by_username(pool: &DbPool, username: &str) -> sqlx::Result<Option<User>> {
+        sqlx::query_as("SELECT * FROM Users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn get_by_oidc_subject(pool: &DbPool, subject: &str) -> sqlx::Result<Option<User>> {
+        sqlx::query_as("SELECT * FROM Users WHERE oidc_subject = $1")
+            .bind(subject)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Create a user on their first single sign-on login, returning its id.
+    ///
+    /// The password is random, these users can only log in through their provider.
+    pub async fn insert_oidc(
+        pool: &DbPool,
+        subject: &str,
+        username: &str,
+        role: UserRole,
+    ) -> sqlx::Result<u32> {
+        let (id,) = sqlx::query_as::<_, (i64,)>(
+            r#"INSERT INTO Users (username, password, role, active, oidc_subject)
+               VALUES ($1, $2, $3, $4, $5)
+               RETURNING id"#,
+        )
+        .bind(username)
+        .bind(hash(random_token(32)))
+        .bind(role)
+        .bind(true)
+        .bind(subject)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(id as u32)
+    }
+
+    pub async fn list(pool: &DbPool) -> sqlx::Result<Vec<User>> {
         sqlx::query_as("SELECT * FROM Users").fetch_all(pool).await
     }
+
+    pub async fn update(
+        pool: &DbPool,
+        user_id: u32,
+        update_data: UserForUpdate,
+    ) -> sqlx::Result<u64> {
+        let mut query = QueryBuilder::new("UPDATE Users SET ");
+        let has_updates = build_update_query!(query, update_data, {
+            role,
+            active,
+            timezone
+        });
+        if !has_updates {
+            // No updates were provided
+            return Ok(0);
+        }
+        query.push(" WHERE id = ").push_bind(i64::from(user_id));
+
+        let result = query.build().execute(pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Active admins other than `user_id`
+    pub async fn count_other_active_admins(pool: &DbPool, user_id: u32) -> sqlx::Result<i64> {
+        let (count,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM Users WHERE role = $1 AND active = $2 AND id != $3",
+        )
+        .bind(UserRole::Admin)
+        .bind(true)
+        .bind(i64::from(user_id))
+        .fetch_one(pool)
+        .await?;
+        Ok(count)
+    }
+
+    pub async fn set_password(pool: &DbPool, user_id: u32, password: String) -> sqlx::Result<u64> {
+        let result = sqlx::query("UPDATE Users SET password = $1 WHERE id = $2")
+            .bind(hash(password))
+            .bind(i64::from(user_id))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Delete a user, transferring the services they own to `transfer_to`
+    pub async fn delete(pool: &DbPool, user_id: u32, transfer_to: u32) -> sqlx::Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("UPDATE Services SET user_id = $1 WHERE user_id = $2")
+            .bind(i64::from(transfer_to))
+            .bind(i64::from(user_id))
+            .execute(&mut *tx)
+            .await?;
+
+        let result = sqlx::query("DELETE FROM Users WHERE id = $1")
+            .bind(i64::from(user_id))
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use sqlx::SqlitePool;
-
     use super::*;
 
-    #[sqlx::test]
-    async fn insert_user_with_defaults(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn insert_user_with_defaults(pool: DbPool) -> sqlx::Result<()> {
         let count = User::insert(
             &pool,
             UserForRegister {
@@ -102,7 +256,7 @@ mod tests {
         assert_eq!(count, 1);
 
         // Verify the user was inserted with correct defaults
-        let user = sqlx::query_as::<_, User>("SELECT * FROM Users WHERE username = ?")
+        let user = sqlx::query_as::<_, User>("SELECT * FROM Users WHERE username = $1")
             .bind("testuser")
             .fetch_one(&pool)
             .await?;
@@ -115,8 +269,8 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn insert_user_with_admin_role(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR")]
+    async fn insert_user_with_admin_role(pool: DbPool) -> sqlx::Result<()> {
         let count = User::insert(
             &pool,
             UserForRegister {
@@ -131,7 +285,7 @@ mod tests {
         assert_eq!(count, 1);
 
         // Verify the user was inserted with admin role
-        let user = sqlx::query_as::<_, User>("SELECT * FROM Users WHERE username = ?")
+        let user = sqlx::query_as::<_, User>("SELECT * FROM Users WHERE username = $1")
             .bind("adminuser")
             .fetch_one(&pool)
             .await?;
@@ -144,8 +298,8 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users"))]
-    async fn get_existing_user(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn get_existing_user(pool: DbPool) -> sqlx::Result<()> {
         let user = User::get(&pool, 1).await?;
 
         assert_eq!(user.id, 1);
@@ -156,16 +310,104 @@ mod tests {
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users"))]
-    async fn get_nonexistent_user_fails(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn get_nonexistent_user_fails(pool: DbPool) -> sqlx::Result<()> {
         let result = User::get(&pool, 999).await;
         assert!(result.is_err());
 
         Ok(())
     }
 
-    #[sqlx::test(fixtures("users"))]
-    async fn list_all_users(pool: SqlitePool) -> sqlx::Result<()> {
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn update_user(pool: DbPool) -> sqlx::Result<()> {
+        let count = User::update(
+            &pool,
+            3,
+            UserForUpdate {
+                role: Some(UserRole::Editor),
+                active: Some(false),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(count, 1);
+
+        let user = User::get(&pool, 3).await?;
+        assert_eq!(user.role, UserRole::Editor);
+        assert!(!user.active);
+        assert_eq!(user.timezone.as_deref(), Some("Europe/Berlin"));
+
+        assert_eq!(User::update(&pool, 3, UserForUpdate::default()).await?, 0);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn change_password(pool: DbPool) -> sqlx::Result<()> {
+        User::set_password(&pool, 1, "new password".into()).await?;
+
+        let user = User::get(&pool, 1).await?;
+        assert!(crate::auth::verify_password("new password", &user.password));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn count_active_admins(pool: DbPool) -> sqlx::Result<()> {
+        // user1 is the only active admin
+        assert_eq!(User::count_other_active_admins(&pool, 1).await?, 0);
+        assert_eq!(User::count_other_active_admins(&pool, 2).await?, 1);
+
+        let update = UserForUpdate {
+            active: Some(true),
+            ..Default::default()
+        };
+        User::update(&pool, 2, update).await?;
+        assert_eq!(User::count_other_active_admins(&pool, 1).await?, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users", "services"))]
+    async fn delete_user_transfers_services(pool: DbPool) -> sqlx::Result<()> {
+        assert_eq!(User::delete(&pool, 3, 1).await?, 1);
+        assert!(User::get_by_username(&pool, "user3").await?.is_none());
+
+        let (owner,) = sqlx::query_as::<_, (i64,)>("SELECT user_id FROM Services WHERE id = 3")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(owner, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn provision_oidc_user(pool: DbPool) -> sqlx::Result<()> {
+        let id = User::insert_oidc(&pool, "https://idp|42", "jane", UserRole::Editor).await?;
+
+        let user = User::get_by_oidc_subject(&pool, "https://idp|42")
+            .await?
+            .expect("user should be linked");
+        assert_eq!(user.id, id);
+        assert_eq!(user.role, UserRole::Editor);
+        assert!(
+            User::get_by_oidc_subject(&pool, "https://idp|43")
+                .await?
+                .is_none()
+        );
+
+        // the subject can only be linked once
+        assert!(
+            User::insert_oidc(&pool, "https://idp|42", "jane2", UserRole::Viewer)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "crate::models::MIGRATOR", fixtures("users"))]
+    async fn list_all_users(pool: DbPool) -> sqlx::Result<()> {
         let users = User::list(&pool).await?;
 
         assert_eq!(users.len(), 4);
@@ -174,4 +416,26 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn validate_new_user() {
+        let user = UserForRegister {
+            username: "alice".into(),
+            role: None,
+            password: "hunter22".into(),
+            timezone: Some("Europe/Paris".into()),
+        };
+        assert!(user.validate().is_ok());
+
+        let user = UserForRegister {
+            username: "".into(),
+            role: None,
+            password: "abc".into(),
+            timezone: Some("Paris".into()),
+        };
+        assert_eq!(
+            user.validate().unwrap_err().fields(),
+            ["password", "timezone", "username"]
+        );
+    }
 }
@@ -9,21 +9,21 @@ use apalis::{
     prelude::{Event, Monitor, WorkerBuilder, WorkerFactoryFn},
 };
 use apalis_cron::{CronStream, Schedule};
-use apalis_sql::sqlite::SqliteStorage;
 use tower::load_shed::LoadShedLayer;
 use tracing::{debug, error, info};
 
 use crate::{
     AppState,
+    config::env_config,
     job::{self, Notification},
-    models::service::Service,
+    models::{JobStorage, service::Service},
     service, utils,
 };
 
 pub async fn monitors(state: &AppState) -> Result<(), Box<dyn std::error::Error>> {
-    let notification_storage: SqliteStorage<Notification> = SqliteStorage::new(state.pool.clone());
+    let notification_storage: JobStorage<Notification> = JobStorage::new(state.pool.clone());
 
-    let monitor_storage: SqliteStorage<Service> = SqliteStorage::new(state.pool.clone());
+    let monitor_storage: JobStorage<Service> = JobStorage::new(state.pool.clone());
 
     let schedule = Schedule::from_str("* * * * * *")?;
     let cron_timer = WorkerBuilder::new("uptime-timer")
@@ -35,6 +35,13 @@ pub async fn monitors(state: &AppState) -> Result<(), Box<dyn std::error::Error>
         .backend(CronStream::new(schedule))
         .build_fn(service::run_timer_cron_service);
 
+    let retention_timer = WorkerBuilder::new("retention-timer")
+        .enable_tracing()
+        .catch_panic()
+        .data(state.clone())
+        .backend(CronStream::new(Schedule::from_str("0 0 * * * *")?))
+        .build_fn(service::run_retention_cron_service);
+
     let notify_worker = WorkerBuilder::new("notification-worker")
         .layer(TraceLayer::new())
         .backend(notification_storage)
@@ -42,6 +49,7 @@ pub async fn monitors(state: &AppState) -> Result<(), Box<dyn std::error::Error>
 
     let monitor_worker = WorkerBuilder::new("monitor-worker")
         .data(state.clone())
+        .concurrency(env_config().worker_concurrency)
         .layer(RetryLayer::new(RetryPolicy::retries(3)))
         .layer(TraceLayer::new())
         .backend(monitor_storage)
@@ -49,6 +57,7 @@ pub async fn monitors(state: &AppState) -> Result<(), Box<dyn std::error::Error>
 
     Monitor::new()
         .register(cron_timer)
+        .register(retention_timer)
         .register(notify_worker)
         .register(monitor_worker)
         .shutdown_timeout(Duration::from_secs(10))
@@ -58,10 +67,8 @@ pub async fn monitors(state: &AppState) -> Result<(), Box<dyn std::error::Error>
                 Event::Start => {
                     info!(target: "worker", worker = %worker_id, "started");
                 }
-                Event::Engage(task_id) => {
-                    if worker_id.name() != "uptime-timer" {
-                        debug!(target: "worker", worker = %worker_id, task = %task_id, "engaged");
-                    }
+                Event::Engage(task_id) if worker_id.name() != "uptime-timer" => {
+                    debug!(target: "worker", worker = %worker_id, task = %task_id, "engaged");
                 }
                 Event::Idle => {
                     debug!(target: "worker", worker = %worker_id, "idle");
@@ -0,0 +1,474 @@
+//! OpenID Connect single sign-on, using the authorization code flow with PKCE
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm},
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{config::OidcConfig, models::user::UserRole, utils::random_token};
+
+/// The subset of the provider metadata we use
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Values of an authorization request, kept by the browser until the callback
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthFlow {
+    pub state: String,
+    pub nonce: String,
+    pub verifier: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub preferred_username: Option<String>,
+    pub email: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl IdClaims {
+    /// Stable identifier of the user across logins
+    pub fn subject(&self) -> String {
+        format!("{}|{}", self.iss, self.sub)
+    }
+
+    pub fn username(&self) -> &str {
+        self.preferred_username
+            .as_deref()
+            .or(self.email.as_deref())
+            .unwrap_or(&self.sub)
+    }
+
+    /// Map the configured role claim to a role, the claim may be a string or a list of strings
+    pub fn role(&self, config: &OidcConfig) -> UserRole {
+        let values: Vec<&str> = match self.extra.get(&config.role_claim) {
+            Some(Value::String(value)) => vec![value],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        let has_any = |wanted: &[String]| values.iter().any(|v| wanted.iter().any(|w| w == v));
+
+        if has_any(&config.admin_values) {
+            UserRole::Admin
+        } else if has_any(&config.editor_values) {
+            UserRole::Editor
+        } else {
+            UserRole::Viewer
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Http(reqwest::Error),
+    Token(jsonwebtoken::errors::Error),
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for OidcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OidcError::Http(e) => write!(f, "provider request failed: {e}"),
+            OidcError::Token(e) => write!(f, "invalid id token: {e}"),
+            OidcError::Invalid(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        OidcError::Http(e)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for OidcError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        OidcError::Token(e)
+    }
+}
+
+pub struct Provider<'a> {
+    config: &'a OidcConfig,
+    metadata: Metadata,
+    authorization_endpoint: Url,
+    http: reqwest::Client,
+}
+
+impl<'a> Provider<'a> {
+    /// Fetch the provider metadata from its discovery document
+    pub async fn discover(config: &'a OidcConfig) -> Result<Provider<'a>, OidcError> {
+        let http = reqwest::Client::new();
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let metadata: Metadata = http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if metadata.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(OidcError::Invalid("issuer mismatch in discovery document"));
+        }
+        let authorization_endpoint = Url::parse(&metadata.authorization_endpoint)
+            .map_err(|_| OidcError::Invalid("invalid authorization endpoint"))?;
+
+        Ok(Provider {
+            config,
+            metadata,
+            authorization_endpoint,
+            http,
+        })
+    }
+
+    /// Url to send the user to, along with the flow to check the callback against
+    pub fn authorize_url(&self) -> (String, AuthFlow) {
+        let flow = AuthFlow {
+            state: random_token(32),
+            nonce: random_token(32),
+            verifier: random_token(64),
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(flow.verifier.as_bytes()));
+
+        let mut url = self.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &flow.state)
+            .append_pair("nonce", &flow.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        (url.to_string(), flow)
+    }
+
+    /// Exchange the authorization code for an id token and validate it
+    pub async fn exchange(&self, code: &str, flow: &AuthFlow) -> Result<IdClaims, OidcError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &flow.verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let tokens: TokenResponse = self
+            .http
+            .post(&self.metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let claims = self.validate(&tokens.id_token).await?;
+        if claims.nonce.as_deref() != Some(&flow.nonce) {
+            return Err(OidcError::Invalid("nonce mismatch"));
+        }
+        Ok(claims)
+    }
+
+    /// Check the signature, issuer, audience and expiry of an id token
+    async fn validate(&self, id_token: &str) -> Result<IdClaims, OidcError> {
+        let header = decode_header(id_token)?;
+        let jwks: JwkSet = self
+            .http
+            .get(&self.metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .ok_or(OidcError::Invalid("no matching key in provider jwks"))?;
+
+        // the algorithm comes from the key, the token header is not trusted with it
+        let alg = key_algorithm(jwk).ok_or(OidcError::Invalid("unsupported provider key"))?;
+        if header.alg != alg {
+            return Err(OidcError::Invalid(
+                "id token algorithm does not match the key",
+            ));
+        }
+        let mut validation = Validation::new(alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&self.metadata.issuer]);
+        let data = decode::<IdClaims>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?;
+
+        Ok(data.claims)
+    }
+}
+
+/// Algorithm an id token signed with a key must use, only RS256 and ES256 are accepted
+fn key_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    let alg = match (&jwk.algorithm, jwk.common.key_algorithm) {
+        (AlgorithmParameters::RSA(_), None | Some(KeyAlgorithm::RS256)) => Algorithm::RS256,
+        (AlgorithmParameters::EllipticCurve(params), None | Some(KeyAlgorithm::ES256))
+            if params.curve == EllipticCurve::P256 =>
+        {
+            Algorithm::ES256
+        }
+        _ => return None,
+    };
+    Some(alg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{Form, Json, Router, extract::State, routing::get, routing::post};
+    use chrono::Utc;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A provider issuing ES256 id tokens, for the code "good-code" only. The code
+    /// "hs256-code" gets a token signed with HS256 instead.
+    async fn mock_issuer(nonce: &'static str, groups: Value) -> OidcConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let key = rcgen::KeyPair::generate().unwrap();
+        let signing_key = EncodingKey::from_ec_pem(key.serialize_pem().as_bytes()).unwrap();
+        // uncompressed point, 0x04 then x and y
+        let (x, y) = key.public_key_raw()[1..].split_at(32);
+        let jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "k1",
+            "x": URL_SAFE_NO_PAD.encode(x),
+            "y": URL_SAFE_NO_PAD.encode(y),
+        });
+
+        let discovery = {
+            let issuer = issuer.clone();
+            move || async move {
+                Json(json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{issuer}/authorize"),
+                    "token_endpoint": format!("{issuer}/token"),
+                    "jwks_uri": format!("{issuer}/jwks"),
+                }))
+            }
+        };
+        let jwks = move || async move { Json(json!({ "keys": [jwk] })) };
+        let token = move |State((issuer, groups)): State<(String, Value)>,
+                          Form(form): Form<HashMap<String, String>>| async move {
+            let (alg, key) = match form.get("code").map(String::as_str) {
+                Some("good-code") => (Algorithm::ES256, signing_key),
+                Some("hs256-code") => (Algorithm::HS256, EncodingKey::from_secret(b"secret")),
+                _ => return Err(axum::http::StatusCode::BAD_REQUEST),
+            };
+            if !form.contains_key("code_verifier") {
+                return Err(axum::http::StatusCode::BAD_REQUEST);
+            }
+            let header = Header {
+                alg,
+                kid: Some("k1".into()),
+                ..Default::default()
+            };
+            let claims = json!({
+                "iss": issuer,
+                "aud": "stamon",
+                "sub": "42",
+                "exp": Utc::now().timestamp() + 60,
+                "nonce": nonce,
+                "preferred_username": "jane",
+                "groups": groups,
+            });
+            let id_token = encode(&header, &claims, &key).unwrap();
+            Ok(Json(
+                json!({ "id_token": id_token, "token_type": "Bearer" }),
+            ))
+        };
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state((issuer.clone(), groups));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        OidcConfig {
+            issuer,
+            client_id: "stamon".into(),
+            redirect_url: "http://localhost/api/oidc/callback".into(),
+            scopes: vec!["openid".into()],
+            role_claim: "groups".into(),
+            admin_values: vec!["ops-admins".into()],
+            editor_values: vec!["ops".into()],
+            ..Default::default()
+        }
+    }
+
+    fn flow(nonce: &str) -> AuthFlow {
+        AuthFlow {
+            state: "state".into(),
+            nonce: nonce.into(),
+            verifier: "verifier".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn authorize_url_uses_pkce() {
+        let config = mock_issuer("n", json!([])).await;
+        let provider = Provider::discover(&config).await.unwrap();
+        let (url, flow) = provider.authorize_url();
+
+        let url = reqwest::Url::parse(&url).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], "stamon");
+        assert_eq!(query["state"], flow.state);
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert_eq!(
+            query["code_challenge"],
+            URL_SAFE_NO_PAD.encode(Sha256::digest(flow.verifier.as_bytes()))
+        );
+    }
+
+    #[tokio::test]
+    async fn exchange_code_for_claims() {
+        let config = mock_issuer("nonce-1", json!(["ops", "dev"])).await;
+        let provider = Provider::discover(&config).await.unwrap();
+
+        let claims = provider
+            .exchange("good-code", &flow("nonce-1"))
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "42");
+        assert_eq!(claims.username(), "jane");
+        assert_eq!(claims.subject(), format!("{}|42", config.issuer));
+        assert_eq!(claims.role(&config), UserRole::Editor);
+
+        assert!(
+            provider
+                .exchange("bad-code", &flow("nonce-1"))
+                .await
+                .is_err()
+        );
+        // the token can't pick an algorithm the provider key isn't for
+        assert!(matches!(
+            provider.exchange("hs256-code", &flow("nonce-1")).await,
+            Err(OidcError::Invalid(_))
+        ));
+        // a replayed id token from another flow is rejected
+        assert!(matches!(
+            provider.exchange("good-code", &flow("nonce-2")).await,
+            Err(OidcError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_authorization_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let metadata = json!({
+            "issuer": issuer,
+            "authorization_endpoint": "not a url",
+            "token_endpoint": format!("{issuer}/token"),
+            "jwks_uri": format!("{issuer}/jwks"),
+        });
+        let app = Router::new().route(
+            "/.well-known/openid-configuration",
+            get(move || async move { Json(metadata) }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = OidcConfig {
+            issuer,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Provider::discover(&config).await,
+            Err(OidcError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_wrong_audience() {
+        let mut config = mock_issuer("n", json!([])).await;
+        config.client_id = "someone-else".into();
+        let provider = Provider::discover(&config).await.unwrap();
+
+        assert!(matches!(
+            provider.exchange("good-code", &flow("n")).await,
+            Err(OidcError::Token(_))
+        ));
+    }
+
+    #[test]
+    fn key_algorithms() {
+        let jwk = |jwk: Value| serde_json::from_value::<Jwk>(jwk).unwrap();
+        let rsa = json!({ "kty": "RSA", "n": "AQAB", "e": "AQAB" });
+        let ec = json!({ "kty": "EC", "crv": "P-256", "x": "AA", "y": "AA" });
+
+        assert_eq!(key_algorithm(&jwk(rsa.clone())), Some(Algorithm::RS256));
+        assert_eq!(key_algorithm(&jwk(ec.clone())), Some(Algorithm::ES256));
+        let mut rs512 = rsa;
+        rs512["alg"] = "RS512".into();
+        assert_eq!(key_algorithm(&jwk(rs512)), None);
+        let mut p384 = ec;
+        p384["crv"] = "P-384".into();
+        assert_eq!(key_algorithm(&jwk(p384)), None);
+        assert_eq!(
+            key_algorithm(&jwk(json!({ "kty": "oct", "k": "c2VjcmV0" }))),
+            None
+        );
+    }
+
+    #[test]
+    fn map_roles_from_claim() {
+        let config = OidcConfig {
+            role_claim: "groups".into(),
+            admin_values: vec!["ops-admins".into()],
+            editor_values: vec!["ops".into()],
+            ..Default::default()
+        };
+        let claims = |groups: Value| IdClaims {
+            iss: "i".into(),
+            sub: "s".into(),
+            nonce: None,
+            preferred_username: None,
+            email: None,
+            extra: Map::from_iter([("groups".to_string(), groups)]),
+        };
+
+        assert_eq!(
+            claims(json!(["ops", "ops-admins"])).role(&config),
+            UserRole::Admin
+        );
+        assert_eq!(claims(json!("ops")).role(&config), UserRole::Editor);
+        assert_eq!(claims(json!(["dev"])).role(&config), UserRole::Viewer);
+        assert_eq!(claims(Value::Null).role(&config), UserRole::Viewer);
+    }
+}
@@ -0,0 +1,31 @@
+use axum::{
+    Router,
+    extract::{Query, State},
+    middleware,
+    routing::get,
+};
+use axum_macros::debug_handler;
+use serde_json::{Value, json};
+
+use crate::{
+    AppState,
+    error::ApiResult,
+    extractors::json::Json,
+    middlewares::role::require_admin_role,
+    models::audit::{AuditEntry, AuditFilter},
+};
+
+#[debug_handler]
+async fn list_audit_log(
+    State(state): State<AppState>,
+    Query(filter): Query<AuditFilter>,
+) -> ApiResult<Json<Value>> {
+    let entries = AuditEntry::list(&state.pool, &filter).await?;
+    Ok(Json(json!({ "entries": entries })))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/audit", get(list_audit_log))
+        .route_layer(middleware::from_fn(require_admin_role))
+}
@@ -1,178 +1,424 @@
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use std::net::IpAddr;
+
 use axum::{
-    Router, debug_handler,
+    Router,
+    body::Bytes,
+    debug_handler,
     extract::State,
+    http::{HeaderMap, header::USER_AGENT},
     response::{IntoResponse, Redirect, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
+};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
 };
-use chrono::{Duration, Utc};
-use jsonwebtoken::{EncodingKey, Header, encode};
-use reqwest::StatusCode;
-use serde_json::json;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{Value, json};
 use sqlx::Row;
 use tracing::{debug, error};
 
 use crate::{
     AppState,
-    auth::Claims,
-    config::env_config,
-    extractors::json::Json,
+    auth::{
+        ACCESS_TOKEN_TTL, AuthError, Claims, LoginChallenge, REFRESH_TOKEN_TTL,
+        verify_dummy_password, verify_password,
+    },
+    error::{ApiError, ApiResult},
+    extractors::{
+        client_ip::ClientIp,
+        json::{Json, ValidJson},
+        path::Path,
+    },
     models::{
-        UserForLogin, UserForRegister,
-        user::{User, UserRole},
+        DbPool, UserForLogin, UserForRegister,
+        auth_event::{AuthEvent, AuthEventForCreate, AuthEventKind},
+        session::{Session, SessionForCreate},
+        two_factor::TwoFactor,
+        user::User,
     },
 };
 
-#[debug_handler]
-async fn login(State(state): State<AppState>, Json(user_login): Json<UserForLogin>) -> Response {
-    let users_exists: bool = match sqlx::query("SELECT EXISTS(SELECT 1 FROM users)")
-        .fetch_one(&state.pool)
-        .await
-    {
-        Ok(row) => row.get(0),
-        Err(e) => {
-            error!("{e}");
-            false
-        }
-    };
+#[derive(Deserialize)]
+struct TwoFactorLogin {
+    challenge: String,
+    code: String,
+}
 
-    if !users_exists {
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Cookie of the access token, how the web ui authenticates its requests
+fn token_cookie(token: String) -> Cookie<'static> {
+    Cookie::build(("token", token))
+        .path("/")
+        .same_site(SameSite::Lax)
+        .secure(true)
+        .http_only(true)
+        .build()
+}
+
+/// Cookie of the refresh token, only sent to the api
+fn refresh_cookie(refresh_token: String) -> Cookie<'static> {
+    Cookie::build(("refresh_token", refresh_token))
+        .path("/api")
+        .same_site(SameSite::Strict)
+        .secure(true)
+        .http_only(true)
+        .build()
+}
+
+/// Respond with a new access and refresh token pair, also set as cookies for the web ui
+fn token_response(claims: &Claims, refresh_token: &str) -> ApiResult<(CookieJar, Json<Value>)> {
+    let token = claims
+        .encode()
+        .map_err(|e| ApiError::Internal(format!("Error encoding token: {e}")))?;
+
+    let mut refresh = refresh_cookie(refresh_token.to_owned());
+    refresh.set_max_age(time::Duration::seconds(REFRESH_TOKEN_TTL.num_seconds()));
+    let cookies = CookieJar::new()
+        .add(token_cookie(token.clone()))
+        .add(refresh);
+    Ok((
+        cookies,
+        Json(json!({
+            "token": token,
+            "refresh_token": refresh_token,
+            "expires_in": ACCESS_TOKEN_TTL.num_seconds(),
+        })),
+    ))
+}
+
+async fn users_exist(pool: &DbPool) -> sqlx::Result<bool> {
+    let row = sqlx::query("SELECT EXISTS(SELECT 1 FROM users)")
+        .fetch_one(pool)
+        .await?;
+    Ok(row.get(0))
+}
+
+#[debug_handler]
+async fn login(
+    State(state): State<AppState>,
+    ClientIp(addr): ClientIp,
+    headers: HeaderMap,
+    Json(user_login): Json<UserForLogin>,
+) -> ApiResult<Response> {
+    if !users_exist(&state.pool).await? {
         debug!("No user registered");
-        return Redirect::temporary("/register").into_response();
+        return Ok(Redirect::temporary("/register").into_response());
     }
 
+    let ip = addr.to_string();
+    check_throttle(&state, &user_login.username, &ip).await?;
+
     let Some(user) = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = $1")
-        .bind(user_login.username)
+        .bind(&user_login.username)
         .fetch_optional(&state.pool)
-        .await
-        .unwrap()
+        .await?
     else {
-        return Redirect::to("/register").into_response();
-    };
-
-    let is_valid = {
-        let parsed_hash = PasswordHash::new(&user.password).unwrap();
-        Argon2::default()
-            .verify_password(user_login.password.as_bytes(), &parsed_hash)
-            .is_ok_and(|_| true)
+        // answer like for a wrong password, in as much time, so usernames can't be guessed
+        verify_dummy_password(&user_login.password);
+        record_failure(&state, None, &user_login.username, &ip, "unknown user").await;
+        return Err(invalid_login());
     };
 
-    if !is_valid {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": "Invalid email or password"})),
-        )
-            .into_response();
+    // Only tell a disabled account apart once the password proved who is asking
+    if !verify_password(&user_login.password, &user.password) {
+        record_failure(&state, Some(user.id), &user.username, &ip, "password").await;
+        return Err(invalid_login());
+    }
+    if !user.active {
+        record_failure(&state, Some(user.id), &user.username, &ip, "disabled").await;
+        return Err(ApiError::Forbidden("Account disabled".into()));
     }
 
-    let now = Utc::now();
-    let issued_at = now.timestamp() as usize;
-    let expiry = (now + Duration::minutes(60)).timestamp() as usize;
-    let claims: Claims = Claims {
-        user_id: user.id,
-        expiry,
-        issued_at,
-        role: UserRole::Admin,
-    };
+    // With two factor enabled, tokens are only issued by `login_two_factor`
+    if TwoFactor::is_enabled(&state.pool, user.id).await? {
+        let challenge = LoginChallenge::new(user.id)
+            .encode()
+            .map_err(|e| ApiError::Internal(format!("Error encoding login challenge: {e}")))?;
+        return Ok(Json(json!({
+            "two_factor_required": true,
+            "challenge": challenge,
+        }))
+        .into_response());
+    }
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(env_config().jwt_secret.as_bytes()),
-    )
-    .unwrap();
+    Ok(start_session(&state, &user, &headers, addr, "password")
+        .await?
+        .into_response())
+}
 
-    Response::builder()
-        .header("Content-Type", "application/json")
-        .header(
-            "Set-Cookie",
-            format!("token={}; Path=/; SameSite=Lax; Secure; HttpOnly", token),
-        )
-        .body(json!({ "token": token }).to_string())
-        .unwrap()
-        .into_response()
+fn invalid_login() -> ApiError {
+    ApiError::BadRequest("Invalid email or password".into())
 }
 
+/// Second step of logging in, with a TOTP or recovery code
 #[debug_handler]
-async fn register(State(state): State<AppState>, Json(user): Json<UserForRegister>) -> Response {
-    let users_exists: bool = match sqlx::query("SELECT EXISTS(SELECT 1 FROM users)")
-        .fetch_one(&state.pool)
-        .await
-    {
-        Ok(row) => row.get(0),
+async fn login_two_factor(
+    State(state): State<AppState>,
+    ClientIp(addr): ClientIp,
+    headers: HeaderMap,
+    Json(login): Json<TwoFactorLogin>,
+) -> ApiResult<(CookieJar, Json<Value>)> {
+    let challenge = LoginChallenge::decode(&login.challenge)
+        .ok_or_else(|| ApiError::Unauthorized("Invalid or expired challenge".into()))?;
+
+    let user = User::get(&state.pool, challenge.user_id).await?;
+    if !user.active {
+        return Err(ApiError::Forbidden("Account disabled".into()));
+    }
+
+    let ip = addr.to_string();
+    check_throttle(&state, &user.username, &ip).await?;
+
+    // TOTP codes are 6 digits, anything else may be a recovery code
+    let code = login.code.trim();
+    let valid = if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) {
+        TwoFactor::verify_code(&state.pool, user.id, code).await?
+    } else {
+        TwoFactor::use_recovery_code(&state.pool, user.id, code).await?
+    };
+    if !valid {
+        record_failure(&state, Some(user.id), &user.username, &ip, "two factor").await;
+        return Err(ApiError::BadRequest("Invalid code".into()));
+    }
+
+    start_session(&state, &user, &headers, addr, "two factor").await
+}
+
+/// Respond with `429` if there were too many failed logins for the username or from the address
+async fn check_throttle(state: &AppState, username: &str, ip: &str) -> ApiResult<()> {
+    match AuthEvent::login_delay(&state.pool, username, ip).await {
+        Ok(Some(delay)) => Err(ApiError::TooManyRequests(delay.num_seconds().max(1))),
+        Ok(None) => Ok(()),
         Err(e) => {
-            error!("{e}");
-            false
+            error!("Error checking failed logins: {e}");
+            Ok(())
         }
+    }
+}
+
+async fn record_failure(
+    state: &AppState,
+    user_id: Option<u32>,
+    username: &str,
+    ip: &str,
+    detail: &str,
+) {
+    let event = AuthEventForCreate {
+        kind: AuthEventKind::LoginFailure,
+        user_id,
+        username: Some(username.to_owned()),
+        ip: Some(ip.to_owned()),
+        detail: Some(detail.to_owned()),
     };
+    if let Err(e) = AuthEvent::insert_failure(&state.pool, event).await {
+        error!("Error recording failed login: {e}");
+    }
+}
 
-    if users_exists {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({"error": "Cannot register"})),
-        )
-            .into_response();
+/// Create a session for a logged in user and respond with its tokens
+pub(super) async fn start_session(
+    state: &AppState,
+    user: &User,
+    headers: &HeaderMap,
+    addr: IpAddr,
+    method: &str,
+) -> ApiResult<(CookieJar, Json<Value>)> {
+    let event = AuthEventForCreate {
+        kind: AuthEventKind::LoginSuccess,
+        user_id: Some(user.id),
+        username: Some(user.username.clone()),
+        ip: Some(addr.to_string()),
+        detail: Some(method.to_owned()),
+    };
+    if let Err(e) = AuthEvent::insert(&state.pool, event).await {
+        error!("Error recording login of user({}): {e}", user.id);
     }
 
-    let count = match User::insert(&state.pool, user).await {
-        Ok(c) => c,
-        Err(e) => {
-            error!("{e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "Registration failed"})),
-            )
-                .into_response();
+    let session = SessionForCreate {
+        user_id: user.id,
+        user_agent: headers
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned),
+        ip: Some(addr.to_string()),
+        expires_at: Utc::now() + REFRESH_TOKEN_TTL,
+    };
+    let (session_id, refresh_token) = Session::create(&state.pool, session).await?;
+
+    token_response(&Claims::new(user.id, user.role, session_id), &refresh_token)
+}
+
+/// Exchange a refresh token, from the cookie or the request body, for a new token pair
+#[debug_handler]
+async fn refresh(
+    State(state): State<AppState>,
+    cookies: CookieJar,
+    body: Bytes,
+) -> ApiResult<(CookieJar, Json<Value>)> {
+    let refresh_token = match cookies.get("refresh_token") {
+        Some(cookie) => cookie.value().to_owned(),
+        None => {
+            serde_json::from_slice::<RefreshRequest>(&body)
+                .map_err(|_| ApiError::BadRequest("Missing refresh token".into()))?
+                .refresh_token
         }
     };
-    debug!("Register {:?}", count);
+    let invalid = || ApiError::Unauthorized("Invalid refresh token".into());
 
-    Json(json!({"message": "user registered"})).into_response()
+    let expires_at = Utc::now() + REFRESH_TOKEN_TTL;
+    let (session, refresh_token) = Session::rotate(&state.pool, &refresh_token, expires_at)
+        .await?
+        .ok_or_else(invalid)?;
+
+    // the user may have been disabled or had their role changed since logging in
+    let role = Session::active_role(&state.pool, session.id, session.user_id)
+        .await?
+        .ok_or_else(invalid)?;
+    token_response(
+        &Claims::new(session.user_id, role, session.id),
+        &refresh_token,
+    )
 }
 
-async fn logout() -> Response {
-    Response::builder()
-        .header("Content-Type", "application/json")
-        .header(
-            "Set-Cookie",
-            "token=; Path=/; SameSite=Lax; Secure; HttpOnly; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
-        )
-        .body(json!({ "message": "Logged out successfully" }).to_string())
-        .unwrap()
-        .into_response()
+#[debug_handler]
+async fn register(
+    State(state): State<AppState>,
+    ValidJson(user): ValidJson<UserForRegister>,
+) -> ApiResult<Json<Value>> {
+    if users_exist(&state.pool).await? {
+        return Err(ApiError::Unauthorized("Cannot register".into()));
+    }
+
+    let count = User::insert(&state.pool, user).await?;
+    debug!("Register {:?}", count);
+
+    Ok(Json(json!({"message": "user registered"})))
 }
 
-async fn check(State(state): State<AppState>) -> Response {
-    let users_exists: bool = match sqlx::query("SELECT EXISTS(SELECT 1 FROM users)")
-        .fetch_one(&state.pool)
-        .await
-    {
-        Ok(row) => row.get(0),
-        Err(e) => {
-            error!("Failed to check for existing users: {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "Service temporarily unavailable"})),
-            )
-                .into_response();
-        }
+/// Revoke the current session, with either the access or the refresh token
+async fn logout(
+    State(state): State<AppState>,
+    claims: Result<Claims, AuthError>,
+    cookies: CookieJar,
+) -> (CookieJar, Json<Value>) {
+    let revoked = match (claims, cookies.get("refresh_token")) {
+        (
+            Ok(Claims {
+                user_id,
+                session_id: Some(session_id),
+                ..
+            }),
+            _,
+        ) => Session::revoke(&state.pool, session_id, user_id).await,
+        (_, Some(cookie)) => Session::revoke_by_token(&state.pool, cookie.value()).await,
+        (_, None) => Ok(0),
     };
+    if let Err(e) = revoked {
+        error!("Error revoking session: {e}");
+    }
 
-    if !users_exists {
+    let mut token = token_cookie(String::new());
+    token.make_removal();
+    let mut refresh = refresh_cookie(String::new());
+    refresh.make_removal();
+    (
+        CookieJar::new().add(token).add(refresh),
+        Json(json!({ "message": "Logged out successfully" })),
+    )
+}
+
+/// Revoke every session of the user, including the current one
+#[debug_handler]
+async fn logout_all(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Value>> {
+    let count = Session::revoke_all(&state.pool, user_id, None).await?;
+    Ok(Json(
+        json!({"message": format!("{count} sessions revoked")}),
+    ))
+}
+
+#[debug_handler]
+async fn list_sessions(claims: Claims, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let sessions = Session::list_active(&state.pool, claims.user_id).await?;
+    Ok(Json(json!({
+        "sessions": sessions,
+        "current": claims.session_id,
+    })))
+}
+
+#[debug_handler]
+async fn revoke_session(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    if Session::revoke(&state.pool, id, user_id).await? == 0 {
+        return Err(ApiError::NotFound("Session not found".into()));
+    }
+    Ok(Json(json!({"message": "Session revoked"})))
+}
+
+async fn check(State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    if !users_exist(&state.pool).await? {
         debug!("No user registered");
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "No user registered"})),
-        )
-            .into_response();
+        return Err(ApiError::NotFound("No user registered".into()));
     }
-    Json(json!({"message": "Already setup"})).into_response()
+    Ok(Json(json!({"message": "Already setup"})))
 }
 
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/check", get(check))
-        .route("/logout", get(logout))
+        .route("/logout", get(logout).post(logout))
+        .route("/logout-all", post(logout_all))
         .route("/login", post(login))
+        .route("/login/2fa", post(login_two_factor))
+        .route("/refresh", post(refresh))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{id}", delete(revoke_session))
         .route("/register", post(register))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::SET_COOKIE;
+
+    use super::*;
+
+    #[test]
+    fn session_cookies() {
+        let mut refresh = refresh_cookie("refresh".into());
+        refresh.make_removal();
+        let res = (
+            CookieJar::new()
+                .add(token_cookie("token".into()))
+                .add(refresh),
+            Json(json!({})),
+        )
+            .into_response();
+
+        let mut cookies: Vec<_> = res
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        cookies.sort();
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies[0].starts_with(
+            "refresh_token=; HttpOnly; SameSite=Strict; Secure; Path=/api; Max-Age=0; Expires="
+        ));
+        assert_eq!(
+            cookies[1],
+            "token=token; HttpOnly; SameSite=Lax; Secure; Path=/"
+        );
+    }
+}
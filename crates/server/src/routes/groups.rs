@@ -0,0 +1,134 @@
+use axum::{
+    Router,
+    extract::State,
+    http::StatusCode,
+    middleware,
+    routing::{get, post, put},
+};
+use axum_macros::debug_handler;
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::{ApiError, ApiResult},
+    extractors::{json::Json, path::Path},
+    middlewares::role::require_editor_role,
+    models::{
+        DbPool,
+        group::{Group, GroupForCreate, GroupForUpdate},
+        log::Status,
+    },
+};
+
+#[derive(Serialize)]
+pub struct GroupWithStatus {
+    #[serde(flatten)]
+    group: Group,
+    status: Status,
+}
+
+/// List the groups visible to `visible_to`, all of them when `None`, with their aggregate
+/// status over the services it can see
+pub async fn groups_with_status(
+    pool: &DbPool,
+    visible_to: Option<u32>,
+) -> sqlx::Result<Vec<GroupWithStatus>> {
+    let mut groups = vec![];
+    for group in Group::list(pool).await? {
+        if let Some(user_id) = visible_to
+            && !group.is_visible_to(pool, user_id).await?
+        {
+            continue;
+        }
+        let status = group.status(pool, visible_to).await?;
+        groups.push(GroupWithStatus { group, status });
+    }
+    Ok(groups)
+}
+
+fn not_found() -> ApiError {
+    ApiError::NotFound("Group not found".into())
+}
+
+#[debug_handler]
+async fn list_groups(claims: Claims, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let groups = groups_with_status(&state.pool, claims.visible_to()).await?;
+    Ok(Json(json!({ "groups": groups })))
+}
+
+#[debug_handler]
+async fn add_group(
+    _: Claims,
+    State(state): State<AppState>,
+    Json(group): Json<GroupForCreate>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    Group::insert(&state.pool, group).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "Group created" })),
+    ))
+}
+
+#[debug_handler]
+async fn get_group(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(group_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    let group = Group::get(&state.pool, group_id)
+        .await?
+        .ok_or_else(not_found)?;
+    if let Some(user_id) = claims.visible_to()
+        && !group.is_visible_to(&state.pool, user_id).await?
+    {
+        return Err(not_found());
+    }
+    let status = group.status(&state.pool, claims.visible_to()).await?;
+    Ok(Json(json!({ "group": GroupWithStatus { group, status } })))
+}
+
+#[debug_handler]
+async fn update_group(
+    _: Claims,
+    State(state): State<AppState>,
+    Path(group_id): Path<u32>,
+    Json(group): Json<GroupForUpdate>,
+) -> ApiResult<Json<Value>> {
+    if let Some(parent_id) = group.parent_id {
+        // the group can't be moved under itself or one of its subgroups
+        let ancestors = Group::ancestors(&state.pool, parent_id).await?;
+        if ancestors.iter().any(|g| g.id == group_id) {
+            return Err(ApiError::BadRequest(
+                "A group cannot be nested in itself".into(),
+            ));
+        }
+    }
+    Group::update(&state.pool, group_id, group).await?;
+    Ok(Json(json!({ "message": "Group updated" })))
+}
+
+#[debug_handler]
+async fn delete_group(
+    _: Claims,
+    State(state): State<AppState>,
+    Path(group_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    if Group::delete(&state.pool, group_id).await? == 0 {
+        return Err(not_found());
+    }
+    Ok(Json(json!({ "message": "Group deleted" })))
+}
+
+pub fn routes() -> Router<AppState> {
+    let write = Router::new()
+        .route("/groups", post(add_group))
+        .route("/groups/{id}", put(update_group).delete(delete_group))
+        .route_layer(middleware::from_fn(require_editor_role));
+
+    Router::new()
+        .route("/groups", get(list_groups))
+        .route("/groups/{id}", get(get_group))
+        .merge(write)
+}
@@ -1,15 +1,13 @@
 use axum::{
     Router,
     extract::{Query, State},
-    response::{IntoResponse, Response},
     routing::get,
 };
 use axum_macros::debug_handler;
 use serde::Deserialize;
-use serde_json::json;
-use tracing::error;
+use serde_json::{Value, json};
 
-use crate::{AppState, auth::Claims, models::log::Log};
+use crate::{AppState, auth::Claims, error::ApiResult, extractors::json::Json, models::log::Log};
 
 #[derive(Deserialize)]
 struct Pagination {
@@ -18,50 +16,22 @@ struct Pagination {
 
 #[debug_handler]
 async fn list_logs(
-    _: Claims,
+    claims: Claims,
     State(state): State<AppState>,
     pagination: Query<Pagination>,
-) -> Response {
-    match Log::list_all(&state.pool, pagination.limit).await {
-        Ok(logs) => Response::builder()
-            .header("Content-Type", "application/json")
-            .body(json!({ "logs": logs }).to_string())
-            .unwrap()
-            .into_response(),
-        Err(e) => {
-            error!("{e}");
-            Response::builder()
-                .header("Content-Type", "application/json")
-                .status(500)
-                .body(json!({ "message": "Internal server error" }).to_string())
-                .unwrap()
-                .into_response()
-        }
-    }
+) -> ApiResult<Json<Value>> {
+    let logs = Log::list_all(&state.pool, pagination.limit, claims.visible_to()).await?;
+    Ok(Json(json!({ "logs": logs })))
 }
 
 #[debug_handler]
 async fn list_log_incidents(
-    _: Claims,
+    claims: Claims,
     State(state): State<AppState>,
     pagination: Query<Pagination>,
-) -> Response {
-    match Log::incidents(&state.pool, pagination.limit).await {
-        Ok(incidents) => Response::builder()
-            .header("Content-Type", "application/json")
-            .body(json!({ "incidents": incidents }).to_string())
-            .unwrap()
-            .into_response(),
-        Err(e) => {
-            error!("{e}");
-            Response::builder()
-                .header("Content-Type", "application/json")
-                .status(500)
-                .body(json!({ "message": "Internal server error" }).to_string())
-                .unwrap()
-                .into_response()
-        }
-    }
+) -> ApiResult<Json<Value>> {
+    let incidents = Log::incidents(&state.pool, pagination.limit, claims.visible_to()).await?;
+    Ok(Json(json!({ "incidents": incidents })))
 }
 
 pub fn routes() -> Router<AppState> {
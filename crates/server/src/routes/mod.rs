@@ -1,25 +1,31 @@
-use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use axum::{Json, Router, extract::State, middleware, routing::get};
 use serde_json::{Value, json};
-use tracing::error;
 
 use crate::{
     AppState,
-    models::service::{Service, Stats},
+    auth::Claims,
+    error::{ApiError, ApiResult},
+    middlewares::audit::audit_log,
+    models::service::Service,
 };
 
+mod audit;
 mod auth;
+mod groups;
 mod logs;
+mod oidc;
+mod push;
 mod service;
+mod settings;
+mod tags;
+mod teams;
+mod tokens;
 mod users;
 
-async fn stats(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
-    match Service::get_stats(&state.pool).await {
-        Ok(stats) => (StatusCode::OK, Json(json!({"stats": stats}))),
-        Err(e) => {
-            error!("{e}");
-            (StatusCode::OK, Json(json!({"stats": Stats::default()})))
-        }
-    }
+async fn stats(claims: Claims, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let groups = groups::groups_with_status(&state.pool, claims.visible_to()).await?;
+    let stats = Service::get_stats(&state.pool, claims.visible_to()).await?;
+    Ok(Json(json!({"stats": stats, "groups": groups})))
 }
 
 pub fn routes() -> Router<AppState> {
@@ -27,17 +33,34 @@ pub fn routes() -> Router<AppState> {
 
     Router::new()
         .merge(auth::routes())
+        .merge(audit::routes())
         .merge(service::routes())
+        .merge(settings::routes())
+        .merge(tags::routes())
+        .merge(groups::routes())
         .merge(logs::routes())
+        .merge(oidc::routes())
+        .merge(push::routes())
+        .merge(teams::routes())
+        .merge(tokens::routes())
         .merge(users::routes())
         .merge(stats_route)
         .fallback(root)
+        .layer(middleware::from_fn(audit_log))
 }
 
 // fallback handler that responds with a 404
-async fn root() -> (StatusCode, Json<Value>) {
-    (
-        StatusCode::NOT_FOUND,
-        Json(json!({ "message": "Not Found" })),
-    )
+async fn root() -> ApiError {
+    ApiError::NotFound("Not Found".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_do_not_overlap() {
+        // read and write routers share paths, merging them panics if methods overlap
+        let _ = routes();
+    }
 }
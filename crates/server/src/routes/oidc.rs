@@ -0,0 +1,178 @@
+use axum::{
+    Router,
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Redirect,
+    routing::get,
+};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use axum_macros::debug_handler;
+use chrono::{TimeDelta, Utc};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    AppState,
+    config::env_config,
+    error::{ApiError, ApiResult},
+    extractors::client_ip::ClientIp,
+    models::user::{User, UserForUpdate},
+    oidc::{AuthFlow, Provider},
+};
+
+const FLOW_COOKIE: &str = "oidc_flow";
+const FLOW_TTL: TimeDelta = TimeDelta::minutes(10);
+
+/// The authorization flow, signed and kept in a cookie until the provider redirects back
+#[derive(Serialize, Deserialize)]
+struct FlowCookie {
+    #[serde(flatten)]
+    flow: AuthFlow,
+    exp: usize,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+fn not_configured() -> ApiError {
+    ApiError::NotFound("Single sign-on is not configured".into())
+}
+
+/// Cookie of the authorization flow, Lax as the provider redirects back with a top level
+/// cross-site navigation
+fn flow_cookie(value: String) -> Cookie<'static> {
+    Cookie::build((FLOW_COOKIE, value))
+        .path("/api/oidc")
+        .same_site(SameSite::Lax)
+        .secure(true)
+        .http_only(true)
+        .build()
+}
+
+/// Redirect to the identity provider to log in
+#[debug_handler]
+async fn login() -> ApiResult<(CookieJar, Redirect)> {
+    let config = env_config().oidc.as_ref().ok_or_else(not_configured)?;
+    let provider = Provider::discover(config).await.map_err(|e| {
+        error!("OIDC discovery failed: {e}");
+        ApiError::Unavailable("Identity provider unavailable".into())
+    })?;
+
+    let (url, flow) = provider.authorize_url();
+    let cookie = FlowCookie {
+        flow,
+        exp: (Utc::now() + FLOW_TTL).timestamp() as usize,
+    };
+    let cookie = encode(
+        &Header::default(),
+        &cookie,
+        &EncodingKey::from_secret(env_config().jwt_secret.as_bytes()),
+    )
+    .map_err(|e| ApiError::Internal(format!("Error encoding oidc flow: {e}")))?;
+
+    let mut cookie = flow_cookie(cookie);
+    cookie.set_max_age(time::Duration::seconds(FLOW_TTL.num_seconds()));
+    Ok((CookieJar::new().add(cookie), Redirect::to(&url)))
+}
+
+/// The provider redirects here with an authorization code, log the user in and
+/// create their account on first login
+#[debug_handler]
+async fn callback(
+    State(state): State<AppState>,
+    ClientIp(addr): ClientIp,
+    headers: HeaderMap,
+    cookies: CookieJar,
+    Query(query): Query<CallbackQuery>,
+) -> ApiResult<(CookieJar, Redirect)> {
+    let config = env_config().oidc.as_ref().ok_or_else(not_configured)?;
+    if let Some(e) = query.error {
+        return Err(ApiError::Unauthorized(format!("Login failed: {e}")));
+    }
+    let code = query
+        .code
+        .ok_or_else(|| ApiError::BadRequest("Missing authorization code".into()))?;
+
+    let flow = cookies.get(FLOW_COOKIE).and_then(|cookie| {
+        decode::<FlowCookie>(
+            cookie.value(),
+            &DecodingKey::from_secret(env_config().jwt_secret.as_bytes()),
+            &Validation::default(),
+        )
+        .ok()
+    });
+    let flow = flow
+        .map(|data| data.claims.flow)
+        .ok_or_else(|| ApiError::BadRequest("Login expired, try again".into()))?;
+    if query.state.as_deref() != Some(&flow.state) {
+        return Err(ApiError::BadRequest("Invalid state".into()));
+    }
+
+    let claims = match Provider::discover(config).await {
+        Ok(provider) => provider.exchange(&code, &flow).await,
+        Err(e) => Err(e),
+    };
+    let claims = claims.map_err(|e| {
+        error!("OIDC login failed: {e}");
+        ApiError::Unauthorized("Login failed".into())
+    })?;
+
+    // Roles follow the provider, they are updated on every login
+    let role = claims.role(config);
+    let user = match User::get_by_oidc_subject(&state.pool, &claims.subject()).await? {
+        Some(user) => {
+            // the last active admin keeps their role, or no one could manage users
+            if user.role != role
+                && let Err(e) = super::users::check_last_admin(&state, &user).await
+            {
+                warn!("Not changing the role of user({}): {e}", user.id);
+            } else if user.role != role {
+                let update = UserForUpdate {
+                    role: Some(role),
+                    ..Default::default()
+                };
+                if let Err(e) = User::update(&state.pool, user.id, update).await {
+                    error!("Error updating role of user({}): {e}", user.id);
+                }
+            }
+            User::get(&state.pool, user.id).await?
+        }
+        None => {
+            if User::get_by_username(&state.pool, claims.username())
+                .await?
+                .is_some()
+            {
+                return Err(ApiError::Conflict(
+                    "Username already taken by a local account".into(),
+                ));
+            }
+            info!("Creating user {} from single sign-on", claims.username());
+            let id =
+                User::insert_oidc(&state.pool, &claims.subject(), claims.username(), role).await?;
+            User::get(&state.pool, id).await?
+        }
+    };
+    if !user.active {
+        return Err(ApiError::Forbidden("Account disabled".into()));
+    }
+
+    let (cookies, _) = super::auth::start_session(&state, &user, &headers, addr, "oidc").await?;
+    // The cookies set by the session are all the web ui needs, send the user back to it
+    let mut flow = flow_cookie(String::new());
+    flow.make_removal();
+    Ok((cookies.add(flow), Redirect::to("/")))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/oidc/login", get(login))
+        .route("/oidc/callback", get(callback))
+}
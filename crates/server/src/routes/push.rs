@@ -0,0 +1,135 @@
+use axum::{
+    Router,
+    extract::{Query, State},
+    routing::get,
+};
+use axum_macros::debug_handler;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+    AppState,
+    error::{ApiError, ApiResult},
+    extractors::{json::Json, path::Path},
+    job::monitor::report,
+    models::{
+        log::{LogForCreate, Status},
+        service::Service,
+    },
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PushStatus {
+    #[default]
+    Up,
+    Down,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PushEvent {
+    Start,
+    Success,
+    Fail,
+}
+
+#[derive(Deserialize)]
+struct PushParams {
+    #[serde(default)]
+    status: PushStatus,
+    msg: Option<String>,
+    ping: Option<u32>,
+}
+
+fn ok_response() -> Json<Value> {
+    Json(json!({ "message": "ok" }))
+}
+
+/// Look up an active push service by token and record that it has been heard from
+async fn touch_service(state: &AppState, token: &str) -> ApiResult<Service> {
+    let service = match Service::get_by_push_token(&state.pool, token).await? {
+        Some(s) if s.active => s,
+        _ => {
+            return Err(ApiError::NotFound("Service not found or inactive".into()));
+        }
+    };
+    Service::set_last_push(&state.pool, service.id, Utc::now()).await?;
+
+    Ok(service)
+}
+
+/// Receive a heartbeat for a push service.
+///
+/// These routes are authenticated by the secret token in the url only, so that they can
+/// be called from cron jobs and scripts.
+#[debug_handler]
+async fn push(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(params): Query<PushParams>,
+) -> ApiResult<Json<Value>> {
+    let service = touch_service(&state, &token).await?;
+
+    let status = match params.status {
+        PushStatus::Up => Status::Up,
+        PushStatus::Down => Status::Down,
+    };
+    let status_log = LogForCreate {
+        service_id: service.id,
+        status,
+        message: params.msg,
+        time: Some(Utc::now()),
+        duration: params.ping.unwrap_or_default(),
+        ..Default::default()
+    };
+    report(&state, &service, status_log).await;
+
+    Ok(ok_response())
+}
+
+/// Receive a `/start`, `/success` or `/fail` signal for a push service run.
+///
+/// The run duration between start and success/fail is recorded in the log.
+#[debug_handler]
+async fn push_event(
+    State(state): State<AppState>,
+    Path((token, event)): Path<(String, PushEvent)>,
+    Query(params): Query<PushParams>,
+) -> ApiResult<Json<Value>> {
+    let service = touch_service(&state, &token).await?;
+
+    let now = Utc::now();
+    let (started_at, status) = match event {
+        PushEvent::Start => (Some(now), None),
+        PushEvent::Success => (None, Some(Status::Up)),
+        PushEvent::Fail => (None, Some(Status::Down)),
+    };
+    Service::set_started_at(&state.pool, service.id, started_at).await?;
+
+    if let Some(status) = status {
+        let duration = service
+            .started_at
+            .map(|start| (now - start).num_milliseconds().max(0) as u32)
+            .or(params.ping)
+            .unwrap_or_default();
+        let status_log = LogForCreate {
+            service_id: service.id,
+            status,
+            message: params.msg,
+            time: Some(now),
+            duration,
+            ..Default::default()
+        };
+        report(&state, &service, status_log).await;
+    }
+
+    Ok(ok_response())
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/push/{token}", get(push).post(push))
+        .route("/push/{token}/{event}", get(push_event).post(push_event))
+}
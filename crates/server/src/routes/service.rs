@@ -1,20 +1,29 @@
 use axum::{
-    Json, Router,
-    extract::{Path, Query, State},
-    response::{IntoResponse, Response},
-    routing::{get, put},
+    Router,
+    extract::{Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post, put},
 };
 use axum_macros::debug_handler;
-use serde::Deserialize;
-use serde_json::json;
-use tracing::error;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 
 use crate::{
     AppState,
     auth::Claims,
+    error::{ApiError, ApiResult},
+    extractors::{
+        json::{Json, ValidJson},
+        path::Path,
+    },
+    middlewares::role::require_editor_role,
     models::{
         log::Log,
-        service::{Service, ServiceForCreate, ServiceForUpdate},
+        service::{Service, ServiceFilter, ServiceForCreate, ServiceForUpdate, Shares},
+        snapshot::ContentSnapshot,
+        tag::{ServiceTag, Tag},
     },
 };
 
@@ -23,130 +32,385 @@ struct Pagination {
     limit: Option<u32>,
 }
 
+/// Snapshots to diff, the two newest when not set
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ServiceWithTags {
+    #[serde(flatten)]
+    service: Service,
+    tags: Vec<Tag>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum BulkAction {
+    Pause,
+    Resume,
+    Delete,
+    /// Replace the tags of the services
+    Retag {
+        tags: Vec<u32>,
+    },
+}
+
+#[derive(Deserialize)]
+struct PauseParams {
+    /// Automatically resume the service at this time
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Dependencies {
+    parents: Vec<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkRequest {
+    ids: Vec<u32>,
+    #[serde(flatten)]
+    action: BulkAction,
+}
+
+fn not_found() -> ApiError {
+    ApiError::NotFound("Service not found".into())
+}
+
+/// Ensure the user can access a service.
+///
+/// Responds with `404` so other users' services are not revealed.
+async fn check_access(state: &AppState, claims: &Claims, service_id: u32) -> ApiResult<()> {
+    let Some(user_id) = claims.visible_to() else {
+        return Ok(());
+    };
+    if Service::can_access(&state.pool, service_id, user_id).await? {
+        Ok(())
+    } else {
+        Err(not_found())
+    }
+}
+
+/// Ensure the tags exist and the user can see them, respond with `404` otherwise
+async fn check_tags(state: &AppState, claims: &Claims, tag_ids: &[u32]) -> ApiResult<()> {
+    let visible = Tag::list(&state.pool, claims.visible_to()).await?;
+    match tag_ids
+        .iter()
+        .find(|id| !visible.iter().any(|tag| tag.id == **id))
+    {
+        Some(id) => Err(ApiError::NotFound(format!("Tag {id} not found"))),
+        None => Ok(()),
+    }
+}
+
+/// Ensure the user owns a service, only owners and admins can change who it is shared with
+async fn check_owner(state: &AppState, claims: &Claims, service_id: u32) -> ApiResult<()> {
+    match Service::get(&state.pool, service_id).await? {
+        Some(s) if claims.is_admin() || s.user_id == claims.user_id => Ok(()),
+        Some(_) => Err(ApiError::Forbidden(
+            "Only the owner can share a service".into(),
+        )),
+        None => Err(not_found()),
+    }
+}
+
 #[debug_handler]
 async fn add_service(
     Claims { user_id, .. }: Claims,
     State(state): State<AppState>,
-    Json(mut service): Json<ServiceForCreate>,
-) -> Response {
+    ValidJson(mut service): ValidJson<ServiceForCreate>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
     service.user_id = Some(user_id);
-    if let Err(e) = Service::insert(&state.pool, service).await {
-        error!("Error adding service: {e}");
-        return Response::builder()
-            .status(500)
-            .header("Content-Type", "application/json")
-            .body(json!({ "message": "Internal server error" }).to_string())
-            .unwrap()
-            .into_response();
-    };
-    Response::builder()
-        .status(201)
-        .header("Content-Type", "application/json")
-        .body(json!({ "message": "Services created" }).to_string())
-        .unwrap()
-        .into_response()
+    if service.interval == 0 {
+        service.interval = state.settings.borrow().default_interval;
+    }
+    Service::insert(&state.pool, service).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "Services created" })),
+    ))
 }
 
 #[debug_handler]
 async fn get_service(
-    _: Claims,
+    claims: Claims,
     State(state): State<AppState>,
     Path(service_id): Path<u32>,
-) -> Response {
-    match Service::get(&state.pool, service_id).await {
-        Err(e) => {
-            error!("Error updating service({service_id}): {e}");
-            Response::builder()
-                .status(500)
-                .header("Content-Type", "application/json")
-                .body(json!({ "message": "Internal server error" }).to_string())
-                .unwrap()
-                .into_response()
-        }
-        Ok(Some(s)) => Response::builder()
-            .status(200)
-            .header("Content-Type", "application/json")
-            .body(json!({ "service": s }).to_string())
-            .unwrap()
-            .into_response(),
-        _ => Response::builder()
-            .status(404)
-            .header("Content-Type", "application/json")
-            .body(json!({ "message": "Service not found" }).to_string())
-            .unwrap()
-            .into_response(),
-    }
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    let service = Service::get(&state.pool, service_id)
+        .await?
+        .ok_or_else(not_found)?;
+    let tags = Tag::list_for_service(&state.pool, service_id).await?;
+    Ok(Json(
+        json!({ "service": ServiceWithTags { service, tags } }),
+    ))
 }
 
 #[debug_handler]
 async fn update_service(
-    _: Claims,
+    claims: Claims,
     State(state): State<AppState>,
     Path(service_id): Path<u32>,
-    Json(service): Json<ServiceForUpdate>,
-) -> Response {
-    if let Err(e) = Service::update(&state.pool, service_id, service).await {
-        error!("Error updating service({service_id}): {e}");
-        return Response::builder()
-            .status(500)
-            .header("Content-Type", "application/json")
-            .body(json!({ "message": "Internal server error" }).to_string())
-            .unwrap()
-            .into_response();
-    };
-    Response::builder()
-        .status(201)
-        .header("Content-Type", "application/json")
-        .body(json!({ "message": "Services updated" }).to_string())
-        .unwrap()
-        .into_response()
-}
-
-#[debug_handler]
-async fn list_services(State(state): State<AppState>) -> Response {
-    let Ok(services) = Service::all(&state.pool).await else {
-        return Response::builder()
-            .header("Content-Type", "application/json")
-            .status(500)
-            .body(json!({ "message": "Internal server error" }).to_string())
-            .unwrap()
-            .into_response();
-    };
-    Response::builder()
-        .header("Content-Type", "application/json")
-        .body(json!({ "services": services }).to_string())
-        .unwrap()
-        .into_response()
+    ValidJson(service): ValidJson<ServiceForUpdate>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    check_access(&state, &claims, service_id).await?;
+    let current = Service::get(&state.pool, service_id)
+        .await?
+        .ok_or_else(not_found)?;
+    service.validate_with(&current)?;
+    Service::update(&state.pool, service_id, service).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "Services updated" })),
+    ))
+}
+
+#[debug_handler]
+async fn delete_service(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    if Service::delete_many(&state.pool, &[service_id]).await? == 0 {
+        return Err(not_found());
+    }
+    Ok(Json(json!({ "message": "Service deleted" })))
+}
+
+#[debug_handler]
+async fn clone_service(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    check_access(&state, &claims, service_id).await?;
+    let id = Service::duplicate(&state.pool, service_id, claims.user_id)
+        .await?
+        .ok_or_else(not_found)?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "Service cloned", "id": id })),
+    ))
+}
+
+#[debug_handler]
+async fn pause_service(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+    Query(PauseParams { until }): Query<PauseParams>,
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    if Service::pause_many(&state.pool, &[service_id], until).await? == 0 {
+        return Err(not_found());
+    }
+    Ok(Json(
+        json!({ "message": "Service paused", "resume_at": until }),
+    ))
+}
+
+#[debug_handler]
+async fn resume_service(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    if Service::resume_many(&state.pool, &[service_id]).await? == 0 {
+        return Err(not_found());
+    }
+    Ok(Json(json!({ "message": "Service resumed" })))
+}
+
+#[debug_handler]
+async fn list_services(
+    claims: Claims,
+    State(state): State<AppState>,
+    Query(mut filter): Query<ServiceFilter>,
+) -> ApiResult<Json<Value>> {
+    filter.visible_to = claims.visible_to();
+    let services = Service::list(&state.pool, &filter).await?;
+    let service_tags = Tag::list_service_tags(&state.pool).await?;
+    let services: Vec<ServiceWithTags> = services
+        .into_iter()
+        .map(|service| ServiceWithTags {
+            tags: service_tags
+                .iter()
+                .filter(|st| st.service_id == service.id)
+                .map(|ServiceTag { tag, .. }| tag.clone())
+                .collect(),
+            service,
+        })
+        .collect();
+    Ok(Json(json!({ "services": services })))
 }
 
 #[debug_handler]
 async fn list_service_logs(
-    //_: Claims,
+    claims: Claims,
     State(state): State<AppState>,
     Path(service_id): Path<u32>,
     pagination: Query<Pagination>,
-) -> Response {
-    match Log::list(&state.pool, service_id, pagination.limit).await {
-        Ok(logs) => Response::builder()
-            .header("Content-Type", "application/json")
-            .body(json!({ "logs": logs }).to_string())
-            .unwrap()
-            .into_response(),
-        Err(e) => {
-            error!("{e}");
-            Response::builder()
-                .header("Content-Type", "application/json")
-                .status(500)
-                .body(json!({ "message": "Internal server error" }).to_string())
-                .unwrap()
-                .into_response()
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    let logs = Log::list(&state.pool, service_id, pagination.limit).await?;
+    Ok(Json(json!({ "logs": logs })))
+}
+
+#[debug_handler]
+async fn list_service_snapshots(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    let snapshots = ContentSnapshot::list(&state.pool, service_id).await?;
+    Ok(Json(json!({ "snapshots": snapshots })))
+}
+
+#[debug_handler]
+async fn diff_service_snapshots(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+    Query(DiffQuery { from, to }): Query<DiffQuery>,
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    let pool = &state.pool;
+    let snapshot = |id| async move {
+        ContentSnapshot::get(pool, service_id, id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("Snapshot not found".into()))
+    };
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (snapshot(from).await?, snapshot(to).await?),
+        (None, None) => {
+            let mut newest = ContentSnapshot::list(&state.pool, service_id).await?;
+            if newest.len() < 2 {
+                return Err(ApiError::NotFound("Not enough snapshots to diff".into()));
+            }
+            let to = newest.remove(0);
+            (newest.remove(0), to)
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Set both from and to, or neither".into(),
+            ));
+        }
+    };
+    let diff = from.diff(&to);
+    Ok(Json(json!({ "from": from, "to": to, "diff": diff })))
+}
+
+#[debug_handler]
+async fn bulk_services(
+    claims: Claims,
+    State(state): State<AppState>,
+    Json(BulkRequest { ids, action }): Json<BulkRequest>,
+) -> ApiResult<Json<Value>> {
+    for service_id in &ids {
+        check_access(&state, &claims, *service_id).await?;
+    }
+    let count = match &action {
+        BulkAction::Pause => Service::pause_many(&state.pool, &ids, None).await?,
+        BulkAction::Resume => Service::resume_many(&state.pool, &ids).await?,
+        BulkAction::Delete => Service::delete_many(&state.pool, &ids).await?,
+        BulkAction::Retag { tags } => {
+            check_tags(&state, &claims, tags).await?;
+            Tag::set_for_services(&state.pool, &ids, tags).await?
+        }
+    };
+    Ok(Json(
+        json!({ "message": "Services updated", "count": count }),
+    ))
+}
+
+#[debug_handler]
+async fn list_service_dependencies(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    let parents = Service::parents(&state.pool, service_id).await?;
+    Ok(Json(json!({ "parents": parents })))
+}
+
+#[debug_handler]
+async fn set_service_dependencies(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+    Json(Dependencies { parents }): Json<Dependencies>,
+) -> ApiResult<Json<Value>> {
+    check_access(&state, &claims, service_id).await?;
+    // Reject dependencies that would form a cycle
+    for parent_id in &parents {
+        check_access(&state, &claims, *parent_id).await?;
+        if *parent_id == service_id
+            || Service::depends_on(&state.pool, *parent_id, service_id).await?
+        {
+            return Err(ApiError::BadRequest(format!(
+                "Service {parent_id} depends on this service"
+            )));
         }
     }
+
+    Service::set_parents(&state.pool, service_id, &parents).await?;
+    Ok(Json(json!({ "message": "Dependencies updated" })))
+}
+
+#[debug_handler]
+async fn list_service_shares(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    check_owner(&state, &claims, service_id).await?;
+    let shares = Service::shares(&state.pool, service_id).await?;
+    Ok(Json(json!({ "shares": shares })))
+}
+
+#[debug_handler]
+async fn set_service_shares(
+    claims: Claims,
+    State(state): State<AppState>,
+    Path(service_id): Path<u32>,
+    Json(shares): Json<Shares>,
+) -> ApiResult<Json<Value>> {
+    check_owner(&state, &claims, service_id).await?;
+    Service::set_shares(&state.pool, service_id, &shares).await?;
+    Ok(Json(json!({ "message": "Shares updated" })))
 }
 
 pub fn routes() -> Router<AppState> {
-    Router::new()
-        .route("/services", get(list_services).post(add_service))
-        .route("/services/{id}", put(update_service).get(get_service))
+    let read = Router::new()
+        .route("/services", get(list_services))
+        .route("/services/{id}", get(get_service))
         .route("/services/{id}/logs", get(list_service_logs))
+        .route(
+            "/services/{id}/dependencies",
+            get(list_service_dependencies),
+        )
+        .route("/services/{id}/shares", get(list_service_shares))
+        .route("/services/{id}/snapshots", get(list_service_snapshots))
+        .route("/services/{id}/snapshots/diff", get(diff_service_snapshots));
+
+    let write = Router::new()
+        .route("/services", post(add_service))
+        .route("/services/bulk", post(bulk_services))
+        .route("/services/{id}", put(update_service).delete(delete_service))
+        .route("/services/{id}/clone", post(clone_service))
+        .route("/services/{id}/pause", post(pause_service))
+        .route("/services/{id}/resume", post(resume_service))
+        .route("/services/{id}/dependencies", put(set_service_dependencies))
+        .route("/services/{id}/shares", put(set_service_shares))
+        .route_layer(middleware::from_fn(require_editor_role));
+
+    read.merge(write)
 }
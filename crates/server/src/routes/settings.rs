@@ -0,0 +1,87 @@
+use axum::{Router, extract::State, middleware, routing::get};
+use axum_macros::debug_handler;
+use serde_json::{Value, json};
+
+use crate::{
+    AppState,
+    error::{ApiError, ApiResult},
+    extractors::json::Json,
+    middlewares::role::require_admin_role,
+    models::settings::Settings,
+};
+
+/// Settings as shown to admins, the SMTP password is never sent back
+fn settings_json(settings: &Settings) -> Value {
+    let mut value = serde_json::to_value(settings).unwrap_or_default();
+    if let Some(smtp) = value.get_mut("smtp").and_then(Value::as_object_mut) {
+        smtp.remove("password");
+        smtp.insert(
+            "password_set".into(),
+            settings.smtp.password.is_some().into(),
+        );
+    }
+    value
+}
+
+#[debug_handler]
+async fn get_settings(State(state): State<AppState>) -> Json<Value> {
+    let settings = state.settings.borrow().clone();
+    Json(json!({ "settings": settings_json(&settings) }))
+}
+
+/// Update some of the settings, fields left out keep their value and `null` resets them
+#[debug_handler]
+async fn update_settings(
+    State(state): State<AppState>,
+    Json(patch): Json<Value>,
+) -> ApiResult<Json<Value>> {
+    if !patch.is_object() {
+        return Err(ApiError::BadRequest("Expected a JSON object".into()));
+    }
+    let settings = state
+        .settings
+        .borrow()
+        .patched(patch)
+        .map_err(ApiError::BadRequest)?;
+    settings.save(&state.pool).await?;
+
+    let body = json!({ "settings": settings_json(&settings) });
+    state.settings.send_replace(settings);
+    Ok(Json(body))
+}
+
+/// Settings needed before logging in, like on the login page
+#[debug_handler]
+async fn public_settings(State(state): State<AppState>) -> Json<Value> {
+    let settings = state.settings.borrow();
+    Json(json!({
+        "site_title": settings.site_title,
+        "public_url": settings.public_url,
+    }))
+}
+
+pub fn routes() -> Router<AppState> {
+    let admin_routes = Router::new()
+        .route("/settings", get(get_settings).put(update_settings))
+        .route_layer(middleware::from_fn(require_admin_role));
+
+    Router::new()
+        .route("/settings/public", get(public_settings))
+        .merge(admin_routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smtp_password_hidden() {
+        let mut settings = Settings::default();
+        settings.smtp.password = Some("hunter2".into());
+
+        let value = settings_json(&settings);
+        assert_eq!(value["smtp"].get("password"), None);
+        assert_eq!(value["smtp"]["password_set"], true);
+        assert_eq!(value["site_title"], "Stamon");
+    }
+}
@@ -0,0 +1,58 @@
+use axum::{
+    Router,
+    extract::State,
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, post},
+};
+use axum_macros::debug_handler;
+use serde_json::{Value, json};
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::{ApiError, ApiResult},
+    extractors::{json::Json, path::Path},
+    middlewares::role::require_editor_role,
+    models::tag::{Tag, TagForCreate},
+};
+
+#[debug_handler]
+async fn list_tags(claims: Claims, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let tags = Tag::list(&state.pool, claims.visible_to()).await?;
+    Ok(Json(json!({ "tags": tags })))
+}
+
+#[debug_handler]
+async fn add_tag(
+    _: Claims,
+    State(state): State<AppState>,
+    Json(tag): Json<TagForCreate>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    Tag::insert(&state.pool, tag).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "Tag created" })),
+    ))
+}
+
+#[debug_handler]
+async fn delete_tag(
+    _: Claims,
+    State(state): State<AppState>,
+    Path(tag_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    if Tag::delete(&state.pool, tag_id).await? == 0 {
+        return Err(ApiError::NotFound("Tag not found".into()));
+    }
+    Ok(Json(json!({ "message": "Tag deleted" })))
+}
+
+pub fn routes() -> Router<AppState> {
+    let write = Router::new()
+        .route("/tags", post(add_tag))
+        .route("/tags/{id}", delete(delete_tag))
+        .route_layer(middleware::from_fn(require_editor_role));
+
+    Router::new().route("/tags", get(list_tags)).merge(write)
+}
@@ -0,0 +1,83 @@
+use axum::{
+    Router,
+    extract::State,
+    http::StatusCode,
+    middleware,
+    routing::{delete, get},
+};
+use axum_macros::debug_handler;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+    AppState,
+    error::{ApiError, ApiResult},
+    extractors::{json::Json, path::Path},
+    middlewares::role::require_admin_role,
+    models::team::{Team, TeamForCreate},
+};
+
+#[derive(Deserialize)]
+struct Members {
+    users: Vec<u32>,
+}
+
+#[debug_handler]
+async fn list_teams(State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let teams = Team::list(&state.pool).await?;
+    Ok(Json(json!({ "teams": teams })))
+}
+
+#[debug_handler]
+async fn add_team(
+    State(state): State<AppState>,
+    Json(team): Json<TeamForCreate>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    Team::insert(&state.pool, team).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "Team created" })),
+    ))
+}
+
+#[debug_handler]
+async fn delete_team(
+    State(state): State<AppState>,
+    Path(team_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    if Team::delete(&state.pool, team_id).await? == 0 {
+        return Err(ApiError::NotFound("Team not found".into()));
+    }
+    Ok(Json(json!({ "message": "Team deleted" })))
+}
+
+#[debug_handler]
+async fn list_team_members(
+    State(state): State<AppState>,
+    Path(team_id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    let users = Team::members(&state.pool, team_id).await?;
+    Ok(Json(json!({ "users": users })))
+}
+
+#[debug_handler]
+async fn set_team_members(
+    State(state): State<AppState>,
+    Path(team_id): Path<u32>,
+    Json(Members { users }): Json<Members>,
+) -> ApiResult<Json<Value>> {
+    Team::set_members(&state.pool, team_id, &users).await?;
+    Ok(Json(json!({ "message": "Team members updated" })))
+}
+
+/// Team management is limited to admins
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/teams", get(list_teams).post(add_team))
+        .route("/teams/{id}", delete(delete_team))
+        .route(
+            "/teams/{id}/members",
+            get(list_team_members).put(set_team_members),
+        )
+        .route_layer(middleware::from_fn(require_admin_role))
+}
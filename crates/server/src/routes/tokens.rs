@@ -0,0 +1,72 @@
+use axum::{
+    Router,
+    extract::State,
+    http::StatusCode,
+    routing::{delete, get},
+};
+use axum_macros::debug_handler;
+use serde_json::{Value, json};
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::{ApiError, ApiResult},
+    extractors::{json::Json, path::Path},
+    models::{
+        api_token::{ApiToken, ApiTokenForCreate, TokenScope},
+        user::UserRole,
+    },
+};
+
+#[debug_handler]
+async fn list_tokens(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Value>> {
+    let tokens = ApiToken::list(&state.pool, user_id).await?;
+    Ok(Json(json!({ "tokens": tokens })))
+}
+
+#[debug_handler]
+async fn add_token(
+    claims: Claims,
+    State(state): State<AppState>,
+    Json(token): Json<ApiTokenForCreate>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    // Tokens can only be created from an interactive session, not with another token
+    if claims.session_id.is_none() {
+        return Err(ApiError::Forbidden(
+            "Api tokens can't create other tokens".into(),
+        ));
+    }
+    if token.scope == TokenScope::Admin && claims.role != UserRole::Admin
+        || token.scope == TokenScope::Write && claims.role == UserRole::Viewer
+    {
+        return Err(ApiError::BadRequest("Scope exceeds your role".into()));
+    }
+
+    let (id, secret) = ApiToken::create(&state.pool, claims.user_id, token).await?;
+    // the token is only ever shown here
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "id": id, "token": secret })),
+    ))
+}
+
+#[debug_handler]
+async fn revoke_token(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    if ApiToken::revoke(&state.pool, id, user_id).await? == 0 {
+        return Err(ApiError::NotFound("Token not found".into()));
+    }
+    Ok(Json(json!({ "message": "Token revoked" })))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/tokens", get(list_tokens).post(add_token))
+        .route("/tokens/{id}", delete(revoke_token))
+}
@@ -1,57 +1,448 @@
 use axum::{
     Router,
-    extract::State,
-    response::{IntoResponse, Response},
-    routing::get,
+    extract::{Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, post, put},
 };
 use axum_macros::debug_handler;
-use serde_json::json;
+use chrono::{TimeDelta, Utc};
+use serde::Deserialize;
+use serde_json::{Value, json};
 use tracing::error;
 
-use crate::{AppState, auth::Claims, models::user::User};
-
-#[debug_handler]
-async fn get_user(Claims { user_id, .. }: Claims, State(state): State<AppState>) -> Response {
-    match User::get(&state.pool, user_id).await {
-        Ok(user) => Response::builder()
-            .header("Content-Type", "application/json")
-            .body(json!({ "user": user }).to_string())
-            .unwrap()
-            .into_response(),
-        Err(e) => {
-            error!("{e}");
-            Response::builder()
-                .header("Content-Type", "application/json")
-                .status(500)
-                .body(json!({ "message": "Internal server error" }).to_string())
-                .unwrap()
-                .into_response()
+use crate::{
+    AppState,
+    auth::{Claims, verify_password},
+    error::{ApiError, ApiResult},
+    extractors::{
+        client_ip::ClientIp,
+        json::{Json, ValidJson},
+        path::Path,
+    },
+    middlewares::role::require_admin_role,
+    models::{
+        auth_event::{AuthEvent, AuthEventFilter, AuthEventForCreate, AuthEventKind},
+        invitation::Invitation,
+        session::Session,
+        two_factor::TwoFactor,
+        user::{MIN_PASSWORD_LENGTH, User, UserForRegister, UserForUpdate, UserRole},
+    },
+    validation::{self, FieldErrors, Validate},
+};
+
+/// Longest an invitation can stay valid, in hours
+const MAX_INVITATION_HOURS: u32 = 30 * 24;
+
+#[derive(Deserialize)]
+struct InvitationForCreate {
+    role: UserRole,
+    /// Hours until the invitation expires, defaults to 3 days
+    expires_in: Option<u32>,
+}
+
+impl Validate for InvitationForCreate {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::default();
+        if let Some(hours) = self.expires_in {
+            errors.check(
+                "expires_in",
+                validation::range(hours, 1, MAX_INVITATION_HOURS),
+            );
         }
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+struct ProfileForUpdate {
+    timezone: Option<String>,
+}
+
+impl Validate for ProfileForUpdate {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::default();
+        if let Some(tz) = &self.timezone {
+            errors.check("timezone", validation::timezone(tz));
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+struct TwoFactorCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordConfirmation {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordForUpdate {
+    current_password: String,
+    new_password: String,
+}
+
+impl Validate for PasswordForUpdate {
+    fn validate(&self) -> Result<(), FieldErrors> {
+        let mut errors = FieldErrors::default();
+        if self.new_password.chars().count() < MIN_PASSWORD_LENGTH {
+            errors.add(
+                "new_password",
+                format!("must be at least {MIN_PASSWORD_LENGTH} characters"),
+            );
+        }
+        errors.into_result()
+    }
+}
+
+/// Respond with `409` if the username is already taken
+async fn check_username(state: &AppState, username: &str) -> ApiResult<()> {
+    match User::get_by_username(&state.pool, username).await? {
+        None => Ok(()),
+        Some(_) => Err(ApiError::Conflict("Username already taken".into())),
+    }
+}
+
+/// A user by id, `404` if it doesn't exist
+async fn find_user(state: &AppState, user_id: u32) -> ApiResult<User> {
+    User::get(&state.pool, user_id).await.map_err(|e| match e {
+        sqlx::Error::RowNotFound => ApiError::NotFound("User not found".into()),
+        e => e.into(),
+    })
+}
+
+#[debug_handler]
+async fn get_user(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Value>> {
+    let user = find_user(&state, user_id).await?;
+    Ok(Json(json!({ "user": user })))
+}
+
+#[debug_handler]
+async fn update_profile(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+    ValidJson(profile): ValidJson<ProfileForUpdate>,
+) -> ApiResult<Json<Value>> {
+    let update = UserForUpdate {
+        timezone: profile.timezone,
+        ..Default::default()
+    };
+    User::update(&state.pool, user_id, update).await?;
+    Ok(Json(json!({ "message": "Profile updated" })))
+}
+
+#[debug_handler]
+async fn change_password(
+    claims: Claims,
+    State(state): State<AppState>,
+    ClientIp(addr): ClientIp,
+    ValidJson(passwords): ValidJson<PasswordForUpdate>,
+) -> ApiResult<Json<Value>> {
+    check_session(&claims)?;
+    let Claims {
+        user_id,
+        session_id,
+        ..
+    } = claims;
+    let user = check_password(&state, user_id, &passwords.current_password).await?;
+    User::set_password(&state.pool, user_id, passwords.new_password).await?;
+    let event = AuthEventForCreate {
+        kind: AuthEventKind::PasswordChange,
+        user_id: Some(user_id),
+        username: Some(user.username),
+        ip: Some(addr.to_string()),
+        detail: None,
+    };
+    if let Err(e) = AuthEvent::insert(&state.pool, event).await {
+        error!("Error recording password change of user({user_id}): {e}");
+    }
+    // Sign out everywhere else, in case the old password leaked
+    if let Err(e) = Session::revoke_all(&state.pool, user_id, session_id).await {
+        error!("Error revoking user({user_id}) sessions: {e}");
+    }
+    Ok(Json(json!({ "message": "Password changed" })))
+}
+
+/// Respond with `403` for requests made with an api token, which can't change credentials
+fn check_session(claims: &Claims) -> ApiResult<()> {
+    match claims.session_id {
+        Some(_) => Ok(()),
+        None => Err(ApiError::Forbidden("Not allowed with an api token".into())),
+    }
+}
+
+/// Respond with `400` if `user` is the last active admin, who can't be demoted, disabled or
+/// deleted without leaving nobody able to manage users
+pub(super) async fn check_last_admin(state: &AppState, user: &User) -> ApiResult<()> {
+    if user.role == UserRole::Admin
+        && user.active
+        && User::count_other_active_admins(&state.pool, user.id).await? == 0
+    {
+        return Err(ApiError::BadRequest(
+            "There must be at least one active admin".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Respond with `400` unless `password` is the user's current password
+async fn check_password(state: &AppState, user_id: u32, password: &str) -> ApiResult<User> {
+    let user = find_user(state, user_id).await?;
+    if !verify_password(password, &user.password) {
+        return Err(ApiError::BadRequest("Invalid current password".into()));
+    }
+    Ok(user)
+}
+
+#[debug_handler]
+async fn two_factor_status(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Value>> {
+    let enabled = TwoFactor::is_enabled(&state.pool, user_id).await?;
+    let recovery_codes = TwoFactor::recovery_codes_left(&state.pool, user_id).await?;
+    Ok(Json(
+        json!({ "enabled": enabled, "recovery_codes_left": recovery_codes }),
+    ))
+}
+
+/// Start enrolling a TOTP app, the secret is only used once confirmed with a code
+#[debug_handler]
+async fn setup_two_factor(claims: Claims, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    check_session(&claims)?;
+    let user = find_user(&state, claims.user_id).await?;
+    if TwoFactor::is_enabled(&state.pool, user.id).await? {
+        return Err(ApiError::Conflict("Two factor is already enabled".into()));
+    }
+    let (secret, uri) = TwoFactor::begin(&state.pool, user.id, &user.username).await?;
+    Ok(Json(json!({ "secret": secret, "otpauth_uri": uri })))
+}
+
+#[debug_handler]
+async fn enable_two_factor(
+    claims: Claims,
+    State(state): State<AppState>,
+    Json(TwoFactorCode { code }): Json<TwoFactorCode>,
+) -> ApiResult<Json<Value>> {
+    check_session(&claims)?;
+    let user_id = claims.user_id;
+    if !TwoFactor::verify_code(&state.pool, user_id, code.trim()).await? {
+        return Err(ApiError::BadRequest("Invalid code".into()));
     }
+    let codes = TwoFactor::enable(&state.pool, user_id).await?;
+    // the recovery codes are only ever shown here
+    Ok(Json(json!({ "recovery_codes": codes })))
 }
 
 #[debug_handler]
-async fn list_users(_: Claims, State(state): State<AppState>) -> Response {
-    match User::list(&state.pool).await {
-        Ok(users) => Response::builder()
-            .header("Content-Type", "application/json")
-            .body(json!({ "users": users }).to_string())
-            .unwrap()
-            .into_response(),
-        Err(e) => {
-            error!("{e}");
-            Response::builder()
-                .header("Content-Type", "application/json")
-                .status(500)
-                .body(json!({ "message": "Internal server error" }).to_string())
-                .unwrap()
-                .into_response()
+async fn disable_two_factor(
+    claims: Claims,
+    State(state): State<AppState>,
+    Json(PasswordConfirmation { password }): Json<PasswordConfirmation>,
+) -> ApiResult<Json<Value>> {
+    check_session(&claims)?;
+    let user_id = claims.user_id;
+    check_password(&state, user_id, &password).await?;
+    TwoFactor::disable(&state.pool, user_id).await?;
+    Ok(Json(json!({ "message": "Two factor disabled" })))
+}
+
+#[debug_handler]
+async fn regenerate_recovery_codes(
+    claims: Claims,
+    State(state): State<AppState>,
+    Json(PasswordConfirmation { password }): Json<PasswordConfirmation>,
+) -> ApiResult<Json<Value>> {
+    check_session(&claims)?;
+    let user_id = claims.user_id;
+    check_password(&state, user_id, &password).await?;
+    if !TwoFactor::is_enabled(&state.pool, user_id).await? {
+        return Err(ApiError::BadRequest("Two factor is not enabled".into()));
+    }
+    let codes = TwoFactor::regenerate_recovery_codes(&state.pool, user_id).await?;
+    Ok(Json(json!({ "recovery_codes": codes })))
+}
+
+#[debug_handler]
+async fn list_users(_: Claims, State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let users = User::list(&state.pool).await?;
+    Ok(Json(json!({ "users": users })))
+}
+
+#[debug_handler]
+async fn add_user(
+    State(state): State<AppState>,
+    ValidJson(user): ValidJson<UserForRegister>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    check_username(&state, &user.username).await?;
+    User::insert(&state.pool, user).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "User created" })),
+    ))
+}
+
+#[debug_handler]
+async fn update_user(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    ValidJson(update): ValidJson<UserForUpdate>,
+) -> ApiResult<Json<Value>> {
+    let revokes_admin =
+        update.active == Some(false) || update.role.is_some_and(|r| r != UserRole::Admin);
+    if revokes_admin {
+        // Keep at least the current admin able to manage users
+        if id == user_id {
+            return Err(ApiError::BadRequest(
+                "You cannot disable or demote yourself".into(),
+            ));
         }
+        check_last_admin(&state, &find_user(&state, id).await?).await?;
+    }
+    if User::update(&state.pool, id, update).await? == 0 {
+        return Err(ApiError::NotFound("User not found".into()));
+    }
+    Ok(Json(json!({ "message": "User updated" })))
+}
+
+#[debug_handler]
+async fn delete_user(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    if id == user_id {
+        return Err(ApiError::BadRequest("You cannot delete yourself".into()));
     }
+    check_last_admin(&state, &find_user(&state, id).await?).await?;
+    // Services owned by the deleted user are transferred to the admin deleting them
+    if User::delete(&state.pool, id, user_id).await? == 0 {
+        return Err(ApiError::NotFound("User not found".into()));
+    }
+    Ok(Json(json!({ "message": "User deleted" })))
+}
+
+#[debug_handler]
+async fn list_auth_events(
+    State(state): State<AppState>,
+    Query(filter): Query<AuthEventFilter>,
+) -> ApiResult<Json<Value>> {
+    let events = AuthEvent::list(&state.pool, &filter).await?;
+    Ok(Json(json!({ "events": events })))
+}
+
+#[debug_handler]
+async fn list_invitations(State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let invitations = Invitation::list_pending(&state.pool).await?;
+    Ok(Json(json!({ "invitations": invitations })))
+}
+
+#[debug_handler]
+async fn add_invitation(
+    Claims { user_id, .. }: Claims,
+    State(state): State<AppState>,
+    ValidJson(invitation): ValidJson<InvitationForCreate>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    let expires_at = Utc::now() + TimeDelta::hours(i64::from(invitation.expires_in.unwrap_or(72)));
+    let token = Invitation::create(&state.pool, invitation.role, user_id, expires_at).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "token": token,
+            "url": format!("/invite/{token}"),
+            "expires_at": expires_at,
+        })),
+    ))
+}
+
+#[debug_handler]
+async fn delete_invitation(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+) -> ApiResult<Json<Value>> {
+    if Invitation::delete(&state.pool, id).await? == 0 {
+        return Err(ApiError::NotFound("Invitation not found".into()));
+    }
+    Ok(Json(json!({ "message": "Invitation deleted" })))
+}
+
+#[debug_handler]
+async fn accept_invitation(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    ValidJson(user): ValidJson<UserForRegister>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    check_username(&state, &user.username).await?;
+    if Invitation::accept(&state.pool, &token, user)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound("Invitation not found or expired".into()));
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "message": "user registered" })),
+    ))
 }
 
 pub fn routes() -> Router<AppState> {
+    let admin = Router::new()
+        .route("/users", get(list_users).post(add_user))
+        .route("/users/{id}", put(update_user).delete(delete_user))
+        .route("/invitations", get(list_invitations).post(add_invitation))
+        .route("/invitations/{id}", delete(delete_invitation))
+        .route("/auth-events", get(list_auth_events))
+        .route_layer(middleware::from_fn(require_admin_role));
+
     Router::new()
-        .route("/users", get(list_users))
-        .route("/user", get(get_user))
+        .route("/user", get(get_user).put(update_profile))
+        .route("/user/password", put(change_password))
+        .route("/user/2fa", get(two_factor_status))
+        .route("/user/2fa/setup", post(setup_two_factor))
+        .route("/user/2fa/enable", post(enable_two_factor))
+        .route("/user/2fa/disable", post(disable_two_factor))
+        .route("/user/2fa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/invitations/{token}/accept", post(accept_invitation))
+        .merge(admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_invitation() {
+        let invitation = |expires_in| InvitationForCreate {
+            role: UserRole::Viewer,
+            expires_in,
+        };
+        assert!(invitation(None).validate().is_ok());
+        assert!(invitation(Some(MAX_INVITATION_HOURS)).validate().is_ok());
+        assert!(invitation(Some(0)).validate().is_err());
+        // would overflow the expiry date
+        assert_eq!(
+            invitation(Some(u32::MAX)).validate().unwrap_err().fields(),
+            ["expires_in"]
+        );
+    }
+
+    #[test]
+    fn validate_new_password() {
+        let passwords = |new_password: &str| PasswordForUpdate {
+            current_password: "hunter22".into(),
+            new_password: new_password.into(),
+        };
+        assert!(passwords("correct horse").validate().is_ok());
+        assert_eq!(
+            passwords("abc").validate().unwrap_err().fields(),
+            ["new_password"]
+        );
+    }
 }
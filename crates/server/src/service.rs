@@ -1,25 +1,28 @@
 use std::ops::Deref;
 
 use apalis::prelude::*;
-use apalis_sql::sqlite::SqliteStorage;
-use chrono::{DateTime, Timelike, Utc};
-use sqlx::sqlite::SqlitePool;
-use tracing::error;
+use chrono::{DateTime, TimeDelta, Timelike, Utc};
+use tracing::{error, info};
 
-use crate::models::service::Service;
+use crate::{
+    AppState,
+    models::{DbPool, JobStorage, log::Log, service::Service},
+};
 
 #[derive(Clone)]
 pub struct TimerService {
-    pub pool: SqlitePool,
+    pub pool: DbPool,
 }
 
 impl TimerService {
-    pub fn new(pool: SqlitePool) -> Self {
+    pub fn new(pool: DbPool) -> Self {
         Self { pool }
     }
 
     pub async fn execute(&self, job: Timer) -> Result<(), Box<dyn std::error::Error>> {
-        let mut storage: SqliteStorage<Service> = SqliteStorage::new(self.pool.clone());
+        let mut storage: JobStorage<Service> = JobStorage::new(self.pool.clone());
+
+        Service::resume_due(&self.pool, *job).await?;
 
         for service in Service::all_active(&self.pool).await? {
             if job.is_interval(service.interval) {
@@ -49,7 +52,7 @@ impl Deref for Timer {
 
 impl Timer {
     fn is_interval(&self, interval: u32) -> bool {
-        self.num_seconds_from_midnight() % interval == 0
+        self.num_seconds_from_midnight().is_multiple_of(interval)
     }
 }
 
@@ -74,3 +77,23 @@ pub async fn run_timer_cron_service(
         }
     }
 }
+
+/// Delete logs older than the retention setting
+pub async fn run_retention_cron_service(job: Timer, state: Data<AppState>) -> bool {
+    let retention_days = state.settings.borrow().retention_days;
+    if retention_days == 0 {
+        return true;
+    }
+    let before = *job - TimeDelta::days(retention_days as i64);
+    match Log::delete_before(&state.pool, before).await {
+        Ok(0) => true,
+        Ok(count) => {
+            info!("Deleted {count} logs older than {retention_days} days");
+            true
+        }
+        Err(e) => {
+            error!("Error deleting old logs: {e}");
+            false
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Built-in HTTPS, with certificates from files or issued with ACME
+
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Router,
+    extract::Request,
+    http::{StatusCode, Uri, header::HOST},
+    response::{IntoResponse, Redirect},
+};
+use axum_server::tls_rustls::RustlsConfig;
+use rcgen::{CertificateParams, KeyPair};
+use tracing::{error, info, warn};
+
+use crate::{
+    acme::{self, Challenges},
+    config::TlsConfig,
+};
+
+/// How often the certificate files are checked for changes
+const WATCH_EVERY: Duration = Duration::from_secs(10);
+
+fn self_signed(domains: &[String]) -> Result<(String, String), rcgen::Error> {
+    let key = KeyPair::generate()?;
+    let cert = CertificateParams::new(domains.to_vec())?.self_signed(&key)?;
+    Ok((cert.pem(), key.serialize_pem()))
+}
+
+/// Load the certificate and key.
+///
+/// With ACME a self-signed certificate is served until the first one is issued.
+pub async fn load(tls: &TlsConfig) -> io::Result<RustlsConfig> {
+    // a crate enabling another provider makes this fail, keep the one installed first
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    if let Some(acme) = &tls.acme {
+        if let Some(dir) = tls.cert.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        if !tls.cert.exists() {
+            warn!("No certificate yet, serving a self-signed one until it is issued");
+            let (cert, key) = self_signed(&acme.domains).map_err(io::Error::other)?;
+            return RustlsConfig::from_pem(cert.into_bytes(), key.into_bytes()).await;
+        }
+    }
+    RustlsConfig::from_pem_file(&tls.cert, &tls.key).await
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reload the certificate when its files change, like when renewed by another tool
+pub async fn watch(tls: TlsConfig, rustls: RustlsConfig) {
+    let mut last = (modified(&tls.cert), modified(&tls.key));
+    loop {
+        tokio::time::sleep(WATCH_EVERY).await;
+        let current = (modified(&tls.cert), modified(&tls.key));
+        if current == last || current.0.is_none() || current.1.is_none() {
+            continue;
+        }
+        last = current;
+        // the old certificate is kept when the new one is invalid, like a half written file
+        match rustls.reload_from_pem_file(&tls.cert, &tls.key).await {
+            Ok(()) => info!("Reloaded certificate {}", tls.cert.display()),
+            Err(e) => error!("Error reloading certificate {}: {e}", tls.cert.display()),
+        }
+    }
+}
+
+/// Where a plain HTTP request is sent to on the HTTPS port
+fn https_uri(host: &str, https_port: u16, uri: &Uri) -> Option<String> {
+    let host = match host.rsplit_once(':') {
+        // not the closing bracket of an IPv6 address
+        Some((host, port)) if !port.contains(']') => host,
+        _ => host,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Some(match https_port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    })
+}
+
+/// The plain HTTP server, answering ACME challenges and redirecting everything else to HTTPS
+pub fn http_routes(challenges: Challenges, https_port: u16) -> Router {
+    acme::challenge_routes(challenges).fallback(move |req: Request| async move {
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+        match https_uri(host, https_port, req.uri()) {
+            Some(uri) => Redirect::permanent(&uri).into_response(),
+            None => StatusCode::BAD_REQUEST.into_response(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_to_https() {
+        let uri: Uri = "/api/services?limit=5".parse().unwrap();
+        assert_eq!(
+            https_uri("status.example.com", 443, &uri).as_deref(),
+            Some("https://status.example.com/api/services?limit=5")
+        );
+        assert_eq!(
+            https_uri("status.example.com:80", 8443, &uri).as_deref(),
+            Some("https://status.example.com:8443/api/services?limit=5")
+        );
+        assert_eq!(
+            https_uri("[::1]", 443, &uri).as_deref(),
+            Some("https://[::1]/api/services?limit=5")
+        );
+        assert_eq!(https_uri("", 443, &uri), None);
+    }
+
+    #[tokio::test]
+    async fn reload_changed_certificate() {
+        let dir = std::env::temp_dir().join(format!("stamon-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tls = TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            http_port: None,
+            acme: None,
+        };
+        let write = |domain: &str| {
+            let (cert, key) = self_signed(&[domain.to_string()]).unwrap();
+            std::fs::write(&tls.cert, cert).unwrap();
+            std::fs::write(&tls.key, key).unwrap();
+        };
+
+        write("one.test");
+        let rustls = load(&tls).await.unwrap();
+        let before = rustls.get_inner();
+
+        write("two.test");
+        rustls
+            .reload_from_pem_file(&tls.cert, &tls.key)
+            .await
+            .unwrap();
+        assert!(!std::sync::Arc::ptr_eq(&before, &rustls.get_inner()));
+
+        // an invalid file keeps the current certificate
+        std::fs::write(&tls.cert, "not a certificate").unwrap();
+        assert!(
+            rustls
+                .reload_from_pem_file(&tls.cert, &tls.key)
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
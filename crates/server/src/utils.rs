@@ -1,7 +1,26 @@
 use std::io;
 
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
 use tokio::signal;
 
+const TOKEN_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Generate a random url-safe alphanumeric token of `len` characters
+pub fn random_token(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|b| TOKEN_CHARS[*b as usize % TOKEN_CHARS.len()] as char)
+        .collect()
+}
+
+/// Hash a random token for storage, tokens are high entropy so a fast digest is enough
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
 pub async fn shutdown_signal() -> io::Result<()> {
     let ctrl_c = signal::ctrl_c();
 
@@ -37,6 +56,22 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_random_token() {
+        let token = random_token(32);
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(token, random_token(32));
+    }
+
+    #[test]
+    fn test_hash_token() {
+        let hash = hash_token("token");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_token("token"));
+        assert_ne!(hash, hash_token("other"));
+    }
+
     #[test]
     fn test_shutdown_signal_exists() {
         // Test that the function exists and can be called
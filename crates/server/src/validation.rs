@@ -0,0 +1,248 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use apalis_cron::Schedule;
+use chrono_tz::Tz;
+use http::{HeaderName, HeaderValue};
+use reqwest::Url;
+use scraper::Selector;
+use serde::Serialize;
+use sqlx::sqlite::SqliteConnectOptions;
+
+use crate::config::env_config;
+
+/// Problems with the fields of a request body, by field name
+#[derive(Debug, Default, Serialize)]
+pub struct FieldErrors(BTreeMap<&'static str, Vec<String>>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.entry(field).or_default().push(message.into());
+    }
+
+    /// Record the error of a check, if it failed
+    pub fn check(&mut self, field: &'static str, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.add(field, message);
+        }
+    }
+
+    pub fn into_result(self) -> Result<(), FieldErrors> {
+        if self.0.is_empty() { Ok(()) } else { Err(self) }
+    }
+
+    #[cfg(test)]
+    pub fn fields(&self) -> Vec<&'static str> {
+        self.0.keys().copied().collect()
+    }
+}
+
+/// Request bodies checked by [`crate::extractors::json::ValidJson`] before reaching a handler
+pub trait Validate {
+    fn validate(&self) -> Result<(), FieldErrors>;
+}
+
+pub fn not_blank(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("must not be empty".into());
+    }
+    Ok(())
+}
+
+pub fn max_length(value: &str, max: usize) -> Result<(), String> {
+    if value.chars().count() > max {
+        return Err(format!("must be at most {max} characters"));
+    }
+    Ok(())
+}
+
+pub fn range<T: PartialOrd + std::fmt::Display>(value: T, min: T, max: T) -> Result<(), String> {
+    if value < min || value > max {
+        return Err(format!("must be between {min} and {max}"));
+    }
+    Ok(())
+}
+
+/// An http(s) url with a host, like `https://example.com:8443/health`
+pub fn http_url(value: &str) -> Result<(), String> {
+    let url = Url::parse(value).map_err(|e| format!("invalid url: {e}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("must start with http:// or https://".into());
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err("must have a host".into());
+    }
+    if url.port() == Some(0) {
+        return Err("invalid port 0".into());
+    }
+    Ok(())
+}
+
+/// The url of a server like `postgres://user:pass@db:5432/app` or
+/// `smtp://mail.example.com:587`, with one of `schemes`
+pub fn server_url(value: &str, schemes: &[&str]) -> Result<(), String> {
+    let url = Url::parse(value).map_err(|e| format!("invalid url: {e}"))?;
+    if !schemes.contains(&url.scheme()) {
+        return Err(format!("must start with {}://", schemes[0]));
+    }
+    if url.port() == Some(0) {
+        return Err("invalid port 0".into());
+    }
+    Ok(())
+}
+
+/// A `sqlite://` url of a database outside the server's data directory and database, which
+/// monitors could otherwise read
+pub fn sqlite_url(value: &str) -> Result<(), String> {
+    server_url(value, &["sqlite"])?;
+    let options = SqliteConnectOptions::from_str(value).map_err(|e| format!("invalid url: {e}"))?;
+    let path = resolve(options.get_filename());
+
+    let config = env_config();
+    let server_db = SqliteConnectOptions::from_str(&config.db_url)
+        .ok()
+        .map(|db| resolve(db.get_filename()));
+    if path.starts_with(resolve(&config.data_path)) || server_db.is_some_and(|db| db == path) {
+        return Err("must not be the server's database".into());
+    }
+    Ok(())
+}
+
+/// Absolute path without links or `..`, as far as it exists
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) if dir.as_os_str().is_empty() => resolve(Path::new(".")).join(name),
+        (Some(dir), Some(name)) => resolve(dir).join(name),
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_owned()),
+    }
+}
+
+pub fn ip_address(value: &str) -> Result<(), String> {
+    IpAddr::from_str(value)
+        .map(|_| ())
+        .map_err(|_| "must be an IP address".into())
+}
+
+/// An IP address or a hostname like `db.example.com`
+pub fn host(value: &str) -> Result<(), String> {
+    if ip_address(value.trim_start_matches('[').trim_end_matches(']')).is_ok() {
+        return Ok(());
+    }
+    let name = value.strip_suffix('.').unwrap_or(value);
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if name.len() > 253 || !name.split('.').all(valid_label) {
+        return Err("must be a hostname or an IP address".into());
+    }
+    Ok(())
+}
+
+/// A gRPC metadata entry, sent as an HTTP/2 header
+pub fn metadata(name: &str, value: &str) -> Result<(), String> {
+    let reserved = name.starts_with("grpc-") || ["content-type", "te"].contains(&name);
+    if reserved || name.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Err(format!("{name} can't be set"));
+    }
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid name {name}"))?;
+    HeaderValue::from_str(value).map_err(|_| format!("invalid value for {name}"))?;
+    Ok(())
+}
+
+pub fn json(value: &str) -> Result<(), String> {
+    serde_json::from_str::<serde_json::Value>(value)
+        .map(|_| ())
+        .map_err(|e| format!("invalid JSON: {e}"))
+}
+
+pub fn regex(value: &str) -> Result<(), String> {
+    regex::Regex::new(value)
+        .map(|_| ())
+        .map_err(|e| format!("invalid regex: {e}"))
+}
+
+pub fn css_selector(value: &str) -> Result<(), String> {
+    Selector::parse(value)
+        .map(|_| ())
+        .map_err(|e| format!("invalid CSS selector: {e}"))
+}
+
+pub fn cron(value: &str) -> Result<(), String> {
+    Schedule::from_str(value)
+        .map(|_| ())
+        .map_err(|e| format!("invalid cron expression: {e}"))
+}
+
+pub fn timezone(value: &str) -> Result<(), String> {
+    Tz::from_str(value)
+        .map(|_| ())
+        .map_err(|_| format!("unknown timezone {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert!(http_url("https://example.com:8443/health").is_ok());
+        assert!(http_url("http://10.0.0.1").is_ok());
+        assert!(http_url("ftp://example.com").is_err());
+        assert!(http_url("example.com").is_err());
+        assert!(http_url("http://example.com:0").is_err());
+        assert!(http_url("http://example.com:70000").is_err());
+
+        assert!(ip_address("::1").is_ok());
+        assert!(ip_address("example.com").is_err());
+
+        assert!(host("example.com").is_ok());
+        assert!(host("localhost").is_ok());
+        assert!(host("[2001:db8::1]").is_ok());
+        assert!(host("http://example.com").is_err());
+        assert!(host("-bad.example.com").is_err());
+        assert!(host("").is_err());
+
+        let postgres = ["postgres", "postgresql"];
+        assert!(server_url("postgresql://app:secret@db:5432/app", &postgres).is_ok());
+        assert!(server_url("mysql://db/app", &postgres).is_err());
+        assert!(server_url("sqlite:///var/lib/app.db", &["sqlite"]).is_ok());
+        assert!(server_url("redis://:secret@cache:6379/1", &["redis"]).is_ok());
+
+        assert!(sqlite_url("sqlite:///var/lib/app.db?mode=ro").is_ok());
+        assert!(sqlite_url("postgres://db/app").is_err());
+        let data = resolve(&env_config().data_path);
+        let server_db = format!("sqlite://{}", data.join("stamon.db").display());
+        assert!(sqlite_url(&server_db).is_err());
+        let dotted = format!("sqlite://{}/../data/stamon.db?mode=ro", data.display());
+        assert!(sqlite_url(&dotted).is_err());
+    }
+
+    #[test]
+    fn grpc_metadata() {
+        assert!(metadata("authorization", "Bearer token").is_ok());
+        assert!(metadata("Authorization", "Bearer token").is_err());
+        assert!(metadata("grpc-timeout", "1S").is_err());
+        assert!(metadata("x-tenant", "a\nb").is_err());
+    }
+
+    #[test]
+    fn collect_errors() {
+        let mut errors = FieldErrors::default();
+        errors.check("name", not_blank(" "));
+        errors.check("interval", range(0, 1, 10));
+        errors.check("url", http_url("https://example.com"));
+        assert_eq!(errors.fields(), ["interval", "name"]);
+
+        assert!(FieldErrors::default().into_result().is_ok());
+    }
+}
@@ -2,17 +2,21 @@ use std::{net::SocketAddr, ops::ControlFlow};
 
 use axum::{
     extract::{
-        ConnectInfo, State, WebSocketUpgrade,
+        ConnectInfo, Query, State, WebSocketUpgrade,
         ws::{Message, WebSocket},
     },
     response::IntoResponse,
 };
 use axum_extra::{TypedHeader, headers::UserAgent};
 use futures::{SinkExt, StreamExt};
-use serde::Serialize;
-use tracing::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
 
-use crate::{AppState, models::log::LogForCreate};
+use crate::{
+    AppState,
+    auth::{AuthError, Claims},
+    models::{DbPool, group::Group, log::LogForCreate, service::Service},
+};
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -29,6 +33,12 @@ pub struct Notification {
     pub title: String,
     pub message: String,
     pub level: Level,
+    /// Service the notification is about, only sent to users who can see it
+    #[serde(skip)]
+    pub service_id: Option<u32>,
+    /// Group the notification is about, only sent to users who can see it
+    #[serde(skip)]
+    pub group_id: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -41,17 +51,52 @@ pub enum Event {
     Notification(Notification),
 }
 
+impl Event {
+    /// Whether a user can see the service or group the event is about, events about neither
+    /// are for admins only
+    async fn is_visible_to(&self, pool: &DbPool, user_id: u32) -> sqlx::Result<bool> {
+        let (service_id, group_id) = match self {
+            Event::Log(log) => (Some(log.service_id), None),
+            Event::Notification(n) => (n.service_id, n.group_id),
+        };
+        if let Some(service_id) = service_id {
+            return Service::can_access(pool, service_id, user_id).await;
+        }
+        match group_id {
+            Some(group_id) => match Group::get(pool, group_id).await? {
+                Some(group) => group.is_visible_to(pool, user_id).await,
+                None => Ok(false),
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    /// Access or api token, for clients that can't send headers or cookies
+    token: Option<String>,
+}
+
 /// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
 /// of websocket negotiation). After this completes, the actual switching from HTTP to
 /// websocket protocol will occur.
 /// This is the last point where we can extract TCP/IP metadata such as IP address of the client
 /// as well as things from HTTP headers such as user-agent of the browser etc.
+/// Clients authenticate like with the api, or with a `token` query parameter since browsers
+/// can't set headers on websockets.
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+    Query(query): Query<WsQuery>,
+    claims: Result<Claims, AuthError>,
+) -> Result<impl IntoResponse, AuthError> {
+    let claims = match query.token {
+        Some(token) => Claims::authenticate(&state.pool, &token).await?,
+        None => claims?,
+    };
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
@@ -60,12 +105,12 @@ pub async fn ws_handler(
     info!("`{user_agent}` at {addr} connected.");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, state))
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, addr, claims, state)))
 }
 
 /// Actual websocket statemachine (one will be spawned per connection)
-#[tracing::instrument(name = "ws", skip(socket, state))]
-async fn handle_socket(mut socket: WebSocket, who: SocketAddr, state: AppState) {
+#[tracing::instrument(name = "ws", skip(socket, claims, state))]
+async fn handle_socket(mut socket: WebSocket, who: SocketAddr, claims: Claims, state: AppState) {
     // send a ping (unsupported by some browsers) just to kick things off and get a response
     if socket
         .send(Message::Ping(vec![1, 2, 3].into()))
@@ -91,6 +136,17 @@ async fn handle_socket(mut socket: WebSocket, who: SocketAddr, state: AppState)
     // messages over the websocket to our client.
     let mut send_task = tokio::spawn(async move {
         while let Ok(msg) = rx.recv().await {
+            // Only send events about services the user can see
+            if let Some(user_id) = claims.visible_to() {
+                match msg.is_visible_to(&state.pool, user_id).await {
+                    Ok(true) => (),
+                    Ok(false) => continue,
+                    Err(e) => {
+                        error!("Failed to check event visibility: {e}");
+                        continue;
+                    }
+                }
+            }
             // In any websocket error, break loop.
             if sender
                 .send(Message::Text(serde_json::to_string(&msg).unwrap().into()))
@@ -147,3 +203,53 @@ fn process_message(msg: Message, who: SocketAddr) -> ControlFlow<(), ()> {
     }
     ControlFlow::Continue(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::group::GroupForCreate;
+
+    fn notification(service_id: Option<u32>, group_id: Option<u32>) -> Event {
+        Event::Notification(Notification {
+            title: "Service Down".into(),
+            message: "Service One is Down".into(),
+            level: Level::Warning,
+            service_id,
+            group_id,
+        })
+    }
+
+    #[sqlx::test(
+        migrator = "crate::models::MIGRATOR",
+        fixtures(path = "models/fixtures", scripts("users", "services"))
+    )]
+    async fn event_visibility(pool: DbPool) -> sqlx::Result<()> {
+        // user 1 owns service 1, user 3 owns service 3
+        let log = Event::Log(LogForCreate {
+            service_id: 1,
+            ..Default::default()
+        });
+        assert!(log.is_visible_to(&pool, 1).await?);
+        assert!(!log.is_visible_to(&pool, 3).await?);
+        assert!(!notification(Some(1), None).is_visible_to(&pool, 3).await?);
+        assert!(notification(Some(3), None).is_visible_to(&pool, 3).await?);
+
+        Group::insert(
+            &pool,
+            GroupForCreate {
+                name: "infra".into(),
+                ..Default::default()
+            },
+        )
+        .await?;
+        sqlx::query("UPDATE Services SET group_id = 1 WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        assert!(notification(None, Some(1)).is_visible_to(&pool, 1).await?);
+        assert!(!notification(None, Some(1)).is_visible_to(&pool, 3).await?);
+        // events about no service are for admins
+        assert!(!notification(None, None).is_visible_to(&pool, 1).await?);
+
+        Ok(())
+    }
+}